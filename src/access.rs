@@ -0,0 +1,407 @@
+//! Effective privileges through role inheritance.
+//!
+//! Memberships are flat in the spec and in the catalog, but a role can use
+//! the privileges of every role it belongs to, directly or transitively.
+//! [`AccessGraph`] walks that graph the way Postgres does:
+//!
+//! * privileges of a group role are inherited only while every role on the
+//!   path up to it has INHERIT;
+//! * any membership, inherited or not, allows `SET ROLE` to the group, which
+//!   then acts with the group's privileges, superuser included;
+//! * owners hold every privilege on their objects, and every role holds the
+//!   privileges granted to PUBLIC.
+//!
+//! Each effective privilege comes with the path of roles that grants it.
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fmt;
+
+use crate::context::{
+    Attributes, Context, DatabaseObject, Privilege, PrivilegeType, RoleAttribute, PUBLIC,
+};
+
+/// How a role gets to use a privilege.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    /// Held by the role itself, or inherited through memberships
+    Inherited,
+    /// Only usable after `SET ROLE` to the last role on the path
+    SetRole,
+}
+
+/// Why the last role on the path holds the privilege.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Source {
+    Grant,
+    Ownership,
+    Superuser,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EffectivePrivilege {
+    pub role: String,
+    pub object: DatabaseObject,
+    pub privilege: PrivilegeType,
+    pub grantable: bool,
+    pub access: Access,
+    pub source: Source,
+    /// Roles from `role` to the holder of the privilege, e.g.
+    /// `["jdoe", "analyst"]`. PUBLIC appears as the last role for privileges
+    /// granted to everyone.
+    pub path: Vec<String>,
+}
+
+impl fmt::Display for EffectivePrivilege {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let privilege = match self.privilege {
+            PrivilegeType::Read => "read",
+            PrivilegeType::Write => "write",
+        };
+        write!(
+            f,
+            "{} can {} {} {}",
+            self.role,
+            privilege,
+            self.object.kind,
+            self.object.fqn()
+        )?;
+        if self.grantable {
+            write!(f, " (grantable)")?;
+        }
+        let source = match self.source {
+            Source::Grant => "granted to",
+            Source::Ownership => "owned by",
+            Source::Superuser => "superuser",
+        };
+        let holder = self.path.last().unwrap_or(&self.role);
+        write!(f, ", {} {} via {}", source, holder, self.path.join(" -> "))?;
+        if self.access == Access::SetRole {
+            write!(f, " after SET ROLE {}", holder)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct RoleNode {
+    inherit: bool,
+    superuser: bool,
+    member_of: Vec<String>,
+    privileges: Vec<Privilege>,
+    owned: Vec<DatabaseObject>,
+}
+
+/// Roles, their memberships and their privileges, captured from a context.
+#[derive(Debug, Default)]
+pub struct AccessGraph {
+    roles: BTreeMap<String, RoleNode>,
+    public: Vec<Privilege>,
+    objects: Vec<DatabaseObject>,
+}
+
+impl AccessGraph {
+    pub fn from_context<T: Context>(context: &mut T) -> Self
+    where
+        T::RoleAttribute: RoleAttribute,
+    {
+        let mut graph = AccessGraph {
+            public: context.get_role_permissions(PUBLIC),
+            objects: context.get_objects(),
+            ..Default::default()
+        };
+        for name in context.get_roles() {
+            let attributes = context.get_role_attributes(&name).get_attributes();
+            let node = RoleNode {
+                inherit: attributes.contains(&Attributes::Inherit),
+                superuser: attributes.contains(&Attributes::Superuser),
+                member_of: context.get_role_memberships(&name).memberships,
+                privileges: context.get_role_permissions(&name),
+                owned: context.get_role_ownerships(&name),
+            };
+            graph.roles.insert(name, node);
+        }
+        graph
+    }
+
+    pub fn roles(&self) -> impl Iterator<Item = &String> {
+        self.roles.keys()
+    }
+
+    /// Objects whose fully qualified name is `name`, e.g. `finance` for a
+    /// schema or `finance.q2_revenue` for a table.
+    pub fn find_objects(&self, name: &str) -> Vec<&DatabaseObject> {
+        self.objects.iter().filter(|o| o.fqn() == name).collect()
+    }
+
+    /// Every privilege `role` can use, directly, through inheritance or
+    /// after SET ROLE. Only the shortest path is reported for each way of
+    /// reaching a holder.
+    pub fn what_can(&self, role: &str) -> Vec<EffectivePrivilege> {
+        let mut effective = vec![];
+        for (path, access) in self.reachable(role) {
+            let holder = &self.roles[path.last().unwrap()];
+            let push = |effective: &mut Vec<EffectivePrivilege>,
+                        object: &DatabaseObject,
+                        privilege: PrivilegeType,
+                        grantable: bool,
+                        source: Source| {
+                effective.push(EffectivePrivilege {
+                    role: role.to_string(),
+                    object: object.clone(),
+                    privilege,
+                    grantable,
+                    access,
+                    source,
+                    path: path.clone(),
+                })
+            };
+
+            if holder.superuser {
+                for object in &self.objects {
+                    for privilege in applicable(object) {
+                        push(&mut effective, object, privilege, true, Source::Superuser);
+                    }
+                }
+                continue;
+            }
+            for object in &holder.owned {
+                for privilege in applicable(object) {
+                    push(&mut effective, object, privilege, true, Source::Ownership);
+                }
+            }
+            for granted in &holder.privileges {
+                for privilege in &granted.privs {
+                    let grantable = granted.grantable.contains(privilege);
+                    push(
+                        &mut effective,
+                        &granted.object,
+                        *privilege,
+                        grantable,
+                        Source::Grant,
+                    );
+                }
+            }
+        }
+
+        // Everyone holds what is granted to PUBLIC
+        if self.roles.contains_key(role) {
+            for granted in &self.public {
+                for privilege in &granted.privs {
+                    effective.push(EffectivePrivilege {
+                        role: role.to_string(),
+                        object: granted.object.clone(),
+                        privilege: *privilege,
+                        grantable: false,
+                        access: Access::Inherited,
+                        source: Source::Grant,
+                        path: vec![role.to_string(), PUBLIC.to_string()],
+                    });
+                }
+            }
+        }
+
+        effective.sort_by_key(|p| (p.object.clone(), p.privilege, p.access, p.path.len()));
+        effective
+    }
+
+    /// Every role that can use a privilege on `object`, and how.
+    pub fn who_can(&self, object: &DatabaseObject) -> Vec<EffectivePrivilege> {
+        let mut effective: Vec<_> = self
+            .roles
+            .keys()
+            .flat_map(|role| self.what_can(role))
+            .filter(|p| &p.object == object)
+            .collect();
+        effective.sort_by_key(|p| (p.role.clone(), p.privilege, p.access, p.path.len()));
+        effective
+    }
+
+    /// The roles `role` can act as, with the shortest path to each. A role is
+    /// inherited if every role before it on the path has INHERIT, otherwise
+    /// it can only be used through SET ROLE. Cycles are cut by only visiting
+    /// each role once per kind of access.
+    fn reachable(&self, role: &str) -> Vec<(Vec<String>, Access)> {
+        if !self.roles.contains_key(role) {
+            return vec![];
+        }
+        let mut reached = vec![];
+        let mut inherited = HashSet::new();
+        for access in [Access::Inherited, Access::SetRole] {
+            let mut seen = HashSet::new();
+            let mut queue = VecDeque::from([vec![role.to_string()]]);
+            while let Some(path) = queue.pop_front() {
+                let name = path.last().unwrap();
+                if !seen.insert(name.clone()) {
+                    continue;
+                }
+                let Some(node) = self.roles.get(name) else {
+                    continue;
+                };
+                match access {
+                    Access::Inherited => {
+                        inherited.insert(name.clone());
+                        reached.push((path.clone(), access));
+                        if !node.inherit {
+                            continue;
+                        }
+                    }
+                    // The role itself needs no SET ROLE
+                    Access::SetRole if path.len() > 1 => {
+                        // Superuser is never inherited, so a superuser group
+                        // is worth reporting even when it is also inherited
+                        if !inherited.contains(name) || node.superuser {
+                            reached.push((path.clone(), access));
+                        }
+                    }
+                    Access::SetRole => {}
+                }
+                for group in &node.member_of {
+                    let mut next = path.clone();
+                    next.push(group.clone());
+                    queue.push_back(next);
+                }
+            }
+        }
+
+        // Superuser is an attribute of the role itself and is not inherited
+        reached
+            .into_iter()
+            .filter(|(path, access)| {
+                let node = &self.roles[path.last().unwrap()];
+                !(node.superuser && *access == Access::Inherited && path.len() > 1)
+            })
+            .collect()
+    }
+}
+
+/// The privilege types that apply to an object's kind.
+fn applicable(object: &DatabaseObject) -> Vec<PrivilegeType> {
+    [PrivilegeType::Read, PrivilegeType::Write]
+        .into_iter()
+        .filter(|p| !object.kind.raw_privileges(p).is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::fakedb::FakeDb;
+    use crate::context::ObjectKind;
+
+    fn graph() -> AccessGraph {
+        let mut db = FakeDb::default();
+        db.apply_all(&[
+            "GRANT analyst TO alice".into(),
+            "ALTER ROLE carol NOINHERIT".into(),
+        ])
+        .unwrap();
+        AccessGraph::from_context(&mut db)
+    }
+
+    fn q2_results() -> DatabaseObject {
+        DatabaseObject::new(
+            ObjectKind::Table,
+            "finance".into(),
+            Some("q2_results".into()),
+        )
+    }
+
+    #[test]
+    fn test_inherited_privileges_carry_their_path() {
+        let graph = graph();
+        let write = graph
+            .who_can(&q2_results())
+            .into_iter()
+            .filter(|p| p.privilege == PrivilegeType::Write)
+            .map(|p| (p.role, p.path, p.access, p.source))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            write,
+            vec![
+                (
+                    "alice".to_string(),
+                    vec!["alice".to_string()],
+                    Access::Inherited,
+                    Source::Ownership
+                ),
+                (
+                    "bob".to_string(),
+                    vec!["bob".to_string()],
+                    Access::Inherited,
+                    Source::Grant
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_noinherit_requires_set_role() {
+        let mut db = FakeDb::default();
+        db.apply_all(&[
+            "GRANT SELECT, REFERENCES ON TABLE finance.q2_results TO developer".into(),
+            "ALTER ROLE developer NOINHERIT".into(),
+            "REVOKE analyst FROM carol".into(),
+            "GRANT developer TO carol".into(),
+            "REVOKE SELECT, REFERENCES ON TABLE finance.q2_results FROM carol".into(),
+            "GRANT SELECT, REFERENCES ON TABLE finance.q2_results TO analyst".into(),
+        ])
+        .unwrap();
+        let graph = AccessGraph::from_context(&mut db);
+
+        let reads: Vec<_> = graph
+            .what_can("carol")
+            .into_iter()
+            .filter(|p| p.object == q2_results())
+            .map(|p| (p.path, p.access))
+            .collect();
+
+        // carol inherits from developer, but developer does not inherit from
+        // analyst, so analyst's grant needs SET ROLE
+        assert_eq!(
+            reads,
+            vec![
+                (vec!["carol".into(), "developer".into()], Access::Inherited),
+                (
+                    vec!["carol".into(), "developer".into(), "analyst".into()],
+                    Access::SetRole
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_public_privileges_apply_to_everyone() {
+        let graph = graph();
+        let function = DatabaseObject::new(
+            ObjectKind::Function,
+            "finance".into(),
+            Some("total_revenue(integer)".into()),
+        );
+
+        let roles: Vec<_> = graph
+            .who_can(&function)
+            .into_iter()
+            .filter(|p| p.source == Source::Grant)
+            .map(|p| p.role)
+            .collect();
+
+        assert_eq!(roles, vec!["alice", "analyst", "bob", "carol", "developer"]);
+    }
+
+    #[test]
+    fn test_superuser_is_not_inherited() {
+        let mut db = FakeDb::default();
+        db.apply("ALTER ROLE developer SUPERUSER").unwrap();
+        let graph = AccessGraph::from_context(&mut db);
+
+        let superuser: Vec<_> = graph
+            .what_can("alice")
+            .into_iter()
+            .filter(|p| p.source == Source::Superuser)
+            .map(|p| p.access)
+            .collect();
+
+        assert!(!superuser.is_empty());
+        assert!(superuser.iter().all(|a| *a == Access::SetRole));
+    }
+}
@@ -1,150 +1,850 @@
-use std::collections::HashSet;
+//! An in-memory database for testing
+//!
+//! `FakeDb` keeps its state in a [`Snapshot`], answers every `Context` query
+//! from it, and can execute the SQL the planner emits against itself. This
+//! lets tests check that applying a plan and planning again yields nothing,
+//! without a running Postgres.
+use anyhow::{bail, Result};
 
+use crate::adapters::snapshot::{
+    Snapshot, SnapshotAttributes, SnapshotContext, SnapshotMembership, SnapshotObject,
+    SnapshotPrivilege, SnapshotRole,
+};
 use crate::context::{
-    Attributes, Context, DatabaseObject, DefaultPrivilege, ObjectKind, Privilege, RoleMembership,
+    is_predefined_role, Context, DatabaseObject, DefaultPrivilege, ObjectKind, Privilege,
+    PrivilegeType, RoleMembership, PUBLIC,
 };
-use crate::context::{PrivilegeType::*, RoleAttribute};
+use crate::policy::{Policy, PolicyCommand, RowSecurity, TablePolicies};
+use crate::scope::Scope;
+use crate::spec::{parse_timestamp, Settings};
+
+const FIXTURE: &str = include_str!("../../resources/fakedb.yml");
 
 /// A fake database context for testing
-pub struct FakeDb {}
-pub struct FakeDbAttribute {
-    enabled: bool,
-    superuser: bool,
+#[derive(Debug, Clone)]
+pub struct FakeDb {
+    context: SnapshotContext,
 }
 
-impl Context for FakeDb {
-    type RoleAttribute = FakeDbAttribute;
-
-    fn database_name(&self) -> &str {
-        "fake_db"
+impl Default for FakeDb {
+    /// A small database with three login roles, two group roles and a
+    /// handful of objects in the `finance` and `marketing` schemas.
+    fn default() -> Self {
+        FakeDb::from_yaml(FIXTURE).expect("Invalid FakeDb fixture")
     }
+}
 
-    fn get_roles(&mut self) -> Vec<String> {
-        vec!["alice".to_string(), "bob".to_string(), "carol".to_string()]
+impl FakeDb {
+    pub fn new(snapshot: Snapshot) -> Self {
+        FakeDb {
+            context: SnapshotContext::new(snapshot),
+        }
     }
 
-    fn get_role_attributes(&mut self, _role: &str) -> Self::RoleAttribute {
-        FakeDbAttribute {
-            enabled: true,
-            superuser: false,
-        }
+    pub fn from_yaml(yaml: &str) -> Result<Self> {
+        Ok(FakeDb::new(serde_yaml::from_str(yaml)?))
     }
 
-    fn get_role_memberships(&mut self, _role: &str) -> RoleMembership {
-        RoleMembership::new(vec!["analyst".to_string(), "developer".to_string()])
+    pub fn snapshot(&self) -> &Snapshot {
+        self.context.snapshot()
     }
 
-    fn get_role_ownerships(&mut self, _role: &str) -> Vec<DatabaseObject> {
-        vec![
-            DatabaseObject::new(ObjectKind::Schema, "marketing".to_string(), None),
-            DatabaseObject::new(ObjectKind::Schema, "finance".to_string(), None),
-            DatabaseObject::new(
-                ObjectKind::Table,
-                "finance".to_string(),
-                Some("q2_results".to_string()),
-            ),
-        ]
+    /// Executes every statement in order, stopping at the first failure.
+    pub fn apply_all(&mut self, sql: &[String]) -> Result<()> {
+        for statement in sql {
+            self.apply(statement)?;
+        }
+        Ok(())
     }
 
-    fn get_role_permissions(&mut self, role: &str) -> Vec<Privilege> {
-        match role {
-            "alice" => vec![
-                Privilege {
-                    object: DatabaseObject::new(ObjectKind::Schema, "marketing".to_string(), None),
-                    privs: HashSet::from_iter(vec![Read]),
-                },
-                Privilege {
-                    object: DatabaseObject::new(ObjectKind::Schema, "finance".to_string(), None),
-                    privs: HashSet::from_iter(vec![Read, Write]),
-                },
-                Privilege {
-                    object: DatabaseObject::new(
-                        ObjectKind::Table,
-                        "finance".to_string(),
-                        Some("q2_results".to_string()),
-                    ),
-                    privs: HashSet::from_iter(vec![Read]),
-                },
-            ],
+    /// Executes a single statement of the kind the planner emits: ALTER ROLE,
+    /// GRANT and REVOKE of memberships, GRANT and REVOKE of privileges, with
+    /// or without the admin and grant options, creating schemas or changing
+    /// their owner, and row-level security and policies on tables.
+    pub fn apply(&mut self, statement: &str) -> Result<()> {
+        let statement = statement.trim().trim_end_matches(';');
+        if let Some(rest) = statement.strip_prefix("ALTER ROLE ") {
+            return self.alter_role(rest);
+        }
+        if let Some(rest) = statement.strip_prefix("CREATE SCHEMA ") {
+            return self.create_schema(rest);
+        }
+        if let Some(rest) = statement.strip_prefix("ALTER SCHEMA ") {
+            return self.alter_schema_owner(rest);
+        }
+        if let Some(rest) = statement.strip_prefix("ALTER TABLE ") {
+            return self.alter_row_security(rest);
+        }
+        if let Some(rest) = statement.strip_prefix("CREATE POLICY ") {
+            return self.create_policy(rest);
+        }
+        if let Some(rest) = statement.strip_prefix("ALTER POLICY ") {
+            return self.alter_policy(rest);
+        }
+        if let Some(rest) = statement.strip_prefix("DROP POLICY ") {
+            let (name, table, _) = self.policy_target(rest)?;
+            if self.row_security(&table)?.policies.remove(&name).is_none() {
+                bail!("Policy {} on {} does not exist", name, table);
+            }
+            self.prune_row_security();
+            return Ok(());
+        }
+        if let Some(rest) = statement.strip_prefix("GRANT ") {
+            return match rest.split_once(" ON ") {
+                Some((privs, rest)) => self.grant_privileges(privs, rest),
+                None => self.grant_membership(rest),
+            };
+        }
+        if let Some(rest) = statement.strip_prefix("REVOKE ") {
+            return match rest.split_once(" ON ") {
+                Some((privs, rest)) => self.revoke_privileges(privs, rest),
+                None => self.revoke_membership(rest),
+            };
+        }
+        bail!("Unsupported statement: {}", statement)
+    }
 
-            "bob" => vec![
-                Privilege {
-                    object: DatabaseObject::new(ObjectKind::Schema, "marketing".to_string(), None),
-                    privs: HashSet::from_iter(vec![Read]),
-                },
-                Privilege {
-                    object: DatabaseObject::new(ObjectKind::Schema, "finance".to_string(), None),
-                    privs: HashSet::from_iter(vec![Read]),
-                },
-                Privilege {
-                    object: DatabaseObject::new(
-                        ObjectKind::Table,
-                        "finance".to_string(),
-                        Some("q2_results".to_string()),
-                    ),
-                    privs: HashSet::from_iter(vec![Read, Write]),
-                },
-            ],
-            "carol" => vec![
-                Privilege {
-                    object: DatabaseObject::new(ObjectKind::Schema, "marketing".to_string(), None),
-                    privs: HashSet::from_iter(vec![Read]),
-                },
-                Privilege {
-                    object: DatabaseObject::new(ObjectKind::Schema, "finance".to_string(), None),
-                    privs: HashSet::from_iter(vec![Read]),
-                },
-                Privilege {
-                    object: DatabaseObject::new(
-                        ObjectKind::Table,
-                        "finance".to_string(),
-                        Some("q2_results".to_string()),
-                    ),
-                    privs: HashSet::from_iter(vec![Read]),
+    fn alter_role(&mut self, rest: &str) -> Result<()> {
+        if let Some((name, change)) = rest.split_once(' ').filter(|(_, change)| {
+            ["IN DATABASE ", "SET ", "RESET "]
+                .iter()
+                .any(|prefix| change.starts_with(prefix))
+        }) {
+            return self.alter_role_setting(name, change);
+        }
+        let mut words = rest.split_whitespace();
+        let Some(name) = words.next() else {
+            bail!("ALTER ROLE requires a role name");
+        };
+        let role = self.role_mut(name)?;
+        while let Some(word) = words.next() {
+            let attributes = &mut role.attributes;
+            let (enabled, keyword) = match word.strip_prefix("NO") {
+                // NOINHERIT and friends, but not a bare NO
+                Some(keyword) if !keyword.is_empty() => (false, keyword),
+                _ => (true, word),
+            };
+            match keyword {
+                "LOGIN" => attributes.can_login = enabled,
+                "SUPERUSER" => attributes.is_superuser = enabled,
+                "CREATEDB" => attributes.create_db = enabled,
+                "CREATEROLE" => attributes.create_role = enabled,
+                "INHERIT" => attributes.inherit = enabled,
+                "REPLICATION" => attributes.replication = enabled,
+                "BYPASSRLS" => attributes.bypass_rls = enabled,
+                "CONNECTION" => {
+                    let limit = match (words.next(), words.next()) {
+                        (Some("LIMIT"), Some(limit)) => limit.parse::<i32>()?,
+                        _ => bail!("Malformed CONNECTION LIMIT for role {}", name),
+                    };
+                    attributes.connection_limit = (limit >= 0).then_some(limit);
+                }
+                "VALID" => {
+                    let valid_until = match (words.next(), words.next()) {
+                        (Some("UNTIL"), Some(timestamp)) => timestamp.trim_matches('\''),
+                        _ => bail!("Malformed VALID UNTIL for role {}", name),
+                    };
+                    attributes.valid_until = parse_timestamp(valid_until)?;
+                }
+                "PASSWORD" => match words.next() {
+                    Some(password) => role.password = Some(password.trim_matches('\'').to_string()),
+                    None => bail!("Malformed PASSWORD for role {}", name),
                 },
-            ],
-            _ => vec![],
+                _ => bail!("Unsupported role attribute: {}", word),
+            }
         }
+        Ok(())
     }
 
-    fn analyze_attributes(&mut self, name: &str, role: &crate::spec::Role) -> Vec<String> {
-        let mut sql = vec![];
-        if role.can_login {
-            sql.push(format!("ALTER ROLE {} LOGIN", name));
+    /// `[IN DATABASE database] SET key = value` or `RESET key`. Values are
+    /// stored unquoted, the way Postgres shows them.
+    fn alter_role_setting(&mut self, name: &str, change: &str) -> Result<()> {
+        let (database, change) = match change.strip_prefix("IN DATABASE ") {
+            Some(rest) => match rest.split_once(' ') {
+                Some((database, change)) => (Some(database), change),
+                None => bail!("Malformed ALTER ROLE {} {}", name, change),
+            },
+            None => (None, change),
+        };
+        let settings = &mut self.role_mut(name)?.settings;
+        let values = match database {
+            Some(database) => settings.databases.entry(database.to_string()).or_default(),
+            None => &mut settings.all,
+        };
+        if let Some(key) = change.strip_prefix("RESET ") {
+            values.remove(key);
+        } else if let Some((key, value)) = change
+            .strip_prefix("SET ")
+            .and_then(|setting| setting.split_once(" = "))
+        {
+            let elements: Vec<String> = value
+                .split("', '")
+                .map(|e| e.trim_matches('\'').replace("''", "'"))
+                .collect();
+            values.insert(key.to_string(), elements.join(", "));
         } else {
-            sql.push(format!("ALTER ROLE {} NOLOGIN", name));
+            bail!("Unsupported ALTER ROLE {} {}", name, change);
+        }
+        settings.databases.retain(|_, values| !values.is_empty());
+        Ok(())
+    }
+
+    fn create_schema(&mut self, rest: &str) -> Result<()> {
+        let Some((schema, owner)) = rest.split_once(" AUTHORIZATION ") else {
+            bail!("Unsupported CREATE SCHEMA: {}", rest);
+        };
+        self.check_role_exists(owner)?;
+        let objects = &mut self.context.snapshot_mut().objects;
+        if objects
+            .iter()
+            .any(|o| o.kind == ObjectKind::Schema && o.schema == schema)
+        {
+            bail!("Schema {} already exists", schema);
         }
+        objects.push(SnapshotObject {
+            kind: ObjectKind::Schema,
+            schema: schema.to_string(),
+            name: None,
+            owner: Some(owner.to_string()),
+            security_definer: false,
+        });
+        objects.sort();
+        Ok(())
+    }
 
-        if role.is_superuser {
-            sql.push(format!("ALTER ROLE {} SUPERUSER", name));
+    fn alter_schema_owner(&mut self, rest: &str) -> Result<()> {
+        let Some((schema, owner)) = rest.split_once(" OWNER TO ") else {
+            bail!("Unsupported ALTER SCHEMA: {}", rest);
+        };
+        self.check_role_exists(owner)?;
+        let objects = &mut self.context.snapshot_mut().objects;
+        match objects
+            .iter_mut()
+            .find(|o| o.kind == ObjectKind::Schema && o.schema == schema)
+        {
+            Some(object) => object.owner = Some(owner.to_string()),
+            None => bail!("Schema {} does not exist", schema),
+        }
+        Ok(())
+    }
+
+    fn alter_row_security(&mut self, rest: &str) -> Result<()> {
+        let Some((table, action)) = rest.split_once(' ') else {
+            bail!("Unsupported ALTER TABLE: {}", rest);
+        };
+        let security = self.row_security(table)?;
+        match action {
+            "ENABLE ROW LEVEL SECURITY" => security.enabled = true,
+            "DISABLE ROW LEVEL SECURITY" => security.enabled = false,
+            "FORCE ROW LEVEL SECURITY" => security.forced = true,
+            "NO FORCE ROW LEVEL SECURITY" => security.forced = false,
+            _ => bail!("Unsupported ALTER TABLE: {}", rest),
+        }
+        self.prune_row_security();
+        Ok(())
+    }
+
+    fn create_policy(&mut self, rest: &str) -> Result<()> {
+        let (name, table, rest) = self.policy_target(rest)?;
+        let (permissive, rest) = match rest.strip_prefix("AS RESTRICTIVE ") {
+            Some(rest) => (false, rest),
+            None => (true, rest),
+        };
+        let Some((command, rest)) = rest
+            .strip_prefix("FOR ")
+            .and_then(|rest| rest.split_once(' '))
+        else {
+            bail!("Unsupported CREATE POLICY: {}", rest);
+        };
+        let Some(command) = PolicyCommand::parse(command) else {
+            bail!("Unknown policy command: {}", command);
+        };
+        let mut policy = Policy {
+            command,
+            permissive,
+            ..Default::default()
+        };
+        self.policy_clauses(&mut policy, rest)?;
+        let security = self.row_security(&table)?;
+        if security.policies.contains_key(&name) {
+            bail!("Policy {} on {} already exists", name, table);
+        }
+        security.policies.insert(name, policy);
+        Ok(())
+    }
+
+    fn alter_policy(&mut self, rest: &str) -> Result<()> {
+        let (name, table, clauses) = self.policy_target(rest)?;
+        let Some(mut policy) = self.row_security(&table)?.policies.remove(&name) else {
+            bail!("Policy {} on {} does not exist", name, table);
+        };
+        self.policy_clauses(&mut policy, clauses)?;
+        self.row_security(&table)?.policies.insert(name, policy);
+        Ok(())
+    }
+
+    /// The policy name, table and remaining clauses of `"name" ON table ...`,
+    /// checking that the table exists.
+    fn policy_target<'a>(&self, rest: &'a str) -> Result<(String, String, &'a str)> {
+        let Some((name, rest)) = unquote_identifier(rest) else {
+            bail!("Malformed policy name: {}", rest);
+        };
+        let Some(rest) = rest.strip_prefix(" ON ") else {
+            bail!("Malformed policy statement: {}", rest);
+        };
+        let (table, clauses) = rest.split_once(' ').unwrap_or((rest, ""));
+        self.find_object(&format!("TABLE {}", table))?;
+        Ok((name, table.to_string(), clauses))
+    }
+
+    /// Applies the `TO`, `USING` and `WITH CHECK` clauses of a policy
+    /// statement. Postgres keeps expressions in parentheses, and so do we.
+    fn policy_clauses(&self, policy: &mut Policy, clauses: &str) -> Result<()> {
+        let (clauses, with_check) = match clauses.split_once("WITH CHECK ") {
+            Some((clauses, expression)) => (clauses, Some(expression.trim())),
+            None => (clauses, None),
+        };
+        let (clauses, using) = match clauses.split_once("USING ") {
+            Some((clauses, expression)) => (clauses, Some(expression.trim())),
+            None => (clauses, None),
+        };
+        if let Some(roles) = clauses.trim().strip_prefix("TO ") {
+            policy.roles = vec![];
+            for role in roles.split(", ") {
+                if role != PUBLIC {
+                    self.check_role_exists(role)?;
+                    policy.roles.push(role.to_string());
+                }
+            }
+        } else if !clauses.trim().is_empty() {
+            bail!("Unsupported policy clause: {}", clauses);
+        }
+        if let Some(using) = using {
+            policy.using = Some(using.to_string());
+        }
+        if let Some(with_check) = with_check {
+            policy.with_check = Some(with_check.to_string());
+        }
+        Ok(())
+    }
+
+    fn row_security(&mut self, table: &str) -> Result<&mut RowSecurity> {
+        self.find_object(&format!("TABLE {}", table))?;
+        Ok(self
+            .context
+            .snapshot_mut()
+            .policies
+            .entry(table.to_string())
+            .or_default())
+    }
+
+    /// Like the catalog, only keeps tables with row-level security enabled
+    /// or with policies.
+    fn prune_row_security(&mut self) {
+        self.context
+            .snapshot_mut()
+            .policies
+            .retain(|_, security| security.enabled || !security.policies.is_empty());
+    }
+
+    fn grant_membership(&mut self, rest: &str) -> Result<()> {
+        let (rest, admin_option) = match rest.strip_suffix(" WITH ADMIN OPTION") {
+            Some(rest) => (rest, true),
+            None => (rest, false),
+        };
+        let Some((group, member)) = rest.split_once(" TO ") else {
+            bail!("Malformed GRANT: {}", rest);
+        };
+        self.check_role_exists(group)?;
+        let member_of = &mut self.role_mut(member)?.member_of;
+        match member_of.iter_mut().find(|m| m.role == group) {
+            // Granting again can add, but never remove, the admin option
+            Some(membership) => membership.admin_option |= admin_option,
+            None => member_of.push(SnapshotMembership {
+                role: group.to_string(),
+                admin_option,
+            }),
+        }
+        Ok(())
+    }
+
+    fn revoke_membership(&mut self, rest: &str) -> Result<()> {
+        let (rest, admin_only) = match rest.strip_prefix("ADMIN OPTION FOR ") {
+            Some(rest) => (rest, true),
+            None => (rest, false),
+        };
+        let Some((group, member)) = rest.split_once(" FROM ") else {
+            bail!("Malformed REVOKE: {}", rest);
+        };
+        let member_of = &mut self.role_mut(member)?.member_of;
+        if admin_only {
+            member_of
+                .iter_mut()
+                .filter(|m| m.role == group)
+                .for_each(|m| m.admin_option = false);
         } else {
-            sql.push(format!("ALTER ROLE {} NOSUPERUSER", name));
+            member_of.retain(|m| m.role != group);
         }
-        sql
+        Ok(())
     }
 
-    fn analyze_memberships(&mut self, name: &str, role: &crate::spec::Role) -> Vec<String> {
-        let mut sql = vec![];
-        for member in &role.member_of {
-            sql.push(format!("GRANT {} TO {}", member, name));
+    fn grant_privileges(&mut self, privs: &str, rest: &str) -> Result<()> {
+        let (rest, grant_option) = match rest.strip_suffix(" WITH GRANT OPTION") {
+            Some(rest) => (rest, true),
+            None => (rest, false),
+        };
+        let Some((target, grantee)) = rest.rsplit_once(" TO ") else {
+            bail!("Malformed GRANT: {}", rest);
+        };
+        let object = self.find_object(target)?;
+        let privs = parse_privileges(&object, privs)?;
+
+        let privileges = &mut self.role_mut(grantee)?.privileges;
+        let index = match privileges.iter().position(|p| is_on(p, &object)) {
+            Some(index) => index,
+            None => {
+                privileges.push(SnapshotPrivilege {
+                    kind: object.kind,
+                    schema: object.schema.clone(),
+                    name: object.unqualified_name.clone(),
+                    privs: vec![],
+                    grantable: vec![],
+                });
+                privileges.len() - 1
+            }
+        };
+        let privilege = &mut privileges[index];
+        for privilege_type in privs {
+            add(&mut privilege.privs, privilege_type);
+            if grant_option {
+                add(&mut privilege.grantable, privilege_type);
+            }
         }
-        sql
+        Ok(())
     }
 
-    fn get_default_permissions(&mut self, role: &str) -> Vec<DefaultPrivilege> {
-        vec![]
+    fn revoke_privileges(&mut self, privs: &str, rest: &str) -> Result<()> {
+        let (privs, grant_option_only) = match privs.strip_prefix("GRANT OPTION FOR ") {
+            Some(privs) => (privs, true),
+            None => (privs, false),
+        };
+        let Some((target, grantee)) = rest.rsplit_once(" FROM ") else {
+            bail!("Malformed REVOKE: {}", rest);
+        };
+        let object = self.find_object(target)?;
+        let privs = parse_privileges(&object, privs)?;
+
+        let privileges = &mut self.role_mut(grantee)?.privileges;
+        for privilege in privileges.iter_mut().filter(|p| is_on(p, &object)) {
+            privilege.grantable.retain(|p| !privs.contains(p));
+            if !grant_option_only {
+                privilege.privs.retain(|p| !privs.contains(p));
+            }
+        }
+        privileges.retain(|p| !p.privs.is_empty());
+        Ok(())
+    }
+
+    /// Looks up the object named by e.g. `TABLE finance.q2_results`.
+    fn find_object(&self, target: &str) -> Result<DatabaseObject> {
+        let Some((keyword, name)) = target.split_once(' ') else {
+            bail!("Malformed object: {}", target);
+        };
+        let (schema, unqualified_name) = match name.split_once('.') {
+            Some((schema, unqualified_name)) => (schema, Some(unqualified_name)),
+            None => (name, None),
+        };
+        self.snapshot()
+            .objects
+            .iter()
+            .find(|o| {
+                o.kind.grant_keyword() == keyword
+                    && o.schema == schema
+                    && o.name.as_deref() == unqualified_name
+            })
+            .map(|o| DatabaseObject::new(o.kind, o.schema.clone(), o.name.clone()))
+            .ok_or_else(|| anyhow::anyhow!("{} does not exist", target))
+    }
+
+    fn check_role_exists(&self, name: &str) -> Result<()> {
+        if !is_predefined_role(name) && !self.snapshot().roles.contains_key(name) {
+            bail!("Role {} does not exist", name);
+        }
+        Ok(())
+    }
+
+    fn role_mut(&mut self, name: &str) -> Result<&mut SnapshotRole> {
+        let roles = &mut self.context.snapshot_mut().roles;
+        // Like Postgres, PUBLIC always exists even when it holds nothing
+        if name == PUBLIC {
+            return Ok(roles.entry(PUBLIC.to_string()).or_default());
+        }
+        match roles.get_mut(name) {
+            Some(role) => Ok(role),
+            None => bail!("Role {} does not exist", name),
+        }
+    }
+}
+
+fn is_on(privilege: &SnapshotPrivilege, object: &DatabaseObject) -> bool {
+    privilege.kind == object.kind
+        && privilege.schema == object.schema
+        && privilege.name == object.unqualified_name
+}
+
+fn add(privs: &mut Vec<PrivilegeType>, privilege_type: PrivilegeType) {
+    if !privs.contains(&privilege_type) {
+        privs.push(privilege_type);
+        privs.sort();
     }
 }
 
-impl RoleAttribute for FakeDbAttribute {
-    fn get_attributes(&self) -> Vec<Attributes> {
-        let mut attrs = vec![];
-        if self.enabled {
-            attrs.push(Attributes::Enabled);
+/// Maps raw privileges such as `SELECT, REFERENCES` to the privilege types
+/// they belong to on `object`.
+fn parse_privileges(object: &DatabaseObject, privs: &str) -> Result<Vec<PrivilegeType>> {
+    let mut types = vec![];
+    for raw in privs.split(", ") {
+        let privilege_type = [PrivilegeType::Read, PrivilegeType::Write]
+            .into_iter()
+            .find(|t| object.kind.raw_privileges(t).contains(&raw));
+        match privilege_type {
+            Some(privilege_type) => add(&mut types, privilege_type),
+            None => bail!("Unsupported privilege {} on {}", raw, object.fqn()),
         }
-        if self.superuser {
-            attrs.push(Attributes::Superuser);
+    }
+    Ok(types)
+}
+
+impl Context for FakeDb {
+    type RoleAttribute = SnapshotAttributes;
+
+    fn database_name(&self) -> &str {
+        self.context.database_name()
+    }
+
+    fn get_roles(&mut self) -> Vec<String> {
+        self.context.get_roles()
+    }
+
+    fn get_role_attributes(&mut self, role: &str) -> Self::RoleAttribute {
+        self.context.get_role_attributes(role)
+    }
+
+    fn get_role_password(&mut self, role: &str) -> Option<String> {
+        self.context.get_role_password(role)
+    }
+
+    fn get_role_settings(&mut self, role: &str) -> Settings {
+        self.context.get_role_settings(role)
+    }
+
+    fn get_role_memberships(&mut self, role: &str) -> RoleMembership {
+        self.context.get_role_memberships(role)
+    }
+
+    fn get_role_ownerships(&mut self, role: &str) -> Vec<DatabaseObject> {
+        self.context.get_role_ownerships(role)
+    }
+
+    fn get_role_permissions(&mut self, role: &str) -> Vec<Privilege> {
+        self.context.get_role_permissions(role)
+    }
+
+    fn get_objects(&mut self) -> Vec<DatabaseObject> {
+        self.context.get_objects()
+    }
+
+    fn get_security_definer_functions(&mut self) -> Vec<DatabaseObject> {
+        self.context.get_security_definer_functions()
+    }
+
+    fn analyze_attributes(&mut self, name: &str, role: &crate::spec::Role) -> Vec<String> {
+        self.context.analyze_attributes(name, role)
+    }
+
+    fn analyze_password(&mut self, name: &str, role: &crate::spec::Role) -> Result<Vec<String>> {
+        self.context.analyze_password(name, role)
+    }
+
+    fn analyze_settings(&mut self, name: &str, role: &crate::spec::Role) -> Vec<String> {
+        self.context.analyze_settings(name, role)
+    }
+
+    fn analyze_personal_schema(
+        &mut self,
+        name: &str,
+        role: &crate::spec::Role,
+        scope: &Scope,
+    ) -> Vec<String> {
+        self.context.analyze_personal_schema(name, role, scope)
+    }
+
+    fn analyze_memberships(
+        &mut self,
+        name: &str,
+        role: &crate::spec::Role,
+        scope: &Scope,
+    ) -> Vec<String> {
+        self.context.analyze_memberships(name, role, scope)
+    }
+
+    fn analyze_privileges(
+        &mut self,
+        name: &str,
+        role: &crate::spec::Role,
+        scope: &Scope,
+    ) -> Vec<String> {
+        self.context.analyze_privileges(name, role, scope)
+    }
+
+    fn get_default_permissions(&mut self, role: &str) -> Vec<DefaultPrivilege> {
+        self.context.get_default_permissions(role)
+    }
+
+    fn get_policies(&mut self) -> TablePolicies {
+        self.context.get_policies()
+    }
+
+    fn analyze_policies(&mut self, policies: &TablePolicies, scope: &Scope) -> Vec<String> {
+        self.context.analyze_policies(policies, scope)
+    }
+}
+
+/// Reads the double-quoted identifier `rest` starts with, returning it with
+/// its doubled quotes undone, and what follows it.
+fn unquote_identifier(rest: &str) -> Option<(String, &str)> {
+    let mut chars = rest.strip_prefix('"')?.char_indices().peekable();
+    let mut name = String::new();
+    while let Some((i, c)) = chars.next() {
+        if c != '"' {
+            name.push(c);
+        } else if chars.next_if(|(_, c)| *c == '"').is_some() {
+            name.push('"');
+        } else {
+            return Some((name, &rest[i + 2..]));
         }
-        attrs
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::role_analyzer;
+    use crate::context::ObjectKind;
+    use crate::spec::DatabaseSpec;
+
+    fn plan(db: &FakeDb, spec: &str) -> Vec<String> {
+        let mut spec: DatabaseSpec = serde_yaml::from_str(spec).unwrap();
+        let mut sql = vec![];
+        role_analyzer(&mut sql, db.clone(), &mut spec).unwrap();
+        sql
+    }
+
+    #[test]
+    fn test_plan_apply_replan_is_empty() {
+        let spec = "
+            version: 1
+            adapter: fake
+            roles:
+              PUBLIC:
+                privileges:
+                  schemas:
+                    read:
+                      - marketing
+              analyst:
+                can_login: no
+              developer:
+                can_login: no
+                member_of:
+                  - analyst
+              alice:
+                can_login: yes
+                is_superuser: yes
+                member_of:
+                  - developer
+              bob:
+                can_login: yes
+                member_of:
+                  - role: analyst
+                    with_admin_option: yes
+                privileges:
+                  schemas:
+                    read:
+                      - finance
+                  tables:
+                    read:
+                      - finance.*
+              carol:
+                has_personal_schema: yes
+                settings:
+                  statement_timeout: 300000
+                  search_path: $user, public
+                  databases:
+                    warehouse:
+                      work_mem: 64MB
+                member_of:
+                  - developer
+                privileges:
+                  schemas:
+                    read:
+                      - object: marketing
+                        with_grant_option: yes
+                    write:
+                      - marketing
+                  functions:
+                    read:
+                      - finance.total_revenue(integer)
+            policies:
+              finance.q2_results:
+                forced: yes
+                policies:
+                  Read own region:
+                    command: select
+                    roles:
+                      - analyst
+                      - developer
+                    using: region = current_setting('app.region'::text)
+                  writers:
+                    command: insert
+                    permissive: no
+                    roles:
+                      - developer
+                    with_check: region = 'emea'
+        ";
+        let mut db = FakeDb::default();
+
+        let sql = plan(&db, spec);
+        assert!(!sql.is_empty());
+        db.apply_all(&sql).unwrap();
+
+        assert_eq!(plan(&db, spec), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_password_and_valid_until_converge() {
+        let file = std::env::temp_dir().join(format!(
+            "permirust-password-converge-{}",
+            std::process::id()
+        ));
+        std::fs::write(&file, "s3cret\n").unwrap();
+        let spec = format!(
+            "
+            version: 1
+            adapter: fake
+            roles:
+              alice:
+                can_login: yes
+                is_superuser: yes
+                password:
+                  file: {}
+                valid_until: 2030-06-30
+            ",
+            file.display()
+        );
+        let mut db = FakeDb::default();
+
+        let sql = plan(&db, &spec);
+        assert!(sql.contains(&"ALTER ROLE alice VALID UNTIL '2030-06-30T00:00:00Z'".to_string()));
+        assert!(sql.iter().all(|s| !s.contains("s3cret")));
+        db.apply_all(&sql).unwrap();
+        assert_eq!(plan(&db, &spec), Vec::<String>::new());
+
+        // Left out, the expiry stays
+        assert_eq!(
+            plan(&db, &spec.replace("valid_until: 2030-06-30", "")),
+            Vec::<String>::new()
+        );
+
+        std::fs::write(&file, "rotated\n").unwrap();
+        let sql = plan(&db, &spec.replace("2030-06-30", "infinity"));
+        assert_eq!(sql.len(), 2);
+        assert_eq!(sql[0], "ALTER ROLE alice VALID UNTIL 'infinity'");
+        assert!(sql[1].starts_with("ALTER ROLE alice PASSWORD 'SCRAM-SHA-256$"));
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn test_expired_entries_are_revoked() {
+        let spec = "
+            version: 1
+            adapter: fake
+            roles:
+              analyst:
+                can_login: no
+              bob:
+                can_login: yes
+                settings:
+                  statement_timeout: 1min
+                member_of:
+                  - role: analyst
+                    expires: 2999-01-01
+                privileges:
+                  schemas:
+                    read:
+                      - object: finance
+                        with_grant_option: yes
+                      - marketing
+                    write:
+                      - finance
+                  tables:
+                    read:
+                      - finance.q2_results
+                    write:
+                      - object: finance.q2_results
+                        expires: 2020-01-01T18:00:00Z
+        ";
+
+        assert_eq!(
+            plan(&FakeDb::default(), spec),
+            vec!["REVOKE INSERT, UPDATE, DELETE, TRUNCATE, TRIGGER ON TABLE finance.q2_results FROM bob"]
+        );
+    }
+
+    #[test]
+    fn test_apply_updates_context() {
+        let mut db = FakeDb::default();
+        db.apply_all(&[
+            "ALTER ROLE carol NOLOGIN CONNECTION LIMIT 5".into(),
+            "REVOKE analyst FROM carol".into(),
+            "GRANT developer TO carol WITH ADMIN OPTION".into(),
+            "REVOKE GRANT OPTION FOR USAGE ON SCHEMA finance FROM bob".into(),
+            "GRANT EXECUTE ON FUNCTION finance.total_revenue(integer) TO carol".into(),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            db.get_role_attributes("carol"),
+            SnapshotAttributes {
+                inherit: true,
+                connection_limit: Some(5),
+                ..Default::default()
+            }
+        );
+        let memberships = db.get_role_memberships("carol");
+        assert_eq!(memberships.memberships, vec!["developer"]);
+        assert!(memberships.is_admin_of("developer"));
+
+        let finance = DatabaseObject::new(ObjectKind::Schema, "finance".into(), None);
+        let bob = db.get_role_permissions("bob");
+        let schema = bob.iter().find(|p| p.object == finance).unwrap();
+        assert!(schema.grantable.is_empty());
+
+        assert!(db
+            .get_role_permissions("carol")
+            .iter()
+            .any(|p| p.object.kind == ObjectKind::Function));
+    }
+
+    #[test]
+    fn test_apply_rejects_unknown_targets() {
+        let mut db = FakeDb::default();
+
+        assert!(db.apply("GRANT analyst TO nobody").is_err());
+        assert!(db.apply("GRANT nobody TO alice").is_err());
+        assert!(db
+            .apply("GRANT SELECT ON TABLE finance.missing TO alice")
+            .is_err());
+        assert!(db.apply("DROP ROLE alice").is_err());
     }
 }
@@ -1,2 +1,3 @@
 pub mod fakedb;
 pub mod postgres;
+pub mod snapshot;
@@ -1,10 +1,18 @@
 //! Postgres context implementation
+use crate::analyzer::{
+    plan_attributes, plan_memberships, plan_personal_schema, plan_privileges, plan_settings,
+};
 use crate::context::{
-    Context, DatabaseObject, DefaultPrivilege, ObjectKind, Privilege, PrivilegeType, RoleAttribute,
+    Context, DatabaseObject, DefaultPrivilege, ObjectKind, Privilege, RoleAttribute, PUBLIC,
 };
+use crate::password::plan_password;
+use crate::policy::{plan_policies, Policy, PolicyCommand, RowSecurity, TablePolicies};
+use crate::scope::Scope;
+use crate::spec::Settings;
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use itertools::Itertools;
-use log::debug;
+use log::{debug, warn};
 use postgres::NoTls;
 use std::collections::{HashMap, HashSet};
 
@@ -44,6 +52,12 @@ pub struct PostgresRoleAttributes {
     enabled: bool,
     superuser: bool,
     createdb: bool,
+    createrole: bool,
+    inherit: bool,
+    replication: bool,
+    bypassrls: bool,
+    connection_limit: i32,
+    valid_until: Option<DateTime<Utc>>,
 }
 
 impl PostgresRoleAttributes {
@@ -52,6 +66,12 @@ impl PostgresRoleAttributes {
             enabled,
             superuser,
             createdb: false,
+            createrole: false,
+            inherit: true,
+            replication: false,
+            bypassrls: false,
+            connection_limit: -1,
+            valid_until: None,
         }
     }
 }
@@ -67,6 +87,27 @@ impl RoleAttribute for PostgresRoleAttributes {
         if self.createdb {
             attrs.push(crate::context::Attributes::CreateDb);
         }
+        if self.createrole {
+            attrs.push(crate::context::Attributes::CreateRole);
+        }
+        if self.inherit {
+            attrs.push(crate::context::Attributes::Inherit);
+        }
+        if self.replication {
+            attrs.push(crate::context::Attributes::Replication);
+        }
+        if self.bypassrls {
+            attrs.push(crate::context::Attributes::BypassRls);
+        }
+        // -1 means no limit
+        if self.connection_limit >= 0 {
+            attrs.push(crate::context::Attributes::ConnectionLimit(
+                self.connection_limit,
+            ));
+        }
+        if let Some(valid_until) = self.valid_until {
+            attrs.push(crate::context::Attributes::ValidUntil(valid_until));
+        }
         attrs
     }
 }
@@ -97,34 +138,69 @@ impl Context for PostgresClient {
             enabled: row.get(2),
             superuser: row.get(8),
             createdb: row.get(4),
+            createrole: row.get(5),
+            inherit: row.get(6),
+            replication: row.get(7),
+            bypassrls: row.get(1),
+            connection_limit: row.get(3),
+            valid_until: row.get(9),
         }
     }
 
+    /// Settings for every database have no database name.
+    fn get_role_settings(&mut self, role: &str) -> Settings {
+        let rows = self
+            .query(crate::queries::Q_GET_ROLE_SETTINGS, &[])
+            .unwrap();
+        let mut settings = Settings::default();
+        for row in rows.iter().filter(|row| row.get::<_, String>(0) == role) {
+            let setting: String = row.get(2);
+            let Some((key, value)) = setting.split_once('=') else {
+                continue;
+            };
+            let values = match row.get::<_, Option<String>>(1) {
+                Some(database) => settings.databases.entry(database).or_default(),
+                None => &mut settings.all,
+            };
+            values.insert(key.to_string(), value.to_string());
+        }
+        settings
+    }
+
+    fn get_role_password(&mut self, role: &str) -> Option<String> {
+        let rows = self
+            .query(crate::queries::Q_GET_ROLE_PASSWORD, &[&role])
+            .unwrap();
+        rows.first().and_then(|row| row.get(0))
+    }
+
     fn get_role_memberships(&mut self, role: &str) -> crate::context::RoleMembership {
-        let members = self
-            .query(crate::queries::Q_ALL_MEMBERSHIPS, &[])
-            .unwrap()
+        let rows = self.query(crate::queries::Q_ALL_MEMBERSHIPS, &[]).unwrap();
+        let rows: Vec<_> = rows
             .iter()
-            .filter_map(|row| {
-                if row.get::<_, String>(0) == role {
-                    Some(row.get(1))
-                } else {
-                    None
-                }
-            })
+            .filter(|row| row.get::<_, String>(0) == role)
             .collect();
 
-        crate::context::RoleMembership {
-            memberships: members,
-        }
+        let members = rows.iter().map(|row| row.get(1)).collect();
+        let admin = rows
+            .iter()
+            .filter(|row| row.get::<_, bool>(2))
+            .map(|row| row.get(1))
+            .collect();
+
+        crate::context::RoleMembership::new(members).with_admin_option(admin)
     }
 
+    /// Objects whose ownership is derived are skipped: sequences that belong
+    /// to a serial or identity column follow their table, and extension
+    /// members follow the extension.
     fn get_role_ownerships(&mut self, role: &str) -> Vec<DatabaseObject> {
         self.query(crate::queries::Q_RAW_OBJECT_ATTRIBUTES, &[])
             .unwrap()
             .iter()
             .filter_map(|row| {
-                if row.get::<_, String>(3) == role {
+                let is_derived = row.get::<_, bool>(4) || row.get::<_, bool>(5);
+                if row.get::<_, String>(3) == role && !is_derived {
                     let kind = ObjectKind::from(row.get::<_, String>(0).as_str());
                     Some(DatabaseObject {
                         kind,
@@ -142,7 +218,7 @@ impl Context for PostgresClient {
     ///
     ///
     /// The main query returns a table of granted permissions:
-    /// (grantee, objkind, schema, unqualified_name, privlege_type)
+    /// (grantee, objkind, schema, unqualified_name, privlege_type, is_grantable)
     ///
     /// We filter for a given row and then group by the DatabaseObject
     /// to get a list of privileges for each object.
@@ -176,64 +252,105 @@ impl Context for PostgresClient {
         let mut permissions = vec![];
         for (object, grp) in grouped_rows {
             debug!("Processing object {:?}", object);
-            let privs = grp
-                .into_iter()
-                .map(|row| match row.get::<_, String>(4).as_str() {
-                    // TODO: Each ObjectKind should have an associated function
-                    // to convert the raw privilege to a PrivilegeType
-                    "SELECT" => PrivilegeType::Read,
-                    "INSERT" => PrivilegeType::Write,
-                    _ => PrivilegeType::Write,
-                })
-                .collect();
-            debug!("Privs: {:?}", privs);
-            permissions.push(Privilege { object, privs });
+            let mut privs = HashSet::new();
+            let mut grantable = HashSet::new();
+            for row in grp {
+                let raw = row.get::<_, String>(4);
+                let Some(privilege) = object.kind.to_privilege(&raw) else {
+                    warn!("Ignoring unknown privilege {} on {}", raw, object.fqn());
+                    continue;
+                };
+                if row.get::<_, bool>(5) {
+                    grantable.insert(privilege);
+                }
+                privs.insert(privilege);
+            }
+            debug!("Privs: {:?}, grantable: {:?}", privs, grantable);
+            if privs.is_empty() {
+                continue;
+            }
+            permissions.push(Privilege {
+                object,
+                privs,
+                grantable,
+            });
         }
         permissions
     }
 
+    /// Extension members are left out, their privileges belong to the
+    /// extension.
+    fn get_objects(&mut self) -> Vec<DatabaseObject> {
+        self.query(crate::queries::Q_RAW_OBJECT_ATTRIBUTES, &[])
+            .unwrap()
+            .iter()
+            .filter(|row| !row.get::<_, bool>(5))
+            .map(|row| DatabaseObject {
+                kind: ObjectKind::from(row.get::<_, String>(0).as_str()),
+                schema: row.get(1),
+                unqualified_name: row.get(2),
+            })
+            .collect()
+    }
+
+    fn get_security_definer_functions(&mut self) -> Vec<DatabaseObject> {
+        self.query(crate::queries::Q_GET_SECURITY_DEFINER_FUNCTIONS, &[])
+            .unwrap()
+            .iter()
+            .map(|row| DatabaseObject::new(ObjectKind::Function, row.get(0), Some(row.get(1))))
+            .collect()
+    }
+
     fn analyze_attributes(&mut self, name: &str, spec_role: &crate::spec::Role) -> Vec<String> {
-        let mut sql = vec![];
         let current = self.get_role_attributes(name);
+        plan_attributes(name, &current, spec_role)
+    }
 
-        if current.enabled != spec_role.can_login {
-            if spec_role.can_login {
-                sql.push(format!("ALTER ROLE {} LOGIN", name));
-            } else {
-                sql.push(format!("ALTER ROLE {} NOLOGIN", name));
-            }
-        }
+    fn analyze_password(
+        &mut self,
+        name: &str,
+        spec_role: &crate::spec::Role,
+    ) -> Result<Vec<String>> {
+        let current = self.get_role_password(name);
+        plan_password(name, current.as_deref(), spec_role)
+    }
 
-        if current.superuser != spec_role.is_superuser {
-            if spec_role.is_superuser {
-                sql.push(format!("ALTER ROLE {} SUPERUSER", name));
-            } else {
-                sql.push(format!("ALTER ROLE {} NOSUPERUSER", name));
-            }
-        }
+    fn analyze_settings(&mut self, name: &str, spec_role: &crate::spec::Role) -> Vec<String> {
+        let current = self.get_role_settings(name);
+        plan_settings(name, &current, spec_role)
+    }
 
-        sql
+    fn analyze_personal_schema(
+        &mut self,
+        name: &str,
+        spec_role: &crate::spec::Role,
+        scope: &Scope,
+    ) -> Vec<String> {
+        let objects = self.get_objects();
+        let owned = self.get_role_ownerships(name);
+        plan_personal_schema(name, &objects, &owned, spec_role, scope)
     }
 
-    fn analyze_memberships(&mut self, name: &str, spec_role: &crate::spec::Role) -> Vec<String> {
-        let mut sql = vec![];
+    fn analyze_memberships(
+        &mut self,
+        name: &str,
+        spec_role: &crate::spec::Role,
+        scope: &Scope,
+    ) -> Vec<String> {
         let current = self.get_role_memberships(name);
+        plan_memberships(name, &current, spec_role, scope)
+    }
 
-        let current_members: HashSet<String> = current.memberships.into_iter().collect();
-        let spec_members: HashSet<String> = spec_role.member_of.iter().cloned().collect();
-
-        let to_add: Vec<_> = spec_members.difference(&current_members).collect();
-        let to_remove: Vec<_> = current_members.difference(&spec_members).collect();
-
-        for member in to_add {
-            sql.push(format!("GRANT {} TO {}", member, name));
-        }
-
-        for member in to_remove {
-            sql.push(format!("REVOKE {} FROM {}", member, name));
-        }
-
-        sql
+    fn analyze_privileges(
+        &mut self,
+        name: &str,
+        spec_role: &crate::spec::Role,
+        scope: &Scope,
+    ) -> Vec<String> {
+        let objects = self.get_objects();
+        let current = self.get_role_permissions(name);
+        let owned = self.get_role_ownerships(name);
+        plan_privileges(name, current, &owned, &objects, spec_role, scope)
     }
 
     fn get_default_permissions(&mut self, role: &str) -> Vec<DefaultPrivilege> {
@@ -264,11 +381,26 @@ impl Context for PostgresClient {
         let mut permissions = vec![];
         for (object, grp) in grouped_rows {
             debug!("Processing object {:?}", object);
-            let privs = grp
+            let privs: HashSet<_> = grp
                 .into_iter()
-                .map(|row| object.1.to_privilege(&row.get::<_, String>(4)))
+                .filter_map(|row| {
+                    let raw = row.get::<_, String>(4);
+                    let privilege = object.1.to_privilege(&raw);
+                    if privilege.is_none() {
+                        warn!(
+                            "Ignoring unknown default privilege {} on {} in {}",
+                            raw,
+                            object.1,
+                            object.0.fqn()
+                        );
+                    }
+                    privilege
+                })
                 .collect();
             debug!("Privs: {:?}", privs);
+            if privs.is_empty() {
+                continue;
+            }
             permissions.push(DefaultPrivilege {
                 parent: object.0,
                 child: object.1,
@@ -278,4 +410,46 @@ impl Context for PostgresClient {
 
         permissions
     }
+
+    /// Postgres keeps expressions as parse trees, they are read back the way
+    /// `pg_get_expr` prints them.
+    fn get_policies(&mut self) -> TablePolicies {
+        let rows = self.query(crate::queries::Q_GET_POLICIES, &[]).unwrap();
+        let mut policies = TablePolicies::new();
+        for row in rows {
+            let table = format!("{}.{}", row.get::<_, String>(0), row.get::<_, String>(1));
+            let security = policies.entry(table).or_insert_with(|| RowSecurity {
+                enabled: row.get(2),
+                forced: row.get(3),
+                ..Default::default()
+            });
+            let Some(name) = row.get::<_, Option<String>>(4) else {
+                continue;
+            };
+            let mut roles: Vec<String> = row.get(7);
+            if roles == [PUBLIC] {
+                roles.clear();
+            }
+            let policy = Policy {
+                command: match row.get::<_, String>(5).as_str() {
+                    "r" => PolicyCommand::Select,
+                    "a" => PolicyCommand::Insert,
+                    "w" => PolicyCommand::Update,
+                    "d" => PolicyCommand::Delete,
+                    _ => PolicyCommand::All,
+                },
+                permissive: row.get(6),
+                roles,
+                using: row.get(8),
+                with_check: row.get(9),
+            };
+            security.policies.insert(name, policy);
+        }
+        policies
+    }
+
+    fn analyze_policies(&mut self, policies: &TablePolicies, scope: &Scope) -> Vec<String> {
+        let current = self.get_policies();
+        plan_policies(&current, policies, scope)
+    }
 }
@@ -0,0 +1,632 @@
+//! Offline snapshot context implementation
+//!
+//! A snapshot is a YAML or JSON file holding everything permirust reads from
+//! a database: roles and their attributes, memberships, object owners,
+//! privileges and default privileges. It is captured from a live context with
+//! [`Snapshot::capture`] and can then stand in for that database, so
+//! generating, planning and reporting work without credentials.
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use anyhow::{Context as _, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::analyzer::{
+    plan_attributes, plan_memberships, plan_personal_schema, plan_privileges, plan_settings,
+};
+use crate::context::{
+    Attributes, Context, DatabaseObject, DefaultPrivilege, ObjectKind, Privilege, PrivilegeType,
+    RoleAttribute, RoleMembership, PUBLIC,
+};
+use crate::password::plan_password;
+use crate::policy::{plan_policies, TablePolicies};
+use crate::scope::Scope;
+use crate::spec::Settings;
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Snapshot {
+    /// The name reported by the captured context, e.g. `postgres`
+    pub database: String,
+    #[serde(default)]
+    pub objects: Vec<SnapshotObject>,
+    /// Every role by name. PUBLIC is included when it holds privileges.
+    #[serde(default)]
+    pub roles: BTreeMap<String, SnapshotRole>,
+    /// Row-level security by table, see [`crate::policy`]
+    #[serde(skip_serializing_if = "TablePolicies::is_empty")]
+    #[serde(default)]
+    pub policies: TablePolicies,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SnapshotObject {
+    pub kind: ObjectKind,
+    pub schema: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Left empty when ownership is derived, e.g. from a table or extension
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// Set on functions declared SECURITY DEFINER
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    #[serde(default)]
+    pub security_definer: bool,
+}
+
+impl SnapshotObject {
+    fn to_object(&self) -> DatabaseObject {
+        DatabaseObject::new(self.kind, self.schema.clone(), self.name.clone())
+    }
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct SnapshotRole {
+    #[serde(flatten)]
+    pub attributes: SnapshotAttributes,
+    /// Password verifiers are never written to snapshot files, only a
+    /// [`crate::adapters::fakedb::FakeDb`] keeps them
+    #[serde(skip)]
+    pub password: Option<String>,
+    #[serde(skip_serializing_if = "Settings::is_empty")]
+    #[serde(default)]
+    pub settings: Settings,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub member_of: Vec<SnapshotMembership>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub privileges: Vec<SnapshotPrivilege>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub default_privileges: Vec<SnapshotDefaultPrivilege>,
+}
+
+/// Attributes left out of a snapshot are false, except `inherit`: roles
+/// inherit the privileges of their groups unless told otherwise, so it is
+/// always written out and true when left out.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct SnapshotAttributes {
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    #[serde(default)]
+    pub can_login: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    #[serde(default)]
+    pub is_superuser: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    #[serde(default)]
+    pub create_db: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    #[serde(default)]
+    pub create_role: bool,
+    #[serde(default = "crate::spec::yes")]
+    pub inherit: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    #[serde(default)]
+    pub replication: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    #[serde(default)]
+    pub bypass_rls: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub connection_limit: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub valid_until: Option<DateTime<Utc>>,
+}
+
+impl Default for SnapshotAttributes {
+    fn default() -> Self {
+        SnapshotAttributes {
+            can_login: false,
+            is_superuser: false,
+            create_db: false,
+            create_role: false,
+            inherit: true,
+            replication: false,
+            bypass_rls: false,
+            connection_limit: None,
+            valid_until: None,
+        }
+    }
+}
+
+impl SnapshotAttributes {
+    fn from_attributes(attributes: &[Attributes]) -> Self {
+        let mut snapshot = SnapshotAttributes {
+            inherit: false,
+            ..Default::default()
+        };
+        for attribute in attributes {
+            match attribute {
+                Attributes::Enabled | Attributes::Login => snapshot.can_login = true,
+                Attributes::Superuser => snapshot.is_superuser = true,
+                Attributes::CreateDb => snapshot.create_db = true,
+                Attributes::CreateRole => snapshot.create_role = true,
+                Attributes::Inherit => snapshot.inherit = true,
+                Attributes::Replication => snapshot.replication = true,
+                Attributes::BypassRls => snapshot.bypass_rls = true,
+                Attributes::ConnectionLimit(n) => snapshot.connection_limit = Some(*n),
+                Attributes::ValidUntil(t) => snapshot.valid_until = Some(*t),
+            }
+        }
+        snapshot
+    }
+}
+
+impl RoleAttribute for SnapshotAttributes {
+    fn get_attributes(&self) -> Vec<Attributes> {
+        let mut attrs = vec![];
+        if self.can_login {
+            attrs.push(Attributes::Enabled);
+        }
+        if self.is_superuser {
+            attrs.push(Attributes::Superuser);
+        }
+        if self.create_db {
+            attrs.push(Attributes::CreateDb);
+        }
+        if self.create_role {
+            attrs.push(Attributes::CreateRole);
+        }
+        if self.inherit {
+            attrs.push(Attributes::Inherit);
+        }
+        if self.replication {
+            attrs.push(Attributes::Replication);
+        }
+        if self.bypass_rls {
+            attrs.push(Attributes::BypassRls);
+        }
+        if let Some(n) = self.connection_limit {
+            attrs.push(Attributes::ConnectionLimit(n));
+        }
+        if let Some(t) = self.valid_until {
+            attrs.push(Attributes::ValidUntil(t));
+        }
+        attrs
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SnapshotMembership {
+    pub role: String,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    #[serde(default)]
+    pub admin_option: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SnapshotPrivilege {
+    pub kind: ObjectKind,
+    pub schema: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub name: Option<String>,
+    pub privs: Vec<PrivilegeType>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub grantable: Vec<PrivilegeType>,
+}
+
+impl SnapshotPrivilege {
+    fn from_privilege(privilege: &Privilege) -> Self {
+        let mut privs: Vec<_> = privilege.privs.iter().copied().collect();
+        let mut grantable: Vec<_> = privilege.grantable.iter().copied().collect();
+        privs.sort();
+        grantable.sort();
+        SnapshotPrivilege {
+            kind: privilege.object.kind,
+            schema: privilege.object.schema.clone(),
+            name: privilege.object.unqualified_name.clone(),
+            privs,
+            grantable,
+        }
+    }
+
+    fn to_privilege(&self) -> Privilege {
+        Privilege::new(
+            DatabaseObject::new(self.kind, self.schema.clone(), self.name.clone()),
+            self.privs.clone(),
+        )
+        .with_grant_option(self.grantable.clone())
+    }
+}
+
+/// Privileges granted on objects of `kind` created in `schema` in future.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SnapshotDefaultPrivilege {
+    pub schema: String,
+    pub kind: ObjectKind,
+    pub privs: Vec<PrivilegeType>,
+}
+
+impl Snapshot {
+    /// Captures a snapshot of everything `context` reports.
+    pub fn capture<T: Context>(context: &mut T) -> Snapshot
+    where
+        T::RoleAttribute: RoleAttribute,
+    {
+        let mut snapshot = Snapshot {
+            database: context.database_name().to_string(),
+            ..Default::default()
+        };
+
+        let mut owners: HashMap<DatabaseObject, String> = HashMap::new();
+        for name in context.get_roles() {
+            for object in context.get_role_ownerships(&name) {
+                owners.insert(object, name.clone());
+            }
+
+            let attributes = context.get_role_attributes(&name).get_attributes();
+            let membership = context.get_role_memberships(&name);
+            let mut member_of: Vec<_> = membership
+                .memberships
+                .iter()
+                .map(|m| SnapshotMembership {
+                    role: m.clone(),
+                    admin_option: membership.is_admin_of(m),
+                })
+                .collect();
+            member_of.sort();
+
+            let role = SnapshotRole {
+                attributes: SnapshotAttributes::from_attributes(&attributes),
+                settings: context.get_role_settings(&name),
+                password: None,
+                member_of,
+                privileges: capture_privileges(context, &name),
+                default_privileges: capture_default_privileges(context, &name),
+            };
+            snapshot.roles.insert(name, role);
+        }
+
+        let public = capture_privileges(context, PUBLIC);
+        if !public.is_empty() {
+            snapshot.roles.insert(
+                PUBLIC.to_string(),
+                SnapshotRole {
+                    privileges: public,
+                    ..Default::default()
+                },
+            );
+        }
+
+        let security_definer = context.get_security_definer_functions();
+        snapshot.objects = context
+            .get_objects()
+            .into_iter()
+            .map(|o| SnapshotObject {
+                owner: owners.get(&o).cloned(),
+                security_definer: security_definer.contains(&o),
+                kind: o.kind,
+                schema: o.schema,
+                name: o.unqualified_name,
+            })
+            .collect();
+        snapshot.objects.sort();
+        snapshot.policies = context.get_policies();
+
+        snapshot
+    }
+
+    /// Reads a snapshot, as JSON if the file ends in `.json` and as YAML
+    /// otherwise.
+    pub fn read_file(path: &str) -> Result<Snapshot> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open snapshot file: {}", path))?;
+        let snapshot = if Path::new(path).extension().is_some_and(|e| e == "json") {
+            serde_json::from_reader(file)?
+        } else {
+            serde_yaml::from_reader(file)?
+        };
+        Ok(snapshot)
+    }
+
+    pub fn to_yaml(&self) -> Result<String, serde_yaml::Error> {
+        serde_yaml::to_string(&self)
+    }
+
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(&self)
+    }
+}
+
+fn capture_privileges<T: Context>(context: &mut T, role: &str) -> Vec<SnapshotPrivilege> {
+    let mut privileges: Vec<_> = context
+        .get_role_permissions(role)
+        .iter()
+        .map(SnapshotPrivilege::from_privilege)
+        .collect();
+    privileges.sort();
+    privileges
+}
+
+fn capture_default_privileges<T: Context>(
+    context: &mut T,
+    role: &str,
+) -> Vec<SnapshotDefaultPrivilege> {
+    let mut defaults: Vec<_> = context
+        .get_default_permissions(role)
+        .into_iter()
+        .map(|d| {
+            let mut privs: Vec<_> = d.privs.into_iter().collect();
+            privs.sort();
+            SnapshotDefaultPrivilege {
+                schema: d.parent.schema,
+                kind: d.child,
+                privs,
+            }
+        })
+        .collect();
+    defaults.sort();
+    defaults
+}
+
+/// A context backed by a [`Snapshot`] instead of a live database.
+#[derive(Debug, Clone)]
+pub struct SnapshotContext {
+    snapshot: Snapshot,
+}
+
+impl SnapshotContext {
+    pub fn new(snapshot: Snapshot) -> Self {
+        SnapshotContext { snapshot }
+    }
+
+    pub fn read_file(path: &str) -> Result<Self> {
+        Ok(SnapshotContext::new(Snapshot::read_file(path)?))
+    }
+
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.snapshot
+    }
+
+    pub fn snapshot_mut(&mut self) -> &mut Snapshot {
+        &mut self.snapshot
+    }
+
+    fn role(&self, name: &str) -> Option<&SnapshotRole> {
+        self.snapshot.roles.get(name)
+    }
+}
+
+impl Context for SnapshotContext {
+    type RoleAttribute = SnapshotAttributes;
+
+    fn database_name(&self) -> &str {
+        &self.snapshot.database
+    }
+
+    fn get_roles(&mut self) -> Vec<String> {
+        self.snapshot
+            .roles
+            .keys()
+            .filter(|name| *name != PUBLIC)
+            .cloned()
+            .collect()
+    }
+
+    fn get_role_attributes(&mut self, role: &str) -> SnapshotAttributes {
+        self.role(role)
+            .map(|r| r.attributes.clone())
+            .unwrap_or_default()
+    }
+
+    fn get_role_password(&mut self, role: &str) -> Option<String> {
+        self.role(role).and_then(|r| r.password.clone())
+    }
+
+    fn get_role_settings(&mut self, role: &str) -> Settings {
+        self.role(role)
+            .map(|r| r.settings.clone())
+            .unwrap_or_default()
+    }
+
+    fn get_role_memberships(&mut self, role: &str) -> RoleMembership {
+        let member_of = self.role(role).map_or(&[][..], |r| &r.member_of[..]);
+        RoleMembership::new(member_of.iter().map(|m| m.role.clone()).collect()).with_admin_option(
+            member_of
+                .iter()
+                .filter(|m| m.admin_option)
+                .map(|m| m.role.clone())
+                .collect(),
+        )
+    }
+
+    fn get_role_ownerships(&mut self, role: &str) -> Vec<DatabaseObject> {
+        self.snapshot
+            .objects
+            .iter()
+            .filter(|o| o.owner.as_deref() == Some(role))
+            .map(SnapshotObject::to_object)
+            .collect()
+    }
+
+    fn get_role_permissions(&mut self, role: &str) -> Vec<Privilege> {
+        self.role(role).map_or(vec![], |r| {
+            r.privileges
+                .iter()
+                .map(SnapshotPrivilege::to_privilege)
+                .collect()
+        })
+    }
+
+    fn get_objects(&mut self) -> Vec<DatabaseObject> {
+        self.snapshot
+            .objects
+            .iter()
+            .map(SnapshotObject::to_object)
+            .collect()
+    }
+
+    fn get_security_definer_functions(&mut self) -> Vec<DatabaseObject> {
+        self.snapshot
+            .objects
+            .iter()
+            .filter(|o| o.security_definer)
+            .map(SnapshotObject::to_object)
+            .collect()
+    }
+
+    fn analyze_attributes(&mut self, name: &str, role: &crate::spec::Role) -> Vec<String> {
+        let current = self.get_role_attributes(name);
+        plan_attributes(name, &current, role)
+    }
+
+    fn analyze_password(&mut self, name: &str, role: &crate::spec::Role) -> Result<Vec<String>> {
+        let current = self.get_role_password(name);
+        plan_password(name, current.as_deref(), role)
+    }
+
+    fn analyze_settings(&mut self, name: &str, role: &crate::spec::Role) -> Vec<String> {
+        let current = self.get_role_settings(name);
+        plan_settings(name, &current, role)
+    }
+
+    fn analyze_personal_schema(
+        &mut self,
+        name: &str,
+        role: &crate::spec::Role,
+        scope: &Scope,
+    ) -> Vec<String> {
+        let objects = self.get_objects();
+        let owned = self.get_role_ownerships(name);
+        plan_personal_schema(name, &objects, &owned, role, scope)
+    }
+
+    fn analyze_memberships(
+        &mut self,
+        name: &str,
+        role: &crate::spec::Role,
+        scope: &Scope,
+    ) -> Vec<String> {
+        let current = self.get_role_memberships(name);
+        plan_memberships(name, &current, role, scope)
+    }
+
+    fn analyze_privileges(
+        &mut self,
+        name: &str,
+        role: &crate::spec::Role,
+        scope: &Scope,
+    ) -> Vec<String> {
+        let objects = self.get_objects();
+        let current = self.get_role_permissions(name);
+        let owned = self.get_role_ownerships(name);
+        plan_privileges(name, current, &owned, &objects, role, scope)
+    }
+
+    fn get_default_permissions(&mut self, role: &str) -> Vec<DefaultPrivilege> {
+        self.role(role).map_or(vec![], |r| {
+            r.default_privileges
+                .iter()
+                .map(|d| DefaultPrivilege {
+                    parent: DatabaseObject::new(ObjectKind::Schema, d.schema.clone(), None),
+                    child: d.kind,
+                    privs: d.privs.iter().copied().collect(),
+                })
+                .collect()
+        })
+    }
+
+    fn get_policies(&mut self) -> TablePolicies {
+        self.snapshot.policies.clone()
+    }
+
+    fn analyze_policies(&mut self, policies: &TablePolicies, scope: &Scope) -> Vec<String> {
+        let current = self.get_policies();
+        plan_policies(&current, policies, scope)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::role_analyzer;
+    use crate::generate::generate_spec;
+    use crate::spec::DatabaseSpec;
+
+    const FIXTURE: &str = include_str!("../../resources/snapshot.yml");
+
+    fn fixture() -> SnapshotContext {
+        SnapshotContext::new(serde_yaml::from_str(FIXTURE).unwrap())
+    }
+
+    #[test]
+    fn test_snapshot_round_trips() {
+        let snapshot = Snapshot::capture(&mut fixture());
+        let expected: Snapshot = serde_yaml::from_str(FIXTURE).unwrap();
+        assert_eq!(snapshot, expected);
+
+        let json = snapshot.to_json().unwrap();
+        assert_eq!(serde_json::from_str::<Snapshot>(&json).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_roles_inherit_unless_told_otherwise() {
+        let attributes: SnapshotAttributes = serde_yaml::from_str("can_login: true").unwrap();
+        assert!(attributes.get_attributes().contains(&Attributes::Inherit));
+
+        let noinherit = SnapshotAttributes {
+            inherit: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_yaml::to_string(&noinherit).unwrap(),
+            "inherit: false\n"
+        );
+    }
+
+    #[test]
+    fn test_generated_spec_plans_nothing() {
+        let yaml = generate_spec(fixture()).unwrap();
+        let mut spec: DatabaseSpec = serde_yaml::from_str(&yaml).unwrap();
+        let mut sql = vec![];
+
+        role_analyzer(&mut sql, fixture(), &mut spec).unwrap();
+
+        assert!(sql.is_empty(), "Expected an empty plan, got {:?}", sql);
+    }
+
+    #[test]
+    fn test_plans_against_snapshot() {
+        let mut spec: DatabaseSpec = serde_yaml::from_str(
+            "
+            version: 1
+            adapter: postgres
+            roles:
+              analyst:
+                can_login: no
+                privileges:
+                  schemas:
+                    read:
+                      - reports
+                  sequences:
+                    read:
+                      - reports.q2_revenue_seq
+              jdoe:
+                member_of:
+                  - analyst
+                privileges:
+                  sequences:
+                    read:
+                      - reports.q2_revenue_seq
+            ",
+        )
+        .unwrap();
+        let mut sql = vec![];
+
+        role_analyzer(&mut sql, fixture(), &mut spec).unwrap();
+
+        assert_eq!(
+            sql,
+            vec![
+                "REVOKE engineer FROM jdoe",
+                "REVOKE UPDATE, USAGE ON SEQUENCE reports.q2_revenue_seq FROM jdoe",
+            ]
+        );
+    }
+}
@@ -1,16 +1,520 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
 use anyhow::Error;
+use chrono::{SecondsFormat, Utc};
+use log::{info, warn};
 
-use crate::{context::Context, spec::DatabaseSpec};
+use crate::{
+    context::{
+        Attributes, Context, DatabaseObject, ObjectKind, Privilege, PrivilegeType, RoleAttribute,
+        RoleMembership, PUBLIC,
+    },
+    scope::Scope,
+    spec::{format_timestamp, DatabaseSpec, Role, Settings},
+};
 
 pub fn role_analyzer<T: Context>(
     sql: &mut Vec<String>,
     mut context: T,
     spec: &mut DatabaseSpec,
 ) -> Result<(), Error> {
+    spec.validate()?;
+    for (name, what, expires) in spec.remove_expired(Utc::now()) {
+        info!(
+            "The {} of {} expired {}, revoking it",
+            what,
+            name,
+            format_timestamp(expires)
+        );
+    }
+
+    let scope = spec.scope();
     for (name, role) in spec.roles.iter() {
+        if !scope.includes_role(name) {
+            warn!("Skipping role {}, it is outside the managed scope", name);
+            continue;
+        }
         println!("Processing role: {}", name);
+        // PUBLIC is a pseudo-role, it has no attributes or memberships
+        if name == PUBLIC {
+            sql.extend(context.analyze_privileges(name, role, &scope));
+            continue;
+        }
         sql.extend(context.analyze_attributes(name, role));
-        sql.extend(context.analyze_memberships(name, role));
+        sql.extend(context.analyze_password(name, role)?);
+        sql.extend(context.analyze_settings(name, role));
+        sql.extend(context.analyze_personal_schema(name, role, &scope));
+        sql.extend(context.analyze_memberships(name, role, &scope));
+        sql.extend(context.analyze_privileges(name, role, &scope));
     }
+    // Policies name roles, so they come once the roles are in place
+    sql.extend(context.analyze_policies(&spec.policies, &scope));
     Ok(())
 }
+
+/// Plans the ALTER ROLE statements that bring a role's attributes in line
+/// with the spec.
+pub fn plan_attributes(name: &str, current: &impl RoleAttribute, spec_role: &Role) -> Vec<String> {
+    let mut sql = vec![];
+
+    if current.is_enabled() != spec_role.can_login {
+        if spec_role.can_login {
+            sql.push(format!("ALTER ROLE {} LOGIN", name));
+        } else {
+            sql.push(format!("ALTER ROLE {} NOLOGIN", name));
+        }
+    }
+
+    let is_superuser = current.get_attributes().contains(&Attributes::Superuser);
+    if is_superuser != spec_role.is_superuser {
+        if spec_role.is_superuser {
+            sql.push(format!("ALTER ROLE {} SUPERUSER", name));
+        } else {
+            sql.push(format!("ALTER ROLE {} NOSUPERUSER", name));
+        }
+    }
+
+    let valid_until = current.get_attributes().into_iter().find_map(|a| match a {
+        Attributes::ValidUntil(t) => Some(t),
+        _ => None,
+    });
+    // Left out of the spec, the expiry is left alone, `infinity` clears it
+    if let Some(wanted) = spec_role.valid_until {
+        if valid_until != wanted {
+            let timestamp = wanted.map_or("infinity".to_string(), |t| {
+                t.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+            });
+            sql.push(format!("ALTER ROLE {} VALID UNTIL '{}'", name, timestamp));
+        }
+    }
+
+    sql
+}
+
+/// Settings whose value is a list, such as `search_path = "$user", public`.
+/// Quoting the whole list would make it a single element, so each element
+/// is quoted on its own.
+const LIST_SETTINGS: &[&str] = &[
+    "search_path",
+    "temp_tablespaces",
+    "local_preload_libraries",
+    "session_preload_libraries",
+];
+
+/// Plans the ALTER ROLE SET and RESET statements that bring a role's
+/// settings in line with the spec, both those for every database and those
+/// for a single one.
+pub fn plan_settings(name: &str, current: &Settings, spec_role: &Role) -> Vec<String> {
+    let desired = &spec_role.settings;
+    let prefix = format!("ALTER ROLE {}", name);
+    let mut sql = diff_settings(&prefix, &current.all, &desired.all);
+
+    let none = BTreeMap::new();
+    let databases: BTreeSet<&String> = current
+        .databases
+        .keys()
+        .chain(desired.databases.keys())
+        .collect();
+    for database in databases {
+        sql.extend(diff_settings(
+            &format!("{} IN DATABASE {}", prefix, database),
+            current.databases.get(database).unwrap_or(&none),
+            desired.databases.get(database).unwrap_or(&none),
+        ));
+    }
+    sql
+}
+
+fn diff_settings(
+    prefix: &str,
+    current: &BTreeMap<String, String>,
+    desired: &BTreeMap<String, String>,
+) -> Vec<String> {
+    let mut sql = vec![];
+    for key in current.keys() {
+        if !desired.contains_key(key) {
+            sql.push(format!("{} RESET {}", prefix, key));
+        }
+    }
+    for (key, value) in desired {
+        let unchanged = current
+            .get(key)
+            .is_some_and(|current| setting_elements(key, current) == setting_elements(key, value));
+        if !unchanged {
+            let elements: Vec<String> = setting_elements(key, value)
+                .iter()
+                .map(|e| format!("'{}'", e.replace('\'', "''")))
+                .collect();
+            sql.push(format!("{} SET {} = {}", prefix, key, elements.join(", ")));
+        }
+    }
+    sql
+}
+
+/// The value of a list setting split into its elements, which Postgres
+/// double quotes when needed, or the value of any other setting.
+pub(crate) fn setting_elements(key: &str, value: &str) -> Vec<String> {
+    if !LIST_SETTINGS.contains(&key) {
+        return vec![value.to_string()];
+    }
+    value
+        .split(',')
+        .map(|e| e.trim().trim_matches('"').to_string())
+        .filter(|e| !e.is_empty())
+        .collect()
+}
+
+/// Plans the statements that give a role with `has_personal_schema` a schema
+/// named after it and owned by it. A personal schema that is no longer
+/// wanted is left alone, dropping it would drop everything inside.
+pub fn plan_personal_schema(
+    name: &str,
+    objects: &[DatabaseObject],
+    owned: &[DatabaseObject],
+    spec_role: &Role,
+    scope: &Scope,
+) -> Vec<String> {
+    let schema = DatabaseObject::new(ObjectKind::Schema, name.to_string(), None);
+    if !spec_role.has_personal_schema || !scope.includes_object(&schema) {
+        return vec![];
+    }
+    if owned.contains(&schema) {
+        vec![]
+    } else if objects.contains(&schema) {
+        vec![format!("ALTER SCHEMA {} OWNER TO {}", name, name)]
+    } else {
+        vec![format!("CREATE SCHEMA {} AUTHORIZATION {}", name, name)]
+    }
+}
+
+/// Plans the GRANT and REVOKE statements that bring a role's memberships in
+/// line with the spec. Memberships in roles outside of `scope` are left alone.
+pub fn plan_memberships(
+    name: &str,
+    current: &RoleMembership,
+    spec_role: &Role,
+    scope: &Scope,
+) -> Vec<String> {
+    let mut sql = vec![];
+
+    let current_members: HashSet<String> = current
+        .memberships
+        .iter()
+        .filter(|m| scope.includes_role(m))
+        .cloned()
+        .collect();
+    let spec_members: HashSet<String> = spec_role
+        .member_of
+        .iter()
+        .map(|m| m.role.clone())
+        .filter(|m| scope.includes_role(m))
+        .collect();
+
+    let to_add: Vec<_> = spec_members.difference(&current_members).collect();
+    let to_remove: Vec<_> = current_members.difference(&spec_members).collect();
+
+    for member in to_add {
+        if spec_role.is_admin_of(member) {
+            sql.push(format!("GRANT {} TO {} WITH ADMIN OPTION", member, name));
+        } else {
+            sql.push(format!("GRANT {} TO {}", member, name));
+        }
+    }
+
+    for member in to_remove {
+        sql.push(format!("REVOKE {} FROM {}", member, name));
+    }
+
+    for member in spec_members.intersection(&current_members) {
+        match (spec_role.is_admin_of(member), current.is_admin_of(member)) {
+            (true, false) => sql.push(format!("GRANT {} TO {} WITH ADMIN OPTION", member, name)),
+            (false, true) => sql.push(format!("REVOKE ADMIN OPTION FOR {} FROM {}", member, name)),
+            _ => {}
+        }
+    }
+
+    sql
+}
+
+/// Plans the GRANT and REVOKE statements that bring a role's privileges in
+/// line with the spec. `objects` is used to expand wildcards, and privileges
+/// on objects outside of `scope` are left alone, and so are privileges on
+/// objects in the role's personal schema, which it is treated as owning.
+pub fn plan_privileges(
+    name: &str,
+    mut current: Vec<Privilege>,
+    owned: &[DatabaseObject],
+    objects: &[DatabaseObject],
+    spec_role: &Role,
+    scope: &Scope,
+) -> Vec<String> {
+    let personal = |object: &DatabaseObject| spec_role.has_personal_schema && object.schema == name;
+    current.retain(|p| scope.includes_object(&p.object) && !personal(&p.object));
+
+    // Owners implicitly hold every privilege on their objects, and adapters
+    // leave them out of the current privileges, so there is nothing to grant.
+    let mut desired = spec_role.privileges.resolve(objects);
+    desired.retain(|p| {
+        !owned.contains(&p.object) && scope.includes_object(&p.object) && !personal(&p.object)
+    });
+
+    diff_privileges(name, &current, &desired)
+}
+
+/// Generates the GRANT and REVOKE statements needed to move a role from its
+/// `current` privileges to the `desired` ones, including adding or removing
+/// the grant option on privileges the role already holds.
+fn diff_privileges(name: &str, current: &[Privilege], desired: &[Privilege]) -> Vec<String> {
+    let current: HashMap<&DatabaseObject, &Privilege> =
+        current.iter().map(|p| (&p.object, p)).collect();
+    let desired: HashMap<&DatabaseObject, &Privilege> =
+        desired.iter().map(|p| (&p.object, p)).collect();
+
+    let mut objects: Vec<&DatabaseObject> = current.keys().chain(desired.keys()).copied().collect();
+    objects.sort();
+    objects.dedup();
+
+    let mut sql = vec![];
+    for object in objects {
+        let has = current.get(object);
+        let wants = desired.get(object);
+        for privilege in [PrivilegeType::Read, PrivilegeType::Write] {
+            if object.kind.raw_privileges(&privilege).is_empty() {
+                continue;
+            }
+            let raw = object.kind.raw_privileges(&privilege).join(", ");
+            let target = format!("{} {}", object.kind.grant_keyword(), object.fqn());
+            let held = has.is_some_and(|p| p.privs.contains(&privilege));
+            let held_grantable = has.is_some_and(|p| p.grantable.contains(&privilege));
+            let wanted = wants.is_some_and(|p| p.privs.contains(&privilege));
+            let wanted_grantable = wants.is_some_and(|p| p.grantable.contains(&privilege));
+
+            if wanted && (!held || (wanted_grantable && !held_grantable)) {
+                if wanted_grantable {
+                    sql.push(format!(
+                        "GRANT {} ON {} TO {} WITH GRANT OPTION",
+                        raw, target, name
+                    ));
+                } else {
+                    sql.push(format!("GRANT {} ON {} TO {}", raw, target, name));
+                }
+            } else if wanted && held_grantable && !wanted_grantable {
+                sql.push(format!(
+                    "REVOKE GRANT OPTION FOR {} ON {} FROM {}",
+                    raw, target, name
+                ));
+            } else if !wanted && held {
+                sql.push(format!("REVOKE {} ON {} FROM {}", raw, target, name));
+            }
+        }
+    }
+    sql
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::fakedb::FakeDb;
+    use crate::context::{ObjectKind, PrivilegeType::*};
+
+    #[test]
+    fn test_predefined_roles_are_membership_targets() {
+        let mut spec: DatabaseSpec = serde_yaml::from_str(
+            "
+            version: 1
+            adapter: fake
+            roles:
+              monitor:
+                member_of:
+                  - pg_monitor
+            ",
+        )
+        .unwrap();
+        let mut sql = vec![];
+
+        role_analyzer(&mut sql, FakeDb::default(), &mut spec).unwrap();
+
+        assert!(sql.contains(&"GRANT pg_monitor TO monitor".to_string()));
+    }
+
+    #[test]
+    fn test_predefined_roles_cannot_be_managed() {
+        let mut spec: DatabaseSpec = serde_yaml::from_str(
+            "
+            version: 1
+            adapter: fake
+            roles:
+              pg_monitor:
+                can_login: no
+            ",
+        )
+        .unwrap();
+
+        let result = role_analyzer(&mut vec![], FakeDb::default(), &mut spec);
+
+        assert!(result.unwrap_err().to_string().contains("predefined role"));
+    }
+
+    #[test]
+    fn test_out_of_scope_roles_and_objects_are_skipped() {
+        let mut spec: DatabaseSpec = serde_yaml::from_str(
+            "
+            version: 1
+            adapter: fake
+            ignore:
+              roles:
+                - rds_*
+              schemas:
+                - marketing
+            roles:
+              rds_superuser:
+                can_login: no
+              developer:
+                can_login: no
+              carol:
+                member_of:
+                  - rds_admin
+                  - developer
+                privileges:
+                  schemas:
+                    read:
+                      - marketing
+                      - finance
+            ",
+        )
+        .unwrap();
+        let mut sql = vec![];
+
+        role_analyzer(&mut sql, FakeDb::default(), &mut spec).unwrap();
+
+        assert!(!sql.iter().any(|s| s.contains("rds_")));
+        assert!(!sql.iter().any(|s| s.contains("marketing")));
+        assert!(sql.contains(&"GRANT developer TO carol".to_string()));
+        assert!(sql.contains(&"GRANT USAGE ON SCHEMA finance TO carol".to_string()));
+    }
+
+    #[test]
+    fn test_plan_settings() {
+        let current: Settings = serde_yaml::from_str(
+            "
+            statement_timeout: 1min
+            search_path: '\"$user\", public'
+            databases:
+              warehouse:
+                work_mem: 64MB
+            ",
+        )
+        .unwrap();
+        let role: Role = serde_yaml::from_str(
+            "
+            settings:
+              search_path: $user, public
+              application_name: o'brien
+              databases:
+                staging:
+                  statement_timeout: 0
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            plan_settings("bob", &current, &role),
+            vec![
+                "ALTER ROLE bob RESET statement_timeout",
+                "ALTER ROLE bob SET application_name = 'o''brien'",
+                "ALTER ROLE bob IN DATABASE staging SET statement_timeout = '0'",
+                "ALTER ROLE bob IN DATABASE warehouse RESET work_mem",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_personal_schema() {
+        let schema = |name: &str| DatabaseObject::new(ObjectKind::Schema, name.into(), None);
+        let role = Role {
+            has_personal_schema: true,
+            ..Default::default()
+        };
+        let scope = Scope::default();
+
+        assert_eq!(
+            plan_personal_schema("bob", &[], &[], &role, &scope),
+            vec!["CREATE SCHEMA bob AUTHORIZATION bob"]
+        );
+        assert_eq!(
+            plan_personal_schema("bob", &[schema("bob")], &[], &role, &scope),
+            vec!["ALTER SCHEMA bob OWNER TO bob"]
+        );
+        assert!(
+            plan_personal_schema("bob", &[schema("bob")], &[schema("bob")], &role, &scope)
+                .is_empty()
+        );
+        assert!(plan_personal_schema("bob", &[], &[], &Role::default(), &scope).is_empty());
+    }
+
+    fn table(name: &str) -> DatabaseObject {
+        DatabaseObject::new(ObjectKind::Table, "finance".into(), Some(name.into()))
+    }
+
+    #[test]
+    fn test_diff_privileges_grants_and_revokes() {
+        let current = vec![Privilege::new(table("q1_revenue"), vec![Read])];
+        let desired = vec![Privilege::new(table("q2_revenue"), vec![Read, Write])];
+
+        assert_eq!(
+            diff_privileges("jdoe", &current, &desired),
+            vec![
+                "REVOKE SELECT, REFERENCES ON TABLE finance.q1_revenue FROM jdoe",
+                "GRANT SELECT, REFERENCES ON TABLE finance.q2_revenue TO jdoe",
+                "GRANT INSERT, UPDATE, DELETE, TRUNCATE, TRIGGER ON TABLE finance.q2_revenue TO jdoe",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_privileges_grant_option() {
+        let current = vec![
+            Privilege::new(table("q1_revenue"), vec![Read]),
+            Privilege::new(table("q2_revenue"), vec![Read]).with_grant_option(vec![Read]),
+        ];
+        let desired = vec![
+            Privilege::new(table("q1_revenue"), vec![Read]).with_grant_option(vec![Read]),
+            Privilege::new(table("q2_revenue"), vec![Read]),
+        ];
+
+        assert_eq!(
+            diff_privileges("jdoe", &current, &desired),
+            vec![
+                "GRANT SELECT, REFERENCES ON TABLE finance.q1_revenue TO jdoe WITH GRANT OPTION",
+                "REVOKE GRANT OPTION FOR SELECT, REFERENCES ON TABLE finance.q2_revenue FROM jdoe",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_privileges_unchanged() {
+        let current =
+            vec![Privilege::new(table("q1_revenue"), vec![Read, Write])
+                .with_grant_option(vec![Write])];
+        let desired =
+            vec![Privilege::new(table("q1_revenue"), vec![Read, Write])
+                .with_grant_option(vec![Write])];
+
+        assert!(diff_privileges("jdoe", &current, &desired).is_empty());
+    }
+
+    #[test]
+    fn test_diff_privileges_revokes_from_public() {
+        let function = DatabaseObject::new(
+            ObjectKind::Function,
+            "finance".into(),
+            Some("total_revenue(integer)".into()),
+        );
+        let current = vec![Privilege::new(function, vec![Read])];
+
+        assert_eq!(
+            diff_privileges("PUBLIC", &current, &[]),
+            vec!["REVOKE EXECUTE ON FUNCTION finance.total_revenue(integer) FROM PUBLIC"]
+        );
+    }
+}
@@ -4,6 +4,14 @@ use std::{
     fmt::{self, Debug, Display},
 };
 
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::policy::TablePolicies;
+use crate::scope::Scope;
+use crate::spec::Settings;
+
 /// A trait for retrieving permission information from a database.
 ///
 /// Any database that can have permissions applied to it can implement this
@@ -23,14 +31,76 @@ pub trait Context {
 
     fn get_role_ownerships(&mut self, role: &str) -> Vec<DatabaseObject>;
 
+    /// The role's password as the database keeps it, e.g. a SCRAM verifier.
+    fn get_role_password(&mut self, role: &str) -> Option<String>;
+
+    /// The configuration parameters set on the role, e.g. with
+    /// `ALTER ROLE ... SET statement_timeout = '5min'`.
+    fn get_role_settings(&mut self, role: &str) -> Settings;
+
     fn get_role_permissions(&mut self, role: &str) -> Vec<Privilege>;
 
+    /// Every object in the database that privileges can be granted on. This
+    /// is used to expand wildcards such as `finance.*` in a spec.
+    fn get_objects(&mut self) -> Vec<DatabaseObject>;
+
+    /// Functions declared SECURITY DEFINER, which run with the privileges of
+    /// their owner rather than of the caller.
+    fn get_security_definer_functions(&mut self) -> Vec<DatabaseObject>;
+
     // TODO: Confusing to have spec::Role and context::Role, consider renaming
     fn analyze_attributes(&mut self, name: &str, role: &crate::spec::Role) -> Vec<String>;
 
-    fn analyze_memberships(&mut self, name: &str, role: &crate::spec::Role) -> Vec<String>;
+    fn analyze_settings(&mut self, name: &str, role: &crate::spec::Role) -> Vec<String>;
+
+    /// Fails when the password of the role cannot be read from its source.
+    fn analyze_password(&mut self, name: &str, role: &crate::spec::Role) -> Result<Vec<String>>;
+
+    /// Creates the personal schema of a role with `has_personal_schema`, or
+    /// hands it over to the role, unless the schema is outside of `scope`.
+    fn analyze_personal_schema(
+        &mut self,
+        name: &str,
+        role: &crate::spec::Role,
+        scope: &Scope,
+    ) -> Vec<String>;
+
+    /// Memberships in roles outside of `scope` are neither granted nor revoked.
+    fn analyze_memberships(
+        &mut self,
+        name: &str,
+        role: &crate::spec::Role,
+        scope: &Scope,
+    ) -> Vec<String>;
+
+    /// Privileges on objects outside of `scope` are neither granted nor revoked.
+    fn analyze_privileges(
+        &mut self,
+        name: &str,
+        role: &crate::spec::Role,
+        scope: &Scope,
+    ) -> Vec<String>;
 
     fn get_default_permissions(&mut self, role: &str) -> Vec<DefaultPrivilege>;
+
+    /// Row-level security on every table that has it enabled or has
+    /// policies, see [`crate::policy`].
+    fn get_policies(&mut self) -> TablePolicies;
+
+    /// Only the tables listed in `policies` are changed, and of those only
+    /// the ones inside `scope`.
+    fn analyze_policies(&mut self, policies: &TablePolicies, scope: &Scope) -> Vec<String>;
+}
+
+/// The pseudo-role that every role is implicitly a member of. Privileges
+/// granted to PUBLIC are available to everyone.
+pub const PUBLIC: &str = "PUBLIC";
+
+/// Whether `role` is one of the predefined roles that ship with Postgres,
+/// such as `pg_monitor` or `pg_read_all_data`. The `pg_` prefix is reserved,
+/// so these can be granted to other roles but are never created or dropped.
+pub fn is_predefined_role(role: &str) -> bool {
+    role.starts_with("pg_")
 }
 
 /// Represents a Role or a User
@@ -64,23 +134,40 @@ pub enum Attributes {
     Replication,
     BypassRls,
     ConnectionLimit(i32),
+    /// When the role's password stops working
+    ValidUntil(DateTime<Utc>),
 }
 
-/// Represents all the privileges of a particular role, e.g. CREATE, SELECT, etc.
+/// Represents all the roles a particular role is a member of.
 #[derive(Debug)]
 pub struct RoleMembership {
     pub memberships: Vec<String>,
+    /// The subset of `memberships` held WITH ADMIN OPTION, i.e. the member
+    /// can grant the group role to others.
+    pub with_admin_option: Vec<String>,
 }
 
 impl RoleMembership {
     pub fn new(memberships: Vec<String>) -> Self {
-        RoleMembership { memberships }
+        RoleMembership {
+            memberships,
+            with_admin_option: vec![],
+        }
+    }
+
+    pub fn with_admin_option(mut self, roles: Vec<String>) -> Self {
+        self.with_admin_option = roles;
+        self
+    }
+
+    pub fn is_admin_of(&self, role: &str) -> bool {
+        self.with_admin_option.iter().any(|r| r == role)
     }
 }
 
 /// Database objects are given a struct in order to deal with quoting
 /// of object names.
-#[derive(PartialEq, Eq, Hash, Ord, PartialOrd)]
+#[derive(Clone, PartialEq, Eq, Hash, Ord, PartialOrd)]
 pub struct DatabaseObject {
     pub kind: ObjectKind,
     pub schema: String,
@@ -114,12 +201,14 @@ impl DatabaseObject {
 
 /// Represents a particular database object. Currently any object
 /// on a database is represented here, but this could be split out by database
-#[derive(Debug, PartialEq, Eq, Hash, Ord, PartialOrd)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Ord, PartialOrd, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ObjectKind {
     Schema,
     Table,
     View,
     Sequence,
+    Function,
 }
 
 impl From<&str> for ObjectKind {
@@ -129,6 +218,7 @@ impl From<&str> for ObjectKind {
             "tables" => ObjectKind::Table,
             "views" => ObjectKind::View,
             "sequences" => ObjectKind::Sequence,
+            "functions" => ObjectKind::Function,
             _ => panic!("Unknown object kind: {}", s),
         }
     }
@@ -141,62 +231,115 @@ impl fmt::Display for ObjectKind {
             ObjectKind::Table => f.write_str("table"),
             ObjectKind::View => f.write_str("view"),
             ObjectKind::Sequence => f.write_str("sequence"),
+            ObjectKind::Function => f.write_str("function"),
         }
     }
 }
 
 impl ObjectKind {
-    pub fn to_privilege(&self, raw_privilege: &str) -> PrivilegeType {
+    /// Maps a privilege as Postgres names it to read or write. Privileges
+    /// permirust does not manage, such as `MAINTAIN` on tables in Postgres
+    /// 17, are `None`.
+    pub fn to_privilege(&self, raw_privilege: &str) -> Option<PrivilegeType> {
         match self {
             ObjectKind::Schema => match raw_privilege {
-                "USAGE" => PrivilegeType::Read,
-                "CREATE" => PrivilegeType::Write,
-                _ => panic!("Unknown privilege: {}", raw_privilege),
+                "USAGE" => Some(PrivilegeType::Read),
+                "CREATE" => Some(PrivilegeType::Write),
+                _ => None,
             },
             ObjectKind::Table => match raw_privilege {
-                "SELECT" => PrivilegeType::Read,
-                "INSERT" => PrivilegeType::Write,
-                "UPDATE" => PrivilegeType::Write,
-                "DELETE" => PrivilegeType::Write,
-                "TRUNCATE" => PrivilegeType::Write,
-                "REFERENCES" => PrivilegeType::Read,
-                "TRIGGER" => PrivilegeType::Write,
-                _ => panic!("Unknown privilege: {}", raw_privilege),
+                "SELECT" => Some(PrivilegeType::Read),
+                "INSERT" => Some(PrivilegeType::Write),
+                "UPDATE" => Some(PrivilegeType::Write),
+                "DELETE" => Some(PrivilegeType::Write),
+                "TRUNCATE" => Some(PrivilegeType::Write),
+                "REFERENCES" => Some(PrivilegeType::Read),
+                "TRIGGER" => Some(PrivilegeType::Write),
+                _ => None,
             },
             ObjectKind::View => match raw_privilege {
-                "SELECT" => PrivilegeType::Read,
-                "INSERT" => PrivilegeType::Write,
-                "UPDATE" => PrivilegeType::Write,
-                "DELETE" => PrivilegeType::Write,
-                "TRUNCATE" => PrivilegeType::Write,
-                "REFERENCES" => PrivilegeType::Read,
-                "TRIGGER" => PrivilegeType::Write,
-                _ => panic!("Unknown privilege: {}", raw_privilege),
+                "SELECT" => Some(PrivilegeType::Read),
+                "INSERT" => Some(PrivilegeType::Write),
+                "UPDATE" => Some(PrivilegeType::Write),
+                "DELETE" => Some(PrivilegeType::Write),
+                "TRUNCATE" => Some(PrivilegeType::Write),
+                "REFERENCES" => Some(PrivilegeType::Read),
+                "TRIGGER" => Some(PrivilegeType::Write),
+                _ => None,
             },
             ObjectKind::Sequence => match raw_privilege {
-                "SELECT" => PrivilegeType::Read,
-                "UPDATE" => PrivilegeType::Write,
-                "USAGE" => PrivilegeType::Write,
-                _ => panic!("Unknown privilege: {}", raw_privilege),
+                "SELECT" => Some(PrivilegeType::Read),
+                "UPDATE" => Some(PrivilegeType::Write),
+                "USAGE" => Some(PrivilegeType::Write),
+                _ => None,
+            },
+            // Executing a function is treated as reading it, there is no
+            // privilege that maps to writing a function
+            ObjectKind::Function => match raw_privilege {
+                "EXECUTE" => Some(PrivilegeType::Read),
+                _ => None,
             },
         }
     }
+
+    /// The inverse of `to_privilege`: the raw privileges that make up a
+    /// generic privilege on this kind of object.
+    pub fn raw_privileges(&self, privilege: &PrivilegeType) -> &'static [&'static str] {
+        match (self, privilege) {
+            (ObjectKind::Schema, PrivilegeType::Read) => &["USAGE"],
+            (ObjectKind::Schema, PrivilegeType::Write) => &["CREATE"],
+            (ObjectKind::Table | ObjectKind::View, PrivilegeType::Read) => {
+                &["SELECT", "REFERENCES"]
+            }
+            (ObjectKind::Table | ObjectKind::View, PrivilegeType::Write) => {
+                &["INSERT", "UPDATE", "DELETE", "TRUNCATE", "TRIGGER"]
+            }
+            (ObjectKind::Sequence, PrivilegeType::Read) => &["SELECT"],
+            (ObjectKind::Sequence, PrivilegeType::Write) => &["UPDATE", "USAGE"],
+            (ObjectKind::Function, PrivilegeType::Read) => &["EXECUTE"],
+            (ObjectKind::Function, PrivilegeType::Write) => &[],
+        }
+    }
+
+    /// The keyword used to refer to this kind of object in GRANT and REVOKE
+    /// statements. Views are granted on as tables.
+    pub fn grant_keyword(&self) -> &'static str {
+        match self {
+            ObjectKind::Schema => "SCHEMA",
+            ObjectKind::Table | ObjectKind::View => "TABLE",
+            ObjectKind::Sequence => "SEQUENCE",
+            ObjectKind::Function => "FUNCTION",
+        }
+    }
 }
 /// These are generic Privileges that will be mapped to from underlying
 /// database grants. Different objects may have different mappings, for example
 /// USAGE may be a READ on a schema but WRITE on a sequence.
 /// If Read/Write are not sufficient we might add more later.
-#[derive(Debug, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum PrivilegeType {
     Read,
     Write,
 }
 
+impl fmt::Display for PrivilegeType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrivilegeType::Read => f.write_str("read"),
+            PrivilegeType::Write => f.write_str("write"),
+        }
+    }
+}
+
 /// Represents the privileges that a role has on a particular object.
 #[derive(Debug)]
 pub struct Privilege {
     pub object: DatabaseObject,
     pub privs: HashSet<PrivilegeType>,
+    /// The subset of `privs` held WITH GRANT OPTION. A generic privilege is
+    /// grantable if any of its underlying raw privileges are.
+    pub grantable: HashSet<PrivilegeType>,
 }
 
 impl Privilege {
@@ -204,8 +347,14 @@ impl Privilege {
         Privilege {
             object,
             privs: HashSet::from_iter(privs),
+            grantable: HashSet::new(),
         }
     }
+
+    pub fn with_grant_option(mut self, grantable: Vec<PrivilegeType>) -> Self {
+        self.grantable = HashSet::from_iter(grantable);
+        self
+    }
 }
 
 /// Represetns a default privlege granted on sub-objects, for example,
@@ -0,0 +1,295 @@
+//! Privilege escalation paths.
+//!
+//! Some privileges make a role as good as a superuser, whatever it was
+//! granted directly. [`EscalationGraph`] looks for roles that, through any
+//! chain of memberships, can reach:
+//!
+//! * a role with SUPERUSER;
+//! * a role with CREATEROLE, which can create roles and grant membership in
+//!   the roles it has ADMIN OPTION on, and before PostgreSQL 16 in any
+//!   non-superuser role;
+//! * a predefined role that runs programs or writes files on the server as
+//!   the operating system user Postgres runs as;
+//! * EXECUTE on a SECURITY DEFINER function owned by a superuser, which runs
+//!   with its owner's privileges.
+//!
+//! Any membership allows `SET ROLE` to the group, so memberships are followed
+//! whether the roles on the path have INHERIT or not. Roles that are
+//! superusers themselves have nothing to escalate to and are not reported.
+//!
+//! Privileges on objects that belong to an extension are not captured, so
+//! SECURITY DEFINER functions installed by an extension are not followed.
+//! Check those with `\df+` on the extension's schema.
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fmt;
+
+use crate::context::{
+    Attributes, Context, DatabaseObject, ObjectKind, PrivilegeType, RoleAttribute, PUBLIC,
+};
+
+/// Predefined roles that act on the server with the privileges of the
+/// operating system user, with what they allow.
+pub const DANGEROUS_PREDEFINED_ROLES: &[(&str, &str)] = &[
+    (
+        "pg_execute_server_program",
+        "can run programs on the database server",
+    ),
+    (
+        "pg_write_server_files",
+        "can write files on the database server",
+    ),
+];
+
+/// What the last role on an escalation path holds.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Reason {
+    Superuser,
+    /// How far CREATEROLE reaches depends on the server version, see the
+    /// module documentation
+    CreateRole,
+    PredefinedRole(&'static str),
+    /// EXECUTE on a SECURITY DEFINER function owned by a superuser
+    SecurityDefiner {
+        function: DatabaseObject,
+        owner: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Escalation {
+    pub role: String,
+    /// Roles from `role` to the one holding the privilege, e.g.
+    /// `["jdoe", "admins"]`. PUBLIC appears as the last role for functions
+    /// everyone can execute.
+    pub path: Vec<String>,
+    pub reason: Reason,
+}
+
+impl fmt::Display for Escalation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let holder = self.path.last().unwrap_or(&self.role);
+        write!(
+            f,
+            "{} can escalate via {}: ",
+            self.role,
+            self.path.join(" -> ")
+        )?;
+        match &self.reason {
+            Reason::Superuser => write!(f, "{} is a superuser", holder),
+            Reason::CreateRole => write!(
+                f,
+                "{} has CREATEROLE, which can grant the roles it administers, or any non-superuser role before PostgreSQL 16",
+                holder
+            ),
+            Reason::PredefinedRole(name) => {
+                let what = DANGEROUS_PREDEFINED_ROLES
+                    .iter()
+                    .find(|(role, _)| role == name)
+                    .map_or("is predefined", |(_, what)| what);
+                write!(f, "{} {}", name, what)
+            }
+            Reason::SecurityDefiner { function, owner } => write!(
+                f,
+                "{} can execute {}, a SECURITY DEFINER function owned by superuser {}",
+                holder,
+                function.fqn(),
+                owner
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct RoleNode {
+    superuser: bool,
+    create_role: bool,
+    member_of: Vec<String>,
+    /// Functions the role can execute through a direct grant
+    executable: Vec<DatabaseObject>,
+}
+
+/// Roles, their memberships and the dangerous functions they can execute,
+/// captured from a context.
+#[derive(Debug, Default)]
+pub struct EscalationGraph {
+    roles: BTreeMap<String, RoleNode>,
+    /// Functions PUBLIC can execute
+    public: Vec<DatabaseObject>,
+    /// SECURITY DEFINER functions owned by a superuser, with their owner
+    dangerous_functions: BTreeMap<DatabaseObject, String>,
+}
+
+impl EscalationGraph {
+    pub fn from_context<T: Context>(context: &mut T) -> Self
+    where
+        T::RoleAttribute: RoleAttribute,
+    {
+        let mut graph = EscalationGraph {
+            public: executable(context, PUBLIC),
+            ..Default::default()
+        };
+        let security_definer = context.get_security_definer_functions();
+        for name in context.get_roles() {
+            let attributes = context.get_role_attributes(&name).get_attributes();
+            let superuser = attributes.contains(&Attributes::Superuser);
+            if superuser {
+                for function in context.get_role_ownerships(&name) {
+                    if security_definer.contains(&function) {
+                        graph.dangerous_functions.insert(function, name.clone());
+                    }
+                }
+            }
+            let node = RoleNode {
+                superuser,
+                create_role: attributes.contains(&Attributes::CreateRole),
+                member_of: context.get_role_memberships(&name).memberships,
+                executable: executable(context, &name),
+            };
+            graph.roles.insert(name, node);
+        }
+        graph
+    }
+
+    /// Every role that can escalate, with the shortest path for each way it
+    /// can.
+    pub fn escalations(&self) -> Vec<Escalation> {
+        self.roles
+            .iter()
+            .filter(|(_, node)| !node.superuser)
+            .flat_map(|(name, _)| self.escalations_of(name))
+            .collect()
+    }
+
+    /// The ways `role` can escalate, with the shortest path for each.
+    pub fn escalations_of(&self, role: &str) -> Vec<Escalation> {
+        let mut found: Vec<Escalation> = vec![];
+        let mut push = |path: &[String], reason: Reason| {
+            if !found.iter().any(|e| e.reason == reason) {
+                found.push(Escalation {
+                    role: role.to_string(),
+                    path: path.to_vec(),
+                    reason,
+                });
+            }
+        };
+
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::from([vec![role.to_string()]]);
+        while let Some(path) = queue.pop_front() {
+            let name = path.last().unwrap();
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+            if let Some((predefined, _)) = DANGEROUS_PREDEFINED_ROLES
+                .iter()
+                .find(|(predefined, _)| predefined == name)
+            {
+                push(&path, Reason::PredefinedRole(predefined));
+            }
+            // Predefined roles are not captured, they end the path
+            let Some(node) = self.roles.get(name) else {
+                continue;
+            };
+            if node.superuser {
+                push(&path, Reason::Superuser);
+            }
+            if node.create_role {
+                push(&path, Reason::CreateRole);
+            }
+            for reason in self.security_definer(&node.executable) {
+                push(&path, reason);
+            }
+            for group in &node.member_of {
+                let mut next = path.clone();
+                next.push(group.clone());
+                queue.push_back(next);
+            }
+        }
+
+        if self.roles.contains_key(role) {
+            let path = [role.to_string(), PUBLIC.to_string()];
+            for reason in self.security_definer(&self.public) {
+                push(&path, reason);
+            }
+        }
+        found
+    }
+
+    fn security_definer(&self, executable: &[DatabaseObject]) -> Vec<Reason> {
+        executable
+            .iter()
+            .filter_map(|function| {
+                self.dangerous_functions
+                    .get(function)
+                    .map(|owner| Reason::SecurityDefiner {
+                        function: function.clone(),
+                        owner: owner.clone(),
+                    })
+            })
+            .collect()
+    }
+}
+
+fn executable<T: Context>(context: &mut T, role: &str) -> Vec<DatabaseObject> {
+    context
+        .get_role_permissions(role)
+        .into_iter()
+        .filter(|p| p.object.kind == ObjectKind::Function && p.privs.contains(&PrivilegeType::Read))
+        .map(|p| p.object)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::fakedb::FakeDb;
+
+    fn escalations(db: &mut FakeDb) -> Vec<String> {
+        EscalationGraph::from_context(db)
+            .escalations()
+            .iter()
+            .map(|e| e.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_escalations() {
+        let mut db = FakeDb::default();
+        assert_eq!(escalations(&mut db), Vec::<String>::new());
+
+        db.apply_all(&[
+            "ALTER ROLE developer CREATEROLE".into(),
+            "GRANT pg_execute_server_program TO analyst".into(),
+        ])
+        .unwrap();
+        assert_eq!(
+            escalations(&mut db),
+            vec![
+                "alice can escalate via alice -> developer: developer has CREATEROLE, which can grant the roles it administers, or any non-superuser role before PostgreSQL 16",
+                "alice can escalate via alice -> developer -> analyst -> pg_execute_server_program: pg_execute_server_program can run programs on the database server",
+                "analyst can escalate via analyst -> pg_execute_server_program: pg_execute_server_program can run programs on the database server",
+                "bob can escalate via bob -> analyst -> pg_execute_server_program: pg_execute_server_program can run programs on the database server",
+                "carol can escalate via carol -> analyst -> pg_execute_server_program: pg_execute_server_program can run programs on the database server",
+                "developer can escalate via developer: developer has CREATEROLE, which can grant the roles it administers, or any non-superuser role before PostgreSQL 16",
+                "developer can escalate via developer -> analyst -> pg_execute_server_program: pg_execute_server_program can run programs on the database server",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_security_definer_functions() {
+        let mut db = FakeDb::default();
+        db.apply("ALTER ROLE alice SUPERUSER").unwrap();
+
+        let found = escalations(&mut db);
+        assert_eq!(
+            found,
+            vec![
+                "analyst can escalate via analyst -> PUBLIC: PUBLIC can execute finance.total_revenue(integer), a SECURITY DEFINER function owned by superuser alice",
+                "bob can escalate via bob -> PUBLIC: PUBLIC can execute finance.total_revenue(integer), a SECURITY DEFINER function owned by superuser alice",
+                "carol can escalate via carol -> PUBLIC: PUBLIC can execute finance.total_revenue(integer), a SECURITY DEFINER function owned by superuser alice",
+                "developer can escalate via developer -> PUBLIC: PUBLIC can execute finance.total_revenue(integer), a SECURITY DEFINER function owned by superuser alice",
+            ]
+        );
+    }
+}
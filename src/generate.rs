@@ -1,17 +1,47 @@
 use crate::context::{
-    Context, DatabaseObject, DefaultPrivilege, Privilege, RoleAttribute, RoleMembership,
+    Context, DatabaseObject, DefaultPrivilege, ObjectKind, Privilege, RoleAttribute,
+    RoleMembership, PUBLIC,
 };
-use crate::spec::DatabaseSpec;
+use crate::policy::table_object;
+use crate::scope::Scope;
+use crate::spec::{resolve_object, DatabaseSpec, Grant, Privileges};
+use crate::template::detect_templates;
 use log::info;
+use std::collections::HashSet;
 
 use anyhow::Result;
 
-pub fn generate_spec<T: Context>(mut context: T) -> Result<String>
+/// Options that control how a spec is generated from a database.
+#[derive(Debug, Default)]
+pub struct GenerateOptions {
+    /// Roles and objects outside of this scope are left out of the spec.
+    pub scope: Scope,
+    /// Collapse grants that cover every object of a kind in a schema into a
+    /// single `schema.*` entry.
+    pub compact: bool,
+    /// Move what groups of roles share into templates, see
+    /// [`crate::template::detect_templates`].
+    pub detect_templates: bool,
+}
+
+pub fn generate_spec<T: Context>(context: T) -> Result<String>
+where
+    <T as crate::context::Context>::RoleAttribute: RoleAttribute,
+{
+    generate_spec_with(context, &GenerateOptions::default())
+}
+
+pub fn generate_spec_with<T: Context>(mut context: T, options: &GenerateOptions) -> Result<String>
 where
     <T as crate::context::Context>::RoleAttribute: RoleAttribute,
 {
+    let scope = &options.scope;
     let mut spec = DatabaseSpec::new(context.database_name());
-    let roles = context.get_roles();
+    spec.ignore = scope.ignore.clone();
+    spec.manage_only = scope.manage_only.clone();
+
+    let mut roles = context.get_roles();
+    roles.retain(|r| scope.includes_role(r));
 
     info!("Roles: {:?}", roles);
 
@@ -21,15 +51,33 @@ where
         .collect();
     let memberships: Vec<RoleMembership> = roles
         .iter()
-        .map(|r| context.get_role_memberships(r))
+        .map(|r| {
+            let membership = context.get_role_memberships(r);
+            RoleMembership::new(
+                membership
+                    .memberships
+                    .into_iter()
+                    .filter(|m| scope.includes_role(m))
+                    .collect(),
+            )
+            .with_admin_option(membership.with_admin_option)
+        })
         .collect();
-    let owners: Vec<Vec<DatabaseObject>> = roles
+    let mut owners: Vec<Vec<DatabaseObject>> = roles
         .iter()
-        .map(|r| context.get_role_ownerships(r))
+        .map(|r| {
+            let mut owned = context.get_role_ownerships(r);
+            owned.retain(|o| scope.includes_object(o));
+            owned
+        })
         .collect();
-    let privs: Vec<Vec<Privilege>> = roles
+    let mut privs: Vec<Vec<Privilege>> = roles
         .iter()
-        .map(|r| context.get_role_permissions(r))
+        .map(|r| {
+            let mut privs = context.get_role_permissions(r);
+            privs.retain(|p| scope.includes_object(&p.object));
+            privs
+        })
         .collect();
     let defaults: Vec<Vec<DefaultPrivilege>> = roles
         .iter()
@@ -38,12 +86,51 @@ where
 
     for (i, role) in roles.iter().enumerate() {
         spec.add_role(role, &attrs[i]);
+        spec.add_settings(role, &context.get_role_settings(role));
         spec.add_memberships(role, &memberships[i]);
+        // A login role owning a schema named after it has a personal schema,
+        // and what is inside is implied rather than listed
+        let personal = attrs[i].is_enabled()
+            && owners[i]
+                .iter()
+                .any(|o| o.kind == ObjectKind::Schema && o.schema == *role);
+        if personal {
+            spec.roles.get_mut(role).unwrap().has_personal_schema = true;
+        }
+        owners[i].retain(|o| !personal || o.schema != *role);
+        privs[i].retain(|p| !personal || p.object.schema != *role);
         spec.add_ownerships(role, &owners[i]);
         spec.add_privileges(role, &privs[i]);
         spec.add_defaults(role, &defaults[i]);
     }
 
+    let mut public = context.get_role_permissions(PUBLIC);
+    public.retain(|p| scope.includes_object(&p.object));
+    if !public.is_empty() && scope.includes_role(PUBLIC) {
+        spec.add_public();
+        spec.add_privileges(PUBLIC, &public);
+    }
+
+    spec.policies = context.get_policies();
+    spec.policies
+        .retain(|table, _| scope.includes_object(&table_object(table)));
+
+    if options.compact {
+        let mut objects = context.get_objects();
+        objects.retain(|o| scope.includes_object(o));
+        for (name, role) in spec.roles.iter_mut() {
+            let owned = roles
+                .iter()
+                .position(|r| r == name)
+                .map_or(&[][..], |i| &owners[i][..]);
+            compact_privileges(&mut role.privileges, &objects, owned);
+        }
+    }
+
+    if options.detect_templates {
+        detect_templates(&mut spec);
+    }
+
     let yaml = match spec.to_yaml() {
         Ok(yaml) => yaml,
         Err(e) => {
@@ -54,15 +141,215 @@ where
     Ok(yaml)
 }
 
+/// Rewrites table, sequence and function grants that cover a whole schema
+/// into `schema.*`, leaving partial coverage explicit.
+fn compact_privileges(
+    privileges: &mut Privileges,
+    objects: &[DatabaseObject],
+    owned: &[DatabaseObject],
+) {
+    let lists = [
+        (ObjectKind::Table, &mut privileges.tables.read),
+        (ObjectKind::Table, &mut privileges.tables.write),
+        (ObjectKind::Sequence, &mut privileges.sequences.read),
+        (ObjectKind::Sequence, &mut privileges.sequences.write),
+        (ObjectKind::Function, &mut privileges.functions.read),
+        (ObjectKind::Function, &mut privileges.functions.write),
+    ];
+    for (kind, grants) in lists {
+        compact_grants(grants, kind, objects, owned);
+    }
+}
+
+/// A schema is fully covered when every object of `kind` in it is either
+/// granted or owned by the role, since owners implicitly hold every
+/// privilege. The wildcard is only grantable if every grant it replaces was;
+/// otherwise the grantable entries stay listed next to it.
+fn compact_grants(
+    grants: &mut Vec<Grant>,
+    kind: ObjectKind,
+    objects: &[DatabaseObject],
+    owned: &[DatabaseObject],
+) {
+    let mut schemas: Vec<String> = grants
+        .iter()
+        .filter_map(|g| {
+            g.object
+                .split_once('.')
+                .map(|(schema, _)| schema.to_string())
+        })
+        .collect();
+    schemas.sort();
+    schemas.dedup();
+
+    for schema in schemas {
+        let members: Vec<String> = resolve_object(kind, &format!("{}.*", schema), objects)
+            .into_iter()
+            .filter(|o| !owned.contains(o))
+            .map(|o| o.fqn())
+            .collect();
+        let granted: HashSet<&str> = grants.iter().map(|g| g.object.as_str()).collect();
+        if members.is_empty() || !members.iter().all(|m| granted.contains(m.as_str())) {
+            continue;
+        }
+
+        let covered = |g: &Grant| members.contains(&g.object);
+        let all_grantable = grants
+            .iter()
+            .filter(|g| covered(g))
+            .all(|g| g.with_grant_option);
+        let position = grants.iter().position(covered).unwrap_or(grants.len());
+
+        grants.retain(|g| !covered(g) || (g.with_grant_option && !all_grantable));
+        grants.insert(
+            position.min(grants.len()),
+            Grant {
+                object: format!("{}.*", schema),
+                with_grant_option: all_grantable,
+                expires: None,
+            },
+        );
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::adapters::fakedb::FakeDb;
+    use crate::scope::ScopeRules;
 
     #[test]
     fn test_generate() {
-        let context = FakeDb {};
+        let context = FakeDb::default();
         let spec = generate_spec(context).unwrap();
         assert!(spec.contains("roles:"), "Spec should contain roles section");
     }
+
+    #[test]
+    fn test_generate_includes_public() {
+        let context = FakeDb::default();
+        let spec: DatabaseSpec = serde_yaml::from_str(&generate_spec(context).unwrap()).unwrap();
+        let public = &spec.roles[PUBLIC];
+        assert!(!public.can_login);
+        assert_eq!(
+            public.privileges.functions.read,
+            vec!["finance.total_revenue(integer)".into()]
+        );
+    }
+
+    #[test]
+    fn test_generate_respects_scope() {
+        let options = GenerateOptions {
+            scope: Scope::new(
+                ScopeRules {
+                    roles: vec!["c*".into()],
+                    schemas: vec!["marketing".into()],
+                    objects: vec![],
+                },
+                ScopeRules::default(),
+            ),
+            ..Default::default()
+        };
+        let yaml = generate_spec_with(FakeDb::default(), &options).unwrap();
+        let spec: DatabaseSpec = serde_yaml::from_str(&yaml).unwrap();
+
+        assert!(!spec.roles.contains_key("carol"));
+        assert_eq!(spec.roles["alice"].owns.schemas, vec!["finance"]);
+        let bob = &spec.roles["bob"];
+        assert_eq!(bob.privileges.schemas.read.len(), 1);
+        assert_eq!(bob.privileges.schemas.read[0].object, "finance");
+        assert_eq!(spec.ignore, options.scope.ignore);
+    }
+
+    #[test]
+    fn test_generate_detects_personal_schemas() {
+        let mut db = FakeDb::default();
+        db.apply_all(&[
+            "CREATE SCHEMA bob AUTHORIZATION bob".into(),
+            "CREATE SCHEMA analyst AUTHORIZATION analyst".into(),
+        ])
+        .unwrap();
+        let spec: DatabaseSpec = serde_yaml::from_str(&generate_spec(db).unwrap()).unwrap();
+
+        let bob = &spec.roles["bob"];
+        assert!(bob.has_personal_schema);
+        assert!(!bob.owns.schemas.contains(&"bob".to_string()));
+        // Group roles cannot log in to use a schema of their own
+        assert!(!spec.roles["analyst"].has_personal_schema);
+        assert_eq!(spec.roles["analyst"].owns.schemas, vec!["analyst"]);
+    }
+
+    #[test]
+    fn test_generate_includes_settings() {
+        let spec: DatabaseSpec =
+            serde_yaml::from_str(&generate_spec(FakeDb::default()).unwrap()).unwrap();
+
+        assert_eq!(spec.roles["bob"].settings.all["statement_timeout"], "1min");
+        assert!(spec.roles["carol"].settings.is_empty());
+    }
+
+    #[test]
+    fn test_generate_includes_policies() {
+        let spec: DatabaseSpec =
+            serde_yaml::from_str(&generate_spec(FakeDb::default()).unwrap()).unwrap();
+
+        let security = &spec.policies["finance.q2_results"];
+        assert!(security.enabled);
+        assert_eq!(security.policies["by_region"].roles, vec!["analyst"]);
+    }
+
+    #[test]
+    fn test_generate_compact() {
+        let options = GenerateOptions {
+            compact: true,
+            ..Default::default()
+        };
+        let yaml = generate_spec_with(FakeDb::default(), &options).unwrap();
+        let spec: DatabaseSpec = serde_yaml::from_str(&yaml).unwrap();
+
+        assert_eq!(
+            spec.roles[PUBLIC].privileges.functions.read,
+            vec!["finance.*".into()]
+        );
+    }
+
+    #[test]
+    fn test_compact_grants() {
+        let table = |name: &str| {
+            DatabaseObject::new(ObjectKind::Table, "reports".into(), Some(name.into()))
+        };
+        let objects = vec![
+            DatabaseObject::new(ObjectKind::Schema, "reports".into(), None),
+            table("some_report"),
+            table("other_report"),
+            table("owned_report"),
+            DatabaseObject::new(ObjectKind::Table, "finance".into(), Some("q2".into())),
+            DatabaseObject::new(ObjectKind::Table, "finance".into(), Some("q3".into())),
+        ];
+        let owned = vec![table("owned_report")];
+        let mut grants = vec![
+            Grant::from("finance.q2"),
+            Grant {
+                object: "reports.some_report".into(),
+                with_grant_option: true,
+                expires: None,
+            },
+            Grant::from("reports.other_report"),
+        ];
+
+        compact_grants(&mut grants, ObjectKind::Table, &objects, &owned);
+
+        assert_eq!(
+            grants,
+            vec![
+                Grant::from("finance.q2"),
+                Grant::from("reports.*"),
+                Grant {
+                    object: "reports.some_report".into(),
+                    with_grant_option: true,
+                    expires: None,
+                },
+            ]
+        );
+    }
 }
@@ -0,0 +1,341 @@
+//! Role hierarchy diagrams.
+//!
+//! [`RoleGraph`] collects roles, their memberships and optionally the schemas
+//! they can write, from a database or a spec, and renders them as Graphviz
+//! DOT or Mermaid. Login roles and group roles get different shapes and
+//! superusers are highlighted.
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use crate::context::{Attributes, Context, ObjectKind, PrivilegeType, RoleAttribute, PUBLIC};
+use crate::spec::DatabaseSpec;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RoleNode {
+    pub can_login: bool,
+    pub is_superuser: bool,
+    /// Groups this role is a member of, with whether it holds the admin
+    /// option
+    pub member_of: BTreeMap<String, bool>,
+    pub writable_schemas: BTreeSet<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RoleGraph {
+    pub roles: BTreeMap<String, RoleNode>,
+}
+
+impl RoleGraph {
+    /// Builds the graph from a database. A role can write a schema it owns
+    /// or holds CREATE on.
+    pub fn from_context<T: Context>(context: &mut T) -> Self
+    where
+        T::RoleAttribute: RoleAttribute,
+    {
+        let mut graph = RoleGraph::default();
+        for name in context.get_roles() {
+            let attributes = context.get_role_attributes(&name).get_attributes();
+            let memberships = context.get_role_memberships(&name);
+            let mut writable_schemas: BTreeSet<String> = context
+                .get_role_ownerships(&name)
+                .into_iter()
+                .filter(|o| o.kind == ObjectKind::Schema)
+                .map(|o| o.schema)
+                .collect();
+            writable_schemas.extend(
+                context
+                    .get_role_permissions(&name)
+                    .into_iter()
+                    .filter(|p| {
+                        p.object.kind == ObjectKind::Schema
+                            && p.privs.contains(&PrivilegeType::Write)
+                    })
+                    .map(|p| p.object.schema),
+            );
+            let node = RoleNode {
+                can_login: attributes.contains(&Attributes::Enabled),
+                is_superuser: attributes.contains(&Attributes::Superuser),
+                member_of: memberships
+                    .memberships
+                    .iter()
+                    .map(|m| (m.clone(), memberships.is_admin_of(m)))
+                    .collect(),
+                writable_schemas,
+            };
+            graph.roles.insert(name, node);
+        }
+        graph.add_referenced_groups();
+        graph
+    }
+
+    /// Builds the graph from a spec.
+    pub fn from_spec(spec: &DatabaseSpec) -> Self {
+        let mut graph = RoleGraph::default();
+        for (name, role) in spec.roles.iter().filter(|(name, _)| *name != PUBLIC) {
+            let mut writable_schemas: BTreeSet<String> =
+                role.owns.schemas.iter().cloned().collect();
+            if role.has_personal_schema {
+                writable_schemas.insert(name.clone());
+            }
+            writable_schemas.extend(
+                role.privileges
+                    .schemas
+                    .write
+                    .iter()
+                    .map(|g| g.object.clone()),
+            );
+            let node = RoleNode {
+                can_login: role.can_login,
+                is_superuser: role.is_superuser,
+                member_of: role
+                    .member_of
+                    .iter()
+                    .map(|m| (m.role.clone(), m.with_admin_option))
+                    .collect(),
+                writable_schemas,
+            };
+            graph.roles.insert(name.clone(), node);
+        }
+        graph.add_referenced_groups();
+        graph
+    }
+
+    /// Adds roles that only appear as groups, such as predefined roles, as
+    /// plain group roles.
+    fn add_referenced_groups(&mut self) {
+        let referenced: Vec<String> = self
+            .roles
+            .values()
+            .flat_map(|r| r.member_of.keys().cloned())
+            .collect();
+        for name in referenced {
+            self.roles.entry(name).or_default();
+        }
+    }
+
+    fn schemas(&self) -> BTreeSet<&String> {
+        self.roles
+            .values()
+            .flat_map(|r| r.writable_schemas.iter())
+            .collect()
+    }
+
+    /// Renders the graph as Graphviz DOT. Edges point from members to their
+    /// groups, and dashed edges from roles to the schemas they can write
+    /// when `with_schemas` is set.
+    pub fn to_dot(&self, with_schemas: bool) -> String {
+        let mut out = vec!["digraph roles {".to_string(), "  rankdir=BT;".to_string()];
+        for (name, role) in &self.roles {
+            let shape = if role.can_login { "ellipse" } else { "box" };
+            let mut attrs = format!("shape={}", shape);
+            if role.is_superuser {
+                attrs.push_str(", style=filled, fillcolor=\"#f8d7da\", color=red");
+            }
+            out.push(format!("  {} [{}];", dot_id(name), attrs));
+        }
+        for (name, role) in &self.roles {
+            for (group, admin) in &role.member_of {
+                let label = if *admin { " [label=\"admin\"]" } else { "" };
+                out.push(format!("  {} -> {}{};", dot_id(name), dot_id(group), label));
+            }
+        }
+        if with_schemas {
+            for schema in self.schemas() {
+                out.push(format!(
+                    "  {} [shape=cylinder, label={}];",
+                    dot_id(&format!("schema:{}", schema)),
+                    dot_id(schema)
+                ));
+            }
+            for (name, role) in &self.roles {
+                for schema in &role.writable_schemas {
+                    out.push(format!(
+                        "  {} -> {} [style=dashed, label=\"write\"];",
+                        dot_id(name),
+                        dot_id(&format!("schema:{}", schema))
+                    ));
+                }
+            }
+        }
+        out.push("}".to_string());
+        out.join("\n")
+    }
+
+    /// Renders the graph as a Mermaid flowchart, with the same layout as
+    /// [`RoleGraph::to_dot`].
+    pub fn to_mermaid(&self, with_schemas: bool) -> String {
+        let role_ids = mermaid_ids(
+            "role",
+            self.roles
+                .iter()
+                .flat_map(|(name, role)| std::iter::once(name).chain(role.member_of.keys())),
+        );
+        let schema_ids = mermaid_ids("schema", self.schemas());
+        let mut out = vec!["flowchart BT".to_string()];
+        for (name, role) in &self.roles {
+            let label = mermaid_label(name);
+            if role.can_login {
+                out.push(format!("  {}([{}])", role_ids[name], label));
+            } else {
+                out.push(format!("  {}[{}]", role_ids[name], label));
+            }
+        }
+        for (name, role) in &self.roles {
+            for (group, admin) in &role.member_of {
+                let arrow = if *admin { "-- admin -->" } else { "-->" };
+                out.push(format!(
+                    "  {} {} {}",
+                    role_ids[name], arrow, role_ids[group]
+                ));
+            }
+        }
+        if with_schemas {
+            for (schema, id) in &schema_ids {
+                out.push(format!("  {}[({})]", id, mermaid_label(schema)));
+            }
+            for (name, role) in &self.roles {
+                for schema in &role.writable_schemas {
+                    out.push(format!(
+                        "  {} -. write .-> {}",
+                        role_ids[name], schema_ids[schema]
+                    ));
+                }
+            }
+        }
+        let superusers: Vec<_> = self
+            .roles
+            .iter()
+            .filter(|(_, r)| r.is_superuser)
+            .map(|(name, _)| role_ids[name].as_str())
+            .collect();
+        if !superusers.is_empty() {
+            out.push("  classDef superuser fill:#f8d7da,stroke:#c00".to_string());
+            out.push(format!("  class {} superuser", superusers.join(",")));
+        }
+        out.join("\n")
+    }
+}
+
+fn dot_id(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Mermaid ids must be plain words, so anything else is replaced and the
+/// real name goes in the label. Names that end up with the same id, such as
+/// `a-b` and `a_b`, are told apart by a number.
+fn mermaid_ids<'a>(
+    prefix: &str,
+    names: impl IntoIterator<Item = &'a String>,
+) -> BTreeMap<&'a String, String> {
+    let names: BTreeSet<&String> = names.into_iter().collect();
+    let mut used = HashSet::new();
+    let mut ids = BTreeMap::new();
+    for name in names {
+        let word: String = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let base = format!("{}_{}", prefix, word);
+        let mut id = base.clone();
+        let mut count = 1;
+        while !used.insert(id.clone()) {
+            count += 1;
+            id = format!("{}_{}", base, count);
+        }
+        ids.insert(name, id);
+    }
+    ids
+}
+
+fn mermaid_label(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "#quot;"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::fakedb::FakeDb;
+
+    #[test]
+    fn test_dot() {
+        let mut db = FakeDb::default();
+        db.apply("ALTER ROLE developer SUPERUSER").unwrap();
+        let dot = RoleGraph::from_context(&mut db).to_dot(true);
+
+        assert!(dot.starts_with("digraph roles {"));
+        assert!(dot.contains("  \"alice\" [shape=ellipse];"));
+        assert!(dot.contains(
+            "  \"developer\" [shape=box, style=filled, fillcolor=\"#f8d7da\", color=red];"
+        ));
+        assert!(dot.contains("  \"alice\" -> \"developer\" [label=\"admin\"];"));
+        assert!(dot.contains("  \"bob\" -> \"analyst\";"));
+        assert!(dot.contains("  \"bob\" -> \"schema:finance\" [style=dashed, label=\"write\"];"));
+        assert!(
+            dot.contains("  \"alice\" -> \"schema:marketing\" [style=dashed, label=\"write\"];")
+        );
+    }
+
+    #[test]
+    fn test_predefined_groups_from_context() {
+        let mut db = FakeDb::default();
+        db.apply("GRANT pg_monitor TO analyst").unwrap();
+        let graph = RoleGraph::from_context(&mut db);
+
+        assert_eq!(graph.roles["pg_monitor"], RoleNode::default());
+        let dot = graph.to_dot(false);
+        assert!(dot.contains("  \"pg_monitor\" [shape=box];"));
+        assert!(dot.contains("  \"analyst\" -> \"pg_monitor\";"));
+    }
+
+    #[test]
+    fn test_mermaid_from_spec() {
+        let spec: DatabaseSpec = serde_yaml::from_str(
+            "
+            version: 1
+            adapter: postgres
+            roles:
+              jdoe:
+                can_login: yes
+                member_of:
+                  - analyst
+                  - pg_monitor
+              analyst:
+                can_login: no
+                is_superuser: yes
+                privileges:
+                  schemas:
+                    write:
+                      - finance
+            ",
+        )
+        .unwrap();
+        let graph = RoleGraph::from_spec(&spec);
+
+        assert_eq!(
+            graph.to_mermaid(false),
+            [
+                "flowchart BT",
+                "  role_analyst[\"analyst\"]",
+                "  role_jdoe([\"jdoe\"])",
+                "  role_pg_monitor[\"pg_monitor\"]",
+                "  role_jdoe --> role_analyst",
+                "  role_jdoe --> role_pg_monitor",
+                "  classDef superuser fill:#f8d7da,stroke:#c00",
+                "  class role_analyst superuser",
+            ]
+            .join("\n")
+        );
+        assert!(graph
+            .to_mermaid(true)
+            .contains("  role_analyst -. write .-> schema_finance"));
+    }
+
+    #[test]
+    fn test_mermaid_ids_are_unique() {
+        let names = ["a-b".to_string(), "a_b".to_string(), "a.b".to_string()];
+        let ids = mermaid_ids("role", &names);
+
+        assert_eq!(ids[&names[0]], "role_a_b");
+        assert_eq!(ids[&names[2]], "role_a_b_2");
+        assert_eq!(ids[&names[1]], "role_a_b_3");
+    }
+}
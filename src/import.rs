@@ -0,0 +1,454 @@
+//! Importing specs from pgbedrock and permifrost.
+//!
+//! Both tools describe roles much like permirust does, so most of a spec
+//! carries over as is. Anything that has no equivalent in a permirust spec
+//! is left out and listed in [`Import::unsupported`], so nothing is dropped
+//! silently.
+//!
+//! permifrost manages Snowflake, where objects live in databases. A
+//! permirust spec describes a single database, so the database part of each
+//! name is dropped: `raw.finance` becomes the schema `finance` and
+//! `raw.finance.q2` the table `finance.q2`.
+use std::collections::BTreeSet;
+
+use anyhow::{Context as _, Result};
+use serde_yaml::{Mapping, Value};
+
+use crate::spec::{DatabaseSpec, Grant, Membership, Privileges, Role};
+
+/// The spec converted from another tool, and what could not be expressed.
+#[derive(Debug)]
+pub struct Import {
+    pub spec: DatabaseSpec,
+    /// One entry per setting that was left out, naming the role it was on
+    pub unsupported: Vec<String>,
+}
+
+/// Converts the pgbedrock or permifrost spec at `path`, as named by `from`.
+pub fn import_file(from: &str, path: &str) -> Result<Import> {
+    let source =
+        std::fs::read_to_string(path).with_context(|| format!("Failed to open {}", path))?;
+    match from {
+        "pgbedrock" => from_pgbedrock(&source),
+        "permifrost" => from_permifrost(&source),
+        _ => anyhow::bail!(
+            "Unknown format to import: {}, expected pgbedrock or permifrost",
+            from
+        ),
+    }
+    .with_context(|| format!("Failed to import {}", path))
+}
+
+/// Converts a pgbedrock spec, a map of role names to their definitions.
+pub fn from_pgbedrock(source: &str) -> Result<Import> {
+    let roles: Option<Mapping> = serde_yaml::from_str(source)?;
+    let mut import = Import::new();
+    for (name, config) in roles.unwrap_or_default() {
+        let name = key(&name)?;
+        // pgbedrock roles cannot log in unless they say so
+        let mut role = Role {
+            can_login: false,
+            ..Default::default()
+        };
+        for (key, value) in entries(&config) {
+            match key.as_str() {
+                "can_login" => role.can_login = boolean(&name, &key, value)?,
+                "is_superuser" => role.is_superuser = boolean(&name, &key, value)?,
+                "member_of" => {
+                    for group in names(value) {
+                        role.member_of.push(Membership::from(group.as_str()));
+                    }
+                }
+                "owns" => import.ownership(&name, &mut role, value, |_, object| Some(object)),
+                "privileges" => {
+                    // `personal_schemas` stands for every personal schema,
+                    // which a spec cannot name
+                    import.privileges(&name, &mut role.privileges, value, |_, object| {
+                        (object.split('.').next() != Some("personal_schemas")).then_some(object)
+                    })
+                }
+                "has_personal_schema" => role.has_personal_schema = boolean(&name, &key, value)?,
+                "attributes" => {
+                    for attribute in names(value) {
+                        import.unsupported(&name, format!("attribute {}", attribute));
+                    }
+                }
+                _ => import.unsupported(&name, format!("key {}", key)),
+            }
+        }
+        import.spec.roles.insert(name, role);
+    }
+    Ok(import)
+}
+
+/// Converts a permifrost spec. Roles and users both become roles, users
+/// being able to log in.
+pub fn from_permifrost(source: &str) -> Result<Import> {
+    let spec: Option<Mapping> = serde_yaml::from_str(source)?;
+    let spec = Value::Mapping(spec.unwrap_or_default());
+    let mut import = Import::new();
+    let mut databases = BTreeSet::new();
+    let mut strip_database = |object: String| {
+        let (database, rest) = object.split_once('.')?;
+        databases.insert(database.to_string());
+        // `db.*` and `db.*.*` cover every schema, which a spec cannot say
+        (!rest.starts_with('*')).then(|| rest.to_string())
+    };
+
+    for (key, value) in entries(&spec) {
+        match key.as_str() {
+            "version" | "roles" | "users" => {}
+            _ => {
+                for name in list_names(value) {
+                    import.unsupported(&name, format!("{} entry", key));
+                }
+            }
+        }
+    }
+
+    for (section, can_login) in [("roles", false), ("users", true)] {
+        let Some(entries_list) = spec.get(section) else {
+            continue;
+        };
+        for (name, config) in named_entries(entries_list)? {
+            let mut role = import.spec.roles.remove(&name).unwrap_or(Role {
+                can_login,
+                ..Default::default()
+            });
+            role.can_login |= can_login;
+            for (key, value) in entries(&config) {
+                match key.as_str() {
+                    "can_login" => role.can_login = boolean(&name, &key, value)?,
+                    "member_of" => match value {
+                        Value::Sequence(_) => {
+                            for group in names(value) {
+                                if !role.member_of.iter().any(|m| m.role == group) {
+                                    role.member_of.push(Membership::from(group.as_str()));
+                                }
+                            }
+                        }
+                        // Patterns such as `include: ["*"]` can't be expanded
+                        // without the roles of the account
+                        Value::Mapping(_) => import.unsupported(
+                            &name,
+                            "member_of include and exclude patterns".to_string(),
+                        ),
+                        _ => import.unsupported(&name, "member_of that is not a list".to_string()),
+                    },
+                    "owns" => {
+                        import.ownership(&name, &mut role, value, |kind, object| match kind {
+                            "databases" => None,
+                            _ => strip_database(object),
+                        })
+                    }
+                    "privileges" => {
+                        import.privileges(&name, &mut role.privileges, value, |kind, object| {
+                            match kind {
+                                "databases" => None,
+                                _ => strip_database(object),
+                            }
+                        })
+                    }
+                    _ => import.unsupported(&name, format!("key {}", key)),
+                }
+            }
+            import.spec.roles.insert(name, role);
+        }
+    }
+
+    if databases.len() > 1 {
+        let databases: Vec<String> = databases.into_iter().collect();
+        import.unsupported.push(format!(
+            "objects from several databases were merged into one: {}",
+            databases.join(", ")
+        ));
+    }
+    Ok(import)
+}
+
+impl Import {
+    fn new() -> Self {
+        Import {
+            spec: DatabaseSpec::new("postgres"),
+            unsupported: vec![],
+        }
+    }
+
+    fn unsupported(&mut self, role: &str, what: String) {
+        self.unsupported.push(format!("{}: {}", role, what));
+    }
+
+    /// Copies `owns` into the role. `convert` maps each object name for
+    /// its kind, returning `None` for objects a spec cannot express.
+    fn ownership(
+        &mut self,
+        role_name: &str,
+        role: &mut Role,
+        owns: &Value,
+        mut convert: impl FnMut(&str, String) -> Option<String>,
+    ) {
+        for (kind, objects) in entries(owns) {
+            let list = match kind.as_str() {
+                "schemas" => &mut role.owns.schemas,
+                "tables" => &mut role.owns.tables,
+                "sequences" => &mut role.owns.sequences,
+                "functions" => &mut role.owns.functions,
+                _ => {
+                    for object in names(objects) {
+                        self.unsupported(role_name, format!("owns {} {}", kind, object));
+                    }
+                    continue;
+                }
+            };
+            for object in names(objects) {
+                match convert(&kind, object.clone()) {
+                    Some(object) => list.push(object),
+                    None => self.unsupported(role_name, format!("owns {} {}", kind, object)),
+                }
+            }
+        }
+    }
+
+    /// Copies `privileges` into `privileges`, see [`Import::ownership`].
+    fn privileges(
+        &mut self,
+        role_name: &str,
+        privileges: &mut Privileges,
+        config: &Value,
+        mut convert: impl FnMut(&str, String) -> Option<String>,
+    ) {
+        for (kind, access) in entries(config) {
+            for (access, objects) in entries(access) {
+                for object in names(objects) {
+                    let grants = grants(privileges, &kind, &access);
+                    match (grants, convert(&kind, object.clone())) {
+                        (Some(grants), Some(object)) => {
+                            let grant = Grant::from(object.as_str());
+                            if !grants.contains(&grant) {
+                                grants.push(grant);
+                            }
+                        }
+                        _ => self.unsupported(
+                            role_name,
+                            format!("{} privilege on {} {}", access, kind, object),
+                        ),
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn grants<'a>(
+    privileges: &'a mut Privileges,
+    kind: &str,
+    access: &str,
+) -> Option<&'a mut Vec<Grant>> {
+    let (read, write) = match kind {
+        "schemas" => (&mut privileges.schemas.read, &mut privileges.schemas.write),
+        "tables" => (&mut privileges.tables.read, &mut privileges.tables.write),
+        "sequences" => (
+            &mut privileges.sequences.read,
+            &mut privileges.sequences.write,
+        ),
+        // Functions only support read, which grants EXECUTE
+        "functions" if access == "read" => return Some(&mut privileges.functions.read),
+        _ => return None,
+    };
+    match access {
+        "read" => Some(read),
+        "write" => Some(write),
+        _ => None,
+    }
+}
+
+fn key(value: &Value) -> Result<String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Number(n) => Ok(n.to_string()),
+        _ => anyhow::bail!("Expected a name, found {:?}", value),
+    }
+}
+
+/// The entries of a map, or nothing for anything else.
+fn entries(value: &Value) -> Vec<(String, &Value)> {
+    match value {
+        Value::Mapping(mapping) => mapping
+            .iter()
+            .filter_map(|(k, v)| key(k).ok().map(|k| (k, v)))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// The names in a list, or the single name given instead of a list.
+fn names(value: &Value) -> Vec<String> {
+    match value {
+        Value::Sequence(items) => items.iter().filter_map(|i| key(i).ok()).collect(),
+        Value::Null => vec![],
+        other => key(other).into_iter().collect(),
+    }
+}
+
+/// permifrost lists entries as single key maps, e.g. `- loader: {...}`.
+fn named_entries(value: &Value) -> Result<Vec<(String, Value)>> {
+    let Value::Sequence(items) = value else {
+        anyhow::bail!("Expected a list of roles or users");
+    };
+    let mut named = vec![];
+    for item in items {
+        match item {
+            Value::Mapping(mapping) => {
+                for (name, config) in mapping {
+                    named.push((key(name)?, config.clone()));
+                }
+            }
+            other => named.push((key(other)?, Value::Null)),
+        }
+    }
+    Ok(named)
+}
+
+/// The names of a permifrost section such as `warehouses`.
+fn list_names(value: &Value) -> Vec<String> {
+    named_entries(value)
+        .map(|entries| entries.into_iter().map(|(name, _)| name).collect())
+        .unwrap_or_default()
+}
+
+fn boolean(role: &str, key: &str, value: &Value) -> Result<bool> {
+    match value {
+        Value::Bool(b) => Ok(*b),
+        Value::String(s) if matches!(s.as_str(), "yes" | "true") => Ok(true),
+        Value::String(s) if matches!(s.as_str(), "no" | "false") => Ok(false),
+        _ => anyhow::bail!("Expected yes or no for {} of {}", key, role),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_pgbedrock() {
+        let import = from_pgbedrock(
+            "
+            analyst:
+              privileges:
+                schemas:
+                  read:
+                    - finance
+                tables:
+                  read:
+                    - finance.*
+            jdoe:
+              can_login: yes
+              has_personal_schema: yes
+              attributes:
+                - CONNECTION LIMIT 10
+              member_of:
+                - analyst
+              owns:
+                schemas:
+                  - reports
+              privileges:
+                tables:
+                  write:
+                    - personal_schemas.*
+                functions:
+                  write:
+                    - reports.refresh()
+            ",
+        )
+        .unwrap();
+
+        let analyst = &import.spec.roles["analyst"];
+        assert!(!analyst.can_login);
+        assert_eq!(
+            analyst.privileges.tables.read,
+            vec![Grant::from("finance.*")]
+        );
+        let jdoe = &import.spec.roles["jdoe"];
+        assert!(jdoe.can_login);
+        assert_eq!(jdoe.member_of, vec![Membership::from("analyst")]);
+        assert_eq!(jdoe.owns.schemas, vec!["reports"]);
+        assert!(jdoe.has_personal_schema);
+        assert_eq!(
+            import.unsupported,
+            vec![
+                "jdoe: attribute CONNECTION LIMIT 10",
+                "jdoe: write privilege on tables personal_schemas.*",
+                "jdoe: write privilege on functions reports.refresh()",
+            ]
+        );
+        import.spec.validate().unwrap();
+    }
+
+    #[test]
+    fn test_from_permifrost() {
+        let import = from_permifrost(
+            "
+            version: \"1.0\"
+            databases:
+              - raw:
+                  shared: no
+            warehouses:
+              - loading:
+                  size: x-small
+            roles:
+              - loader:
+                  warehouses:
+                    - loading
+                  privileges:
+                    databases:
+                      read:
+                        - raw
+                    schemas:
+                      read:
+                        - raw.finance
+                      write:
+                        - raw.*
+                    tables:
+                      read:
+                        - raw.finance.*
+                        - analytics.reports.q2
+              - airflow:
+                  member_of:
+                    - loader
+              - reporter:
+                  member_of:
+                    exclude:
+                      - loader
+            users:
+              - airflow:
+                  can_login: yes
+                  member_of:
+                    - loader
+            ",
+        )
+        .unwrap();
+
+        let loader = &import.spec.roles["loader"];
+        assert!(!loader.can_login);
+        assert_eq!(loader.privileges.schemas.read, vec![Grant::from("finance")]);
+        assert_eq!(
+            loader.privileges.tables.read,
+            vec![Grant::from("finance.*"), Grant::from("reports.q2")]
+        );
+        let airflow = &import.spec.roles["airflow"];
+        assert!(airflow.can_login);
+        assert_eq!(airflow.member_of, vec![Membership::from("loader")]);
+        assert_eq!(
+            import.unsupported,
+            vec![
+                "raw: databases entry",
+                "loading: warehouses entry",
+                "loader: key warehouses",
+                "loader: read privilege on databases raw",
+                "loader: write privilege on schemas raw.*",
+                "reporter: member_of include and exclude patterns",
+                "objects from several databases were merged into one: analytics, raw",
+            ]
+        );
+    }
+}
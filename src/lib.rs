@@ -1,6 +1,20 @@
+pub mod access;
 pub mod adapters;
 pub mod analyzer;
 pub mod context;
+pub mod escalation;
 pub mod generate;
+pub mod graph;
+pub mod import;
+pub mod load;
+pub mod overlay;
+pub mod password;
+pub mod policy;
 mod queries;
+pub mod report;
+pub mod rules;
+pub mod schema;
+pub mod scope;
 pub mod spec;
+pub mod template;
+pub mod validate;
@@ -0,0 +1,392 @@
+//! Specs split across several files.
+//!
+//! A spec can be a single file, a directory, or a file that pulls in others
+//! with `include`:
+//!
+//! ```yaml
+//! version: 1
+//! adapter: postgres
+//! include:
+//!   - teams/
+//!   - shared/service_accounts.yml
+//! ```
+//!
+//! Included paths are relative to the file that includes them. A directory
+//! stands for every `.yml` and `.yaml` file below it, in path order, so each
+//! team can own its own file. The files are merged into one
+//! [`DatabaseSpec`]: `ignore` and `manage_only` rules are combined, `version`
+//! and `adapter` must agree wherever they are set, and a role, or the
+//! policies of a table, may only be defined once. Each file can have
+//! overlays per environment, see [`crate::overlay`].
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context as _, Result};
+use serde::Deserialize;
+use serde_yaml::Value;
+
+use crate::overlay;
+use crate::policy::TablePolicies;
+use crate::scope::ScopeRules;
+use crate::spec::DatabaseSpec;
+use crate::template::{self, Templates};
+
+/// One file of a spec. Unlike a whole spec every key is optional.
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct SpecFile {
+    pub version: Option<u8>,
+    pub adapter: Option<String>,
+    #[serde(default)]
+    pub ignore: ScopeRules,
+    #[serde(default)]
+    pub manage_only: ScopeRules,
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub templates: Templates,
+    /// Roles are kept as YAML until the templates they extend are known
+    #[serde(default)]
+    pub roles: HashMap<String, Value>,
+    #[serde(default)]
+    pub policies: TablePolicies,
+}
+
+impl TryFrom<SpecFile> for DatabaseSpec {
+    type Error = String;
+
+    fn try_from(spec: SpecFile) -> Result<Self, Self::Error> {
+        Ok(DatabaseSpec {
+            version: spec.version.ok_or("missing field `version`")?,
+            adapter: spec.adapter.ok_or("missing field `adapter`")?,
+            roles: template::expand_roles(&spec.templates, spec.roles)?,
+            ignore: spec.ignore,
+            manage_only: spec.manage_only,
+            include: spec.include,
+            templates: spec.templates,
+            policies: spec.policies,
+        })
+    }
+}
+
+impl SpecFile {
+    /// Reads the file at `path` with its overlay for `env`, see
+    /// [`crate::overlay`].
+    fn read(path: &Path, env: Option<&str>) -> Result<SpecFile> {
+        let spec: Option<SpecFile> = serde_yaml::from_value(overlay::read_yaml(path, env)?)
+            .with_context(|| format!("Failed to parse spec file: {}", path.display()))?;
+        Ok(spec.unwrap_or_default())
+    }
+}
+
+/// Every file making up the spec at `path`, in the order they are merged.
+/// A file reached more than once, e.g. through a directory and an
+/// `include`, is only listed the first time. Files that do not parse are
+/// still listed, their includes are skipped.
+pub fn spec_files(path: &Path) -> Result<Vec<PathBuf>> {
+    #[derive(Deserialize)]
+    struct Includes {
+        #[serde(default)]
+        include: Vec<String>,
+    }
+
+    let mut files = vec![];
+    collect(path, &mut HashSet::new(), &mut files, &mut |file| {
+        let source = std::fs::read_to_string(file)
+            .with_context(|| format!("Failed to open spec file: {}", file.display()))?;
+        Ok(serde_yaml::from_str::<Option<Includes>>(&source)
+            .ok()
+            .flatten()
+            .map(|i| i.include)
+            .unwrap_or_default())
+    })?;
+    Ok(files)
+}
+
+/// Walks the spec at `path` depth first, calling `includes` on each file to
+/// read it and get the paths it includes.
+fn collect(
+    path: &Path,
+    seen: &mut HashSet<PathBuf>,
+    files: &mut Vec<PathBuf>,
+    includes: &mut dyn FnMut(&Path) -> Result<Vec<String>>,
+) -> Result<()> {
+    if path.is_dir() {
+        for file in yaml_files(path)? {
+            collect(&file, seen, files, includes)?;
+        }
+        return Ok(());
+    }
+
+    let canonical = path
+        .canonicalize()
+        .with_context(|| format!("Failed to open spec file: {}", path.display()))?;
+    if !seen.insert(canonical) {
+        return Ok(());
+    }
+    files.push(path.to_path_buf());
+    let base = path.parent().unwrap_or(Path::new("."));
+    for include in includes(path)? {
+        collect(&base.join(include), seen, files, includes)
+            .with_context(|| format!("Included from {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// The `.yml` and `.yaml` files below `dir`, sorted by path. Hidden files
+/// and directories are skipped, and so are overlays, which are only read
+/// with the file they belong to.
+fn yaml_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read spec directory: {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        let hidden = path
+            .file_name()
+            .is_some_and(|n| n.to_string_lossy().starts_with('.'));
+        if hidden {
+            continue;
+        }
+        if path.is_dir() {
+            files.extend(yaml_files(&path)?);
+        } else if path.extension().is_some_and(|e| e == "yml" || e == "yaml")
+            && !overlay::is_overlay(&path)
+        {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Reads the spec at `path`, a file or a directory, following includes and
+/// merging everything into one spec. Each file is merged with its overlay
+/// for `env`, if any. Conflicts between files are all reported at once.
+pub fn load_spec(path: &Path, env: Option<&str>) -> Result<DatabaseSpec> {
+    let mut files: Vec<(PathBuf, SpecFile)> = vec![];
+    collect(path, &mut HashSet::new(), &mut vec![], &mut |file| {
+        let spec = SpecFile::read(file, env)?;
+        let includes = spec.include.clone();
+        files.push((file.to_path_buf(), spec));
+        Ok(includes)
+    })?;
+    let mut conflicts = vec![];
+    let mut version: Option<(u8, &Path)> = None;
+    let mut adapter: Option<(&String, &Path)> = None;
+    let mut templates_in: BTreeMap<&String, &Path> = BTreeMap::new();
+    let mut roles_in: BTreeMap<&String, &Path> = BTreeMap::new();
+    let mut tables_in: BTreeMap<&String, &Path> = BTreeMap::new();
+
+    for (file, spec) in &files {
+        match (version, spec.version) {
+            (Some((first, first_file)), Some(v)) if first != v => conflicts.push(format!(
+                "version is {} in {} but {} in {}",
+                first,
+                first_file.display(),
+                v,
+                file.display()
+            )),
+            (None, Some(v)) => version = Some((v, file)),
+            _ => {}
+        }
+        match (adapter, &spec.adapter) {
+            (Some((first, first_file)), Some(a)) if first != a => conflicts.push(format!(
+                "adapter is {} in {} but {} in {}",
+                first,
+                first_file.display(),
+                a,
+                file.display()
+            )),
+            (None, Some(a)) => adapter = Some((a, file)),
+            _ => {}
+        }
+        for name in spec.templates.keys() {
+            define_once("Template", name, file, &mut templates_in, &mut conflicts);
+        }
+        let mut names: Vec<&String> = spec.roles.keys().collect();
+        names.sort();
+        for name in names {
+            define_once("Role", name, file, &mut roles_in, &mut conflicts);
+        }
+        for table in spec.policies.keys() {
+            define_once("Policies for", table, file, &mut tables_in, &mut conflicts);
+        }
+    }
+
+    if version.is_none() {
+        anyhow::bail!("No spec file in {} sets `version`", path.display());
+    }
+    if adapter.is_none() {
+        anyhow::bail!("No spec file in {} sets `adapter`", path.display());
+    }
+    if !conflicts.is_empty() {
+        anyhow::bail!(
+            "Conflicting spec files in {}:\n  {}",
+            path.display(),
+            conflicts.join("\n  ")
+        );
+    }
+
+    let mut merged = SpecFile {
+        version: version.map(|(v, _)| v),
+        adapter: adapter.map(|(a, _)| a.clone()),
+        ..Default::default()
+    };
+    for (_, spec) in files {
+        extend_rules(&mut merged.ignore, &spec.ignore);
+        extend_rules(&mut merged.manage_only, &spec.manage_only);
+        merged.templates.extend(spec.templates);
+        merged.roles.extend(spec.roles);
+        merged.policies.extend(spec.policies);
+    }
+    DatabaseSpec::try_from(merged).map_err(|e| anyhow::anyhow!("Invalid spec: {}", e))
+}
+
+/// Records that `name` is defined in `file`, or a conflict if another file
+/// already defines it.
+fn define_once<'a>(
+    what: &str,
+    name: &'a String,
+    file: &'a Path,
+    defined_in: &mut BTreeMap<&'a String, &'a Path>,
+    conflicts: &mut Vec<String>,
+) {
+    match defined_in.get(name) {
+        Some(first) => conflicts.push(format!(
+            "{} {} is defined in both {} and {}",
+            what,
+            name,
+            first.display(),
+            file.display()
+        )),
+        None => {
+            defined_in.insert(name, file);
+        }
+    }
+}
+
+fn extend_rules(rules: &mut ScopeRules, other: &ScopeRules) {
+    rules.roles.extend(other.roles.iter().cloned());
+    rules.schemas.extend(other.schemas.iter().cloned());
+    rules.objects.extend(other.objects.iter().cloned());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory holding `files`, removed when dropped.
+    struct TempSpec(PathBuf);
+
+    impl TempSpec {
+        fn new(name: &str, files: &[(&str, &str)]) -> TempSpec {
+            let dir =
+                std::env::temp_dir().join(format!("permirust-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            for (file, contents) in files {
+                let path = dir.join(file);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, contents).unwrap();
+            }
+            TempSpec(dir)
+        }
+    }
+
+    impl Drop for TempSpec {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_include_merges_files() {
+        let path = Path::new("resources/split/spec.yml");
+        let spec = load_spec(path, None).unwrap();
+
+        let mut roles: Vec<&String> = spec.roles.keys().collect();
+        roles.sort();
+        assert_eq!(roles, vec!["analyst", "finance", "jdoe", "reporter"]);
+        assert_eq!(spec.adapter, "postgres");
+        assert_eq!(spec.ignore.roles, vec!["rds_*", "tmp_*"]);
+        assert!(spec.include.is_empty());
+        // Templates apply across files
+        assert_eq!(spec.roles["jdoe"].member_of[0].role, "analyst");
+        assert_eq!(
+            spec_files(path).unwrap(),
+            vec![
+                PathBuf::from("resources/split/spec.yml"),
+                PathBuf::from("resources/split/teams/analytics.yml"),
+                PathBuf::from("resources/split/teams/finance.yml"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_directory_is_read_once_per_file() {
+        // The directory contains spec.yml, which includes teams/ again
+        let spec = load_spec(Path::new("resources/split"), None).unwrap();
+        assert_eq!(spec.roles.len(), 4);
+    }
+
+    #[test]
+    fn test_env_overlays() {
+        let path = Path::new("resources/overlay");
+        let roles = |env| {
+            let spec = load_spec(path, env).unwrap();
+            let mut roles: Vec<String> = spec.roles.keys().cloned().collect();
+            roles.sort();
+            (roles, spec.roles["analyst"].is_superuser)
+        };
+
+        assert_eq!(roles(None), (vec!["analyst".into(), "app".into()], false));
+        assert_eq!(
+            roles(Some("dev")),
+            (vec!["analyst".into(), "app".into(), "sandbox".into()], true)
+        );
+        let (prod, superuser) = roles(Some("prod"));
+        assert_eq!(prod, vec!["analyst", "app", "etl"]);
+        assert!(!superuser);
+        // Privileges from the base file are kept by the overlay
+        let dev = load_spec(path, Some("dev")).unwrap();
+        assert_eq!(dev.roles["analyst"].privileges.schemas.read.len(), 1);
+    }
+
+    #[test]
+    fn test_reports_conflicts() {
+        let dir = TempSpec::new(
+            "conflicts",
+            &[
+                (
+                    "a.yml",
+                    "version: 1\nadapter: postgres\nroles:\n  jdoe: {}\n",
+                ),
+                (
+                    "b.yml",
+                    "adapter: snowflake\nroles:\n  jdoe: {}\n  bob: {}\n",
+                ),
+            ],
+        );
+
+        let error = load_spec(&dir.0, None).unwrap_err().to_string();
+        let a = dir.0.join("a.yml");
+        let b = dir.0.join("b.yml");
+        assert!(error.contains(&format!(
+            "adapter is postgres in {} but snowflake in {}",
+            a.display(),
+            b.display()
+        )));
+        assert!(error.contains(&format!(
+            "Role jdoe is defined in both {} and {}",
+            a.display(),
+            b.display()
+        )));
+        assert!(!error.contains("bob"));
+    }
+
+    #[test]
+    fn test_requires_version_and_adapter() {
+        let dir = TempSpec::new("missing", &[("a.yml", "roles:\n  jdoe: {}\n")]);
+        let error = load_spec(&dir.0, None).unwrap_err().to_string();
+        assert!(error.contains("sets `version`"));
+    }
+}
@@ -4,10 +4,19 @@ use std::process::exit;
 use clap::{Parser, Subcommand};
 use log::error;
 use log::info;
+use permirust::access::AccessGraph;
 use permirust::adapters::fakedb::FakeDb;
 use permirust::adapters::postgres::PostgresClient;
+use permirust::adapters::snapshot::{Snapshot, SnapshotContext};
 use permirust::analyzer::role_analyzer;
-use permirust::generate::generate_spec;
+use permirust::context::{Context, PrivilegeType};
+use permirust::escalation::EscalationGraph;
+use permirust::generate::{generate_spec_with, GenerateOptions};
+use permirust::graph::RoleGraph;
+use permirust::password::redact;
+use permirust::report::{spec_history, Report};
+use permirust::rules::{render, Catalog, Rules, Severity};
+use permirust::spec::DatabaseSpec;
 
 #[derive(Parser)]
 #[command(
@@ -22,14 +31,142 @@ struct Cli {
     #[arg(short, long, default_value = "postgres")]
     adapter: String,
 
+    /// Merge the spec overlays for this environment, e.g. `prod` reads
+    /// spec.prod.yml on top of spec.yml
+    #[arg(long, global = true)]
+    env: Option<String>,
+
+    /// Read the database state from a snapshot file instead of connecting
+    #[arg(long, value_name = "FILE", global = true)]
+    snapshot: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    Generate {},
+    Generate {
+        /// Collapse grants covering a whole schema into `schema.*`
+        #[arg(long)]
+        compact: bool,
+        /// Move what groups of roles have in common into templates
+        #[arg(long)]
+        detect_templates: bool,
+    },
+    /// Plan the SQL needed to make the database match the spec, read from
+    /// --spec (a file or a directory) or ./resources/spec.yml
     Configure {},
+    /// Capture the current database state into a snapshot file
+    Snapshot {
+        /// Output format, `yaml` or `json`
+        #[arg(short, long, default_value = "yaml")]
+        format: String,
+    },
+    /// List every role that can use an object, and through which roles
+    WhoCan {
+        /// A schema, or a fully qualified table, sequence or function
+        object: String,
+        /// Only show `read` or `write` privileges
+        #[arg(short, long)]
+        privilege: Option<String>,
+    },
+    /// List every privilege a role can use, and through which roles
+    WhatCan { role: String },
+    /// List every role that can escalate to the equivalent of a superuser,
+    /// and the chain of roles that allows it. SECURITY DEFINER functions that
+    /// belong to an extension are not checked.
+    Escalations {
+        /// Only show the escalations of this role
+        role: Option<String>,
+    },
+    /// Check a spec and report every problem with its location
+    Validate {
+        /// The spec to check, defaults to --spec or ./resources/spec.yml
+        file: Option<PathBuf>,
+        /// Warn about memberships and grants expiring within this many days
+        #[arg(long, default_value = "7")]
+        expiring_within: i64,
+    },
+    /// Convert a pgbedrock or permifrost spec into a permirust spec
+    Import {
+        /// The tool the spec is written for, `pgbedrock` or `permifrost`
+        #[arg(long)]
+        from: String,
+        file: PathBuf,
+    },
+    /// Print the JSON Schema of the spec format, for editor autocompletion
+    Schema {},
+    /// Check the spec, or the database when no spec is given, against
+    /// security rules
+    Lint {
+        /// The rules to check, every built-in check when not given
+        #[arg(long, value_name = "FILE")]
+        rules: Option<PathBuf>,
+        /// Output format, `text`, `json` or `sarif`
+        #[arg(short, long, default_value = "text")]
+        format: String,
+    },
+    /// Write an access review of every login role, from the spec or from
+    /// the database when no spec is given
+    Report {
+        /// Output format, `markdown` or `html`
+        #[arg(short, long, default_value = "markdown")]
+        format: String,
+    },
+    /// Draw the role hierarchy from the spec, or from the database when no
+    /// spec is given
+    Graph {
+        /// Output format, `dot` or `mermaid`
+        #[arg(short, long, default_value = "dot")]
+        format: String,
+        /// Also draw edges to the schemas each role can write
+        #[arg(long)]
+        schemas: bool,
+    },
+}
+
+fn connect() -> PostgresClient {
+    let conn_str = "host=localhost port=54321 user=postgres password=password";
+    match PostgresClient::new(conn_str) {
+        Ok(db) => db,
+        Err(e) => {
+            error!("Failed to connect to database: {}", e);
+            error!("Please check your connection string and try again");
+            exit(1);
+        }
+    }
+}
+
+/// Builds the access graph from the snapshot when one is given, and from
+/// the database otherwise.
+fn access_graph(snapshot: Option<&std::path::Path>) -> AccessGraph {
+    if let Some(path) = snapshot {
+        return AccessGraph::from_context(&mut read_snapshot(path));
+    }
+    AccessGraph::from_context(&mut connect())
+}
+
+fn read_spec(path: &str, env: Option<&str>) -> anyhow::Result<DatabaseSpec> {
+    if let Some(env) = env {
+        info!("Using environment: {}", env);
+    }
+    DatabaseSpec::read_file_for_env(path, env)
+}
+
+/// The planned statements, with password verifiers masked.
+fn redacted(sql: &[String]) -> Vec<String> {
+    sql.iter().map(|statement| redact(statement)).collect()
+}
+
+fn read_snapshot(path: &std::path::Path) -> SnapshotContext {
+    match SnapshotContext::read_file(&path.to_string_lossy()) {
+        Ok(context) => {
+            info!("Using snapshot: {}", path.display());
+            context
+        }
+        Err(e) => panic!("Failed to read snapshot file: {}", e),
+    }
 }
 
 fn main() {
@@ -41,14 +178,38 @@ fn main() {
     }
 
     match &cli.command {
-        Some(Commands::Generate {}) => {
+        Some(Commands::Generate {
+            compact,
+            detect_templates,
+        }) => {
             info!("Generating...");
+            // An existing spec can restrict what is generated with its
+            // `ignore` and `manage_only` rules
+            let mut options = GenerateOptions {
+                compact: *compact,
+                detect_templates: *detect_templates,
+                ..Default::default()
+            };
+            if let Some(spec) = cli.spec.as_deref() {
+                match read_spec(&spec.to_string_lossy(), cli.env.as_deref()) {
+                    Ok(spec) => options.scope = spec.scope(),
+                    Err(e) => panic!("Failed to read spec file: {}", e),
+                }
+            }
+            if let Some(path) = cli.snapshot.as_deref() {
+                let spec = generate_spec_with(read_snapshot(path), &options)
+                    .expect("Failed to generate spec");
+                info!("Successfully generated spec");
+                println!("{}", spec);
+                return;
+            }
             match cli.adapter.as_str() {
                 "postgres" => {
                     let conn_str = "host=localhost port=54321 user=postgres password=password";
                     match PostgresClient::new(conn_str) {
                         Ok(db) => {
-                            let spec = generate_spec(db).expect("Failed to generate spec");
+                            let spec =
+                                generate_spec_with(db, &options).expect("Failed to generate spec");
                             info!("Successfully generated spec");
                             println!("{}", spec);
                         }
@@ -60,8 +221,8 @@ fn main() {
                     }
                 }
                 "fake" => {
-                    let db = FakeDb {};
-                    generate_spec(db).expect("Failed to generate spec");
+                    let db = FakeDb::default();
+                    generate_spec_with(db, &options).expect("Failed to generate spec");
                 }
                 _ => panic!("Unknown adapter"),
             };
@@ -69,8 +230,13 @@ fn main() {
 
         Some(Commands::Configure {}) => {
             info!("Configuring...");
-            let fpath = "./resources/spec.yml";
-            let mut spec = match permirust::spec::DatabaseSpec::read_file(fpath) {
+            let fpath = cli
+                .spec
+                .as_deref()
+                .map_or("./resources/spec.yml".to_string(), |p| {
+                    p.to_string_lossy().to_string()
+                });
+            let mut spec = match read_spec(&fpath, cli.env.as_deref()) {
                 Ok(spec) => {
                     info!("Successfully read spec");
                     spec
@@ -80,6 +246,14 @@ fn main() {
 
             let mut sql: Vec<String> = vec![];
 
+            if let Some(path) = cli.snapshot.as_deref() {
+                role_analyzer(&mut sql, read_snapshot(path), &mut spec)
+                    .expect("Failed to analyze roles");
+                info!("Successfully analyzed roles");
+                println!("Sql: {:?}", redacted(&sql));
+                return;
+            }
+
             match spec.adapter.as_str() {
                 "postgres" => {
                     let conn_str = "host=localhost port=54321 user=postgres password=password";
@@ -90,7 +264,7 @@ fn main() {
                             role_analyzer(&mut sql, db, &mut spec)
                                 .expect("Failed to analyze roles");
                             info!("Successfully analyzed roles");
-                            println!("Sql: {:?}", sql)
+                            println!("Sql: {:?}", redacted(&sql))
                         }
                         Err(e) => {
                             error!("Failed to connect to database: {}", e);
@@ -102,6 +276,197 @@ fn main() {
                 _ => panic!("Unknown adapter"),
             }
         }
+        Some(Commands::Snapshot { format }) => {
+            info!("Capturing snapshot...");
+            let snapshot = Snapshot::capture(&mut connect());
+            let output = match format.as_str() {
+                "yaml" => snapshot.to_yaml().expect("Failed to serialize snapshot"),
+                "json" => snapshot.to_json().expect("Failed to serialize snapshot"),
+                _ => panic!("Unknown snapshot format: {}", format),
+            };
+            info!("Successfully captured snapshot");
+            println!("{}", output);
+        }
+        Some(Commands::WhoCan { object, privilege }) => {
+            let privilege = privilege.as_deref().map(|p| match p {
+                "read" => PrivilegeType::Read,
+                "write" => PrivilegeType::Write,
+                _ => panic!("Unknown privilege: {}", p),
+            });
+            let graph = access_graph(cli.snapshot.as_deref());
+            let objects = graph.find_objects(object);
+            if objects.is_empty() {
+                error!("No object named {}", object);
+                exit(1);
+            }
+            for object in objects {
+                for effective in graph.who_can(object) {
+                    if privilege.is_none_or(|p| p == effective.privilege) {
+                        println!("{}", effective);
+                    }
+                }
+            }
+        }
+        Some(Commands::WhatCan { role }) => {
+            let graph = access_graph(cli.snapshot.as_deref());
+            if !graph.roles().any(|r| r == role) {
+                error!("No role named {}", role);
+                exit(1);
+            }
+            for effective in graph.what_can(role) {
+                println!("{}", effective);
+            }
+        }
+        Some(Commands::Escalations { role }) => {
+            let graph = if let Some(path) = cli.snapshot.as_deref() {
+                EscalationGraph::from_context(&mut read_snapshot(path))
+            } else {
+                EscalationGraph::from_context(&mut connect())
+            };
+            let escalations = match role {
+                Some(role) => graph.escalations_of(role),
+                None => graph.escalations(),
+            };
+            for escalation in escalations {
+                println!("{}", escalation);
+            }
+        }
+        Some(Commands::Validate {
+            file,
+            expiring_within,
+        }) => {
+            let path = file
+                .as_deref()
+                .or(cli.spec.as_deref())
+                .map_or("./resources/spec.yml".to_string(), |p| {
+                    p.to_string_lossy().to_string()
+                });
+            let errors = match permirust::validate::validate_file(&path, cli.env.as_deref()) {
+                Ok(errors) => errors,
+                Err(e) => {
+                    error!("{:#}", e);
+                    exit(1);
+                }
+            };
+            if errors.is_empty() {
+                println!("{} is valid", path);
+                let within = chrono::Duration::days(*expiring_within);
+                match permirust::validate::expiry_warnings(
+                    &path,
+                    cli.env.as_deref(),
+                    chrono::Utc::now(),
+                    within,
+                ) {
+                    Ok(warnings) => {
+                        for w in &warnings {
+                            println!("{}:{}:{}: warning: {}", w.file, w.line, w.column, w.message);
+                        }
+                    }
+                    Err(e) => error!("{:#}", e),
+                }
+                return;
+            }
+            for e in &errors {
+                println!("{}", e);
+            }
+            println!("{} problem(s) found", errors.len());
+            exit(1);
+        }
+        Some(Commands::Import { from, file }) => {
+            let import = match permirust::import::import_file(from, &file.to_string_lossy()) {
+                Ok(import) => import,
+                Err(e) => {
+                    error!("{:#}", e);
+                    exit(1);
+                }
+            };
+            let spec = import.spec.to_yaml().expect("Failed to serialize spec");
+            println!("{}", spec);
+            if !import.unsupported.is_empty() {
+                eprintln!("Could not express {} item(s):", import.unsupported.len());
+                for item in &import.unsupported {
+                    eprintln!("  {}", item);
+                }
+            }
+        }
+        Some(Commands::Schema {}) => {
+            let schema = permirust::schema::spec_schema_json().expect("Failed to serialize schema");
+            println!("{}", schema);
+        }
+        Some(Commands::Lint { rules, format }) => {
+            let rules = match rules.as_deref() {
+                Some(path) => match Rules::read_file(&path.to_string_lossy()) {
+                    Ok(rules) => rules,
+                    Err(e) => {
+                        error!("{:#}", e);
+                        exit(1);
+                    }
+                },
+                None => Rules::default(),
+            };
+            let (catalog, source) = if let Some(spec) = cli.spec.as_deref() {
+                match read_spec(&spec.to_string_lossy(), cli.env.as_deref()) {
+                    Ok(parsed) => (Catalog::from_spec(&parsed), Some(spec)),
+                    Err(e) => panic!("Failed to read spec file: {}", e),
+                }
+            } else if let Some(path) = cli.snapshot.as_deref() {
+                (Catalog::from_context(&mut read_snapshot(path)), Some(path))
+            } else {
+                (Catalog::from_context(&mut connect()), None)
+            };
+            let findings = rules.check(&catalog);
+            let source = source.map(|p| p.to_string_lossy().to_string());
+            match render(&findings, &rules, format, source.as_deref()) {
+                Ok(output) if !output.is_empty() => println!("{}", output),
+                Ok(_) => {}
+                Err(e) => {
+                    error!("{:#}", e);
+                    exit(1);
+                }
+            }
+            if findings.iter().any(|f| f.severity == Severity::Error) {
+                exit(1);
+            }
+        }
+        Some(Commands::Report { format }) => {
+            let report = if let Some(spec) = cli.spec.as_deref() {
+                let path = spec.to_string_lossy();
+                match read_spec(&path, cli.env.as_deref()) {
+                    Ok(parsed) => Report::from_spec(&parsed, &path, &spec_history(&path)),
+                    Err(e) => panic!("Failed to read spec file: {}", e),
+                }
+            } else if let Some(path) = cli.snapshot.as_deref() {
+                Report::from_context(&mut read_snapshot(path), &path.to_string_lossy())
+            } else {
+                let mut db = connect();
+                let source = format!("database {}", db.database_name());
+                Report::from_context(&mut db, &source)
+            };
+            match report.render(format) {
+                Ok(output) => print!("{}", output),
+                Err(e) => {
+                    error!("{:#}", e);
+                    exit(1);
+                }
+            }
+        }
+        Some(Commands::Graph { format, schemas }) => {
+            let graph = if let Some(spec) = cli.spec.as_deref() {
+                match read_spec(&spec.to_string_lossy(), cli.env.as_deref()) {
+                    Ok(spec) => RoleGraph::from_spec(&spec),
+                    Err(e) => panic!("Failed to read spec file: {}", e),
+                }
+            } else if let Some(path) = cli.snapshot.as_deref() {
+                RoleGraph::from_context(&mut read_snapshot(path))
+            } else {
+                RoleGraph::from_context(&mut connect())
+            };
+            match format.as_str() {
+                "dot" => println!("{}", graph.to_dot(*schemas)),
+                "mermaid" => println!("{}", graph.to_mermaid(*schemas)),
+                _ => panic!("Unknown graph format: {}", format),
+            }
+        }
         None => println!("No subcommand was used"),
     }
 }
@@ -0,0 +1,180 @@
+//! Per-environment specs.
+//!
+//! Strings in a spec can use environment variables, written `${NAME}`, or
+//! `${NAME:-default}` to fall back to a default when `NAME` is not set. Write
+//! `$${` for a literal `${`.
+//!
+//! A spec file can also have overlays for each environment, e.g. `spec.yml`
+//! with `spec.prod.yml` and `spec.dev.yml` next to it. Loading with
+//! `--env prod` merges `spec.prod.yml` over `spec.yml`, following the same
+//! rules as templates: values in the overlay win, lists are combined with
+//! the overlay's entries replacing those for the same role or object, and
+//! maps are merged key by key. Overlays are never loaded on their own, even
+//! when their directory is.
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context as _, Result};
+use serde_yaml::Value;
+
+use crate::template::merge;
+
+/// Reads the YAML at `path`, merges the overlay for `env` over it and
+/// substitutes environment variables.
+pub(crate) fn read_yaml(path: &Path, env: Option<&str>) -> Result<Value> {
+    let mut value = parse_file(path)?;
+    if let Some(overlay) = env.and_then(|env| overlay_path(path, env)) {
+        value = merge(value, parse_file(&overlay)?);
+    }
+    substitute_value(&mut value)
+        .with_context(|| format!("Failed to parse spec file: {}", path.display()))?;
+    Ok(value)
+}
+
+fn parse_file(path: &Path) -> Result<Value> {
+    let source = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to open spec file: {}", path.display()))?;
+    serde_yaml::from_str(&source)
+        .with_context(|| format!("Failed to parse spec file: {}", path.display()))
+}
+
+/// The overlay of `file` for `env`, e.g. `spec.prod.yml` for `spec.yml`,
+/// if it exists.
+pub(crate) fn overlay_path(file: &Path, env: &str) -> Option<PathBuf> {
+    let stem = file.file_stem()?.to_string_lossy();
+    let extension = file.extension()?.to_string_lossy();
+    let overlay = file.with_file_name(format!("{}.{}.{}", stem, env, extension));
+    overlay.is_file().then_some(overlay)
+}
+
+/// Whether `file` is an overlay, i.e. named `name.env.yml` next to a
+/// `name.yml` or `name.yaml`.
+pub(crate) fn is_overlay(file: &Path) -> bool {
+    let Some(stem) = file.file_stem().map(|s| s.to_string_lossy()) else {
+        return false;
+    };
+    let Some((base, _)) = stem.rsplit_once('.') else {
+        return false;
+    };
+    ["yml", "yaml"].iter().any(|extension| {
+        file.with_file_name(format!("{}.{}", base, extension))
+            .is_file()
+    })
+}
+
+/// Substitutes environment variables in every string of `value`, keys
+/// included, reporting all unset variables at once.
+pub(crate) fn substitute_value(value: &mut Value) -> Result<()> {
+    let mut missing = vec![];
+    substitute_all(value, &mut missing);
+    if !missing.is_empty() {
+        missing.sort();
+        missing.dedup();
+        anyhow::bail!("Environment variables are not set: {}", missing.join(", "));
+    }
+    Ok(())
+}
+
+fn substitute_all(value: &mut Value, missing: &mut Vec<String>) {
+    match value {
+        Value::String(s) => match substitute(s) {
+            Ok(substituted) => *s = substituted,
+            Err(names) => missing.extend(names),
+        },
+        Value::Sequence(items) => {
+            for item in items {
+                substitute_all(item, missing);
+            }
+        }
+        Value::Mapping(mapping) => {
+            let entries = std::mem::take(mapping);
+            for (mut key, mut value) in entries {
+                substitute_all(&mut key, missing);
+                substitute_all(&mut value, missing);
+                mapping.insert(key, value);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Substitutes environment variables in `text`, or returns the names of
+/// those that are not set.
+pub(crate) fn substitute(text: &str) -> Result<String, Vec<String>> {
+    substitute_with(text, |name| std::env::var(name).ok())
+}
+
+fn substitute_with(
+    text: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<String, Vec<String>> {
+    let mut out = String::new();
+    let mut missing = vec![];
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("$${") {
+            out.push_str("${");
+            rest = after;
+            continue;
+        }
+        let Some(end) = rest.strip_prefix("${").and_then(|r| r.find('}')) else {
+            out.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+        let expression = &rest[2..2 + end];
+        let (name, default) = match expression.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (expression, None),
+        };
+        match lookup(name).or(default.map(str::to_string)) {
+            Some(value) => out.push_str(&value),
+            None => missing.push(name.to_string()),
+        }
+        rest = &rest[2 + end + 1..];
+    }
+    out.push_str(rest);
+    if !missing.is_empty() {
+        return Err(missing);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_substitute() {
+        let lookup = |name: &str| (name == "ENV").then(|| "prod".to_string());
+
+        assert_eq!(
+            substitute_with("loader_${ENV}", lookup),
+            Ok("loader_prod".to_string())
+        );
+        assert_eq!(
+            substitute_with("${MISSING:-dev}_${ENV}", lookup),
+            Ok("dev_prod".to_string())
+        );
+        assert_eq!(
+            substitute_with("$${ENV} costs $5", lookup),
+            Ok("${ENV} costs $5".to_string())
+        );
+        assert_eq!(
+            substitute_with("${A}_${ENV}_${B}", lookup),
+            Err(vec!["A".to_string(), "B".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_overlays() {
+        let spec = Path::new("resources/overlay/spec.yml");
+        let overlay = Path::new("resources/overlay/spec.dev.yml");
+
+        assert_eq!(overlay_path(spec, "dev"), Some(overlay.to_path_buf()));
+        assert_eq!(overlay_path(spec, "staging"), None);
+        assert!(is_overlay(overlay));
+        assert!(!is_overlay(spec));
+    }
+}
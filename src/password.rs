@@ -0,0 +1,208 @@
+//! Passwords for login roles.
+//!
+//! A spec never holds a password, only where to read it from, either an
+//! environment variable or a file such as a mounted Kubernetes secret:
+//!
+//! ```yaml
+//! roles:
+//!   loader:
+//!     password:
+//!       env: LOADER_PASSWORD
+//!     valid_until: 2025-06-30
+//!   reporting:
+//!     password:
+//!       file: /var/run/secrets/reporting/password
+//! ```
+//!
+//! Passwords are hashed with SCRAM-SHA-256 before they leave permirust, so
+//! plans, logs and the server only ever see the verifier. The verifier a
+//! role has is checked against its password, and left alone when it
+//! matches, so plans stay empty once applied. Roles without `password`
+//! keep whatever password they have, and roles without `valid_until` keep
+//! their expiry; `valid_until: infinity` clears it.
+use std::fmt;
+
+use anyhow::{bail, Context as _, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use pbkdf2::hmac::{Hmac, Mac};
+use pbkdf2::pbkdf2_hmac_array;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::spec::Role;
+
+/// Where the password of a role is read from.
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum PasswordSource {
+    Env {
+        /// The name of an environment variable holding the password
+        env: String,
+    },
+    File {
+        /// The path of a file holding the password
+        file: String,
+    },
+}
+
+impl PasswordSource {
+    /// Reads the password. Trailing line breaks, which files usually end
+    /// with, are not part of it.
+    pub fn read(&self) -> Result<String> {
+        let password = match self {
+            PasswordSource::Env { env: name } => std::env::var(name)
+                .with_context(|| format!("Environment variable {} is not set", name))?,
+            PasswordSource::File { file: path } => std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read password file: {}", path))?,
+        };
+        let password = password.trim_end_matches(['\r', '\n']);
+        if password.is_empty() {
+            bail!("The password in {} is empty", self);
+        }
+        Ok(password.to_string())
+    }
+}
+
+impl fmt::Display for PasswordSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PasswordSource::Env { env: name } => write!(f, "environment variable {}", name),
+            PasswordSource::File { file: path } => write!(f, "file {}", path),
+        }
+    }
+}
+
+/// Plans the ALTER ROLE PASSWORD statement for a role with a `password`,
+/// unless `current`, the verifier the role has, is already for it.
+pub fn plan_password(name: &str, current: Option<&str>, spec_role: &Role) -> Result<Vec<String>> {
+    let Some(source) = &spec_role.password else {
+        return Ok(vec![]);
+    };
+    let password = source
+        .read()
+        .with_context(|| format!("Failed to read the password of role {}", name))?;
+    if current.is_some_and(|verifier| verify(&password, verifier)) {
+        return Ok(vec![]);
+    }
+    Ok(vec![format!(
+        "ALTER ROLE {} PASSWORD '{}'",
+        name,
+        scram_sha_256(&password)
+    )])
+}
+
+/// `statement` with the verifier of a PASSWORD clause masked, for printing
+/// and logging plans. A verifier is enough to guess the password offline.
+pub fn redact(statement: &str) -> String {
+    const CLAUSE: &str = " PASSWORD '";
+    let Some(start) = statement.find(CLAUSE).map(|i| i + CLAUSE.len()) else {
+        return statement.to_string();
+    };
+    match statement[start..].find('\'') {
+        Some(end) => format!("{}***{}", &statement[..start], &statement[start + end..]),
+        None => format!("{}***'", &statement[..start]),
+    }
+}
+
+/// A SCRAM-SHA-256 verifier for `password` with a random salt, in the
+/// format Postgres keeps in `pg_authid.rolpassword`.
+pub fn scram_sha_256(password: &str) -> String {
+    postgres_protocol::password::scram_sha_256(password.as_bytes())
+}
+
+/// Whether `verifier`, as found in `pg_authid.rolpassword`, is for
+/// `password`. Verifiers in other formats, such as MD5, never match.
+pub fn verify(password: &str, verifier: &str) -> bool {
+    let parsed = verifier.strip_prefix("SCRAM-SHA-256$").and_then(|rest| {
+        let (parameters, keys) = rest.split_once('$')?;
+        let (iterations, salt) = parameters.split_once(':')?;
+        let (stored_key, server_key) = keys.split_once(':')?;
+        Some((
+            iterations.parse::<u32>().ok()?,
+            STANDARD.decode(salt).ok()?,
+            stored_key,
+            server_key,
+        ))
+    });
+    let Some((iterations, salt, stored_key, server_key)) = parsed else {
+        return false;
+    };
+    let (expected_stored, expected_server) = keys(password, &salt, iterations);
+    STANDARD.encode(expected_stored) == stored_key && STANDARD.encode(expected_server) == server_key
+}
+
+/// The stored and server keys of RFC 5802. Like libpq, passwords are
+/// normalized with SASLprep when they can be.
+fn keys(password: &str, salt: &[u8], iterations: u32) -> ([u8; 32], [u8; 32]) {
+    let prepared = stringprep::saslprep(password).unwrap_or(password.into());
+    let salted = pbkdf2_hmac_array::<Sha256, 32>(prepared.as_bytes(), salt, iterations);
+    let client_key = hmac(&salted, b"Client Key");
+    let stored_key: [u8; 32] = Sha256::digest(client_key).into();
+    (stored_key, hmac(&salted, b"Server Key"))
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // From `CREATE ROLE jdoe PASSWORD 'correct horse'` on Postgres 15
+    const VERIFIER: &str = "SCRAM-SHA-256$4096:jdDkjWSt87Vdv5QVoggrew==$rija1RvHAtSiJoXIjjqsDd1BEf4X/O59DTsbCunLYG8=:58GBXsrPvmdVEC916aUfr2qrbpZeoFHSMqgekkwSMO4=";
+
+    #[test]
+    fn test_verify() {
+        assert!(verify("correct horse", VERIFIER));
+        assert!(!verify("battery staple", VERIFIER));
+        assert!(!verify(
+            "correct horse",
+            "md5a3556571e93b0d20722ba62be61e8c2d"
+        ));
+
+        let verifier = scram_sha_256("battery staple");
+        assert!(verify("battery staple", &verifier));
+        assert_ne!(verifier, scram_sha_256("battery staple"));
+    }
+
+    #[test]
+    fn test_plan_password() {
+        let dir =
+            std::env::temp_dir().join(format!("permirust-plan-password-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("jdoe");
+        std::fs::write(&file, "correct horse\n").unwrap();
+        let role = Role {
+            password: Some(PasswordSource::File {
+                file: file.display().to_string(),
+            }),
+            ..Default::default()
+        };
+
+        assert!(plan_password("jdoe", Some(VERIFIER), &role)
+            .unwrap()
+            .is_empty());
+        let sql = plan_password("jdoe", None, &role).unwrap();
+        assert_eq!(sql.len(), 1);
+        assert!(sql[0].starts_with("ALTER ROLE jdoe PASSWORD 'SCRAM-SHA-256$4096:"));
+        assert!(!sql[0].contains("correct horse"));
+        assert_eq!(redact(&sql[0]), "ALTER ROLE jdoe PASSWORD '***'");
+        assert_eq!(redact("ALTER ROLE jdoe LOGIN"), "ALTER ROLE jdoe LOGIN");
+
+        let missing = Role {
+            password: Some(PasswordSource::Env {
+                env: "PERMIRUST_TEST_UNSET".to_string(),
+            }),
+            ..Default::default()
+        };
+        let error = plan_password("jdoe", None, &missing).unwrap_err();
+        assert!(format!("{:#}", error).contains("PERMIRUST_TEST_UNSET is not set"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
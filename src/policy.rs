@@ -0,0 +1,705 @@
+//! Row-level security.
+//!
+//! The `policies` section of a spec lists tables by `schema.table`, with
+//! whether row-level security is enabled and forced on them, and their
+//! policies:
+//!
+//! ```yaml
+//! policies:
+//!   finance.invoices:
+//!     enabled: yes
+//!     forced: yes
+//!     policies:
+//!       tenant_isolation:
+//!         command: all
+//!         roles:
+//!           - analyst
+//!         using: tenant_id = current_setting('app.tenant')::integer
+//!       auditors_see_everything:
+//!         command: select
+//!         permissive: yes
+//!         roles:
+//!           - auditor
+//!         using: "true"
+//! ```
+//!
+//! Only the tables listed are managed: their policies are created, altered
+//! and dropped to match the spec, while other tables are left alone, so a
+//! spec without `policies` never touches row-level security.
+//!
+//! Postgres rewrites expressions when it stores them, adding casts and
+//! parentheses, so `tenant_id = current_setting('app.tenant')::integer`
+//! reads back as `(tenant_id = (current_setting('app.tenant'::text))::integer)`.
+//! Expressions are compared ignoring whitespace, the case of unquoted words,
+//! casts to text and parentheses that do not change their meaning, so both
+//! match. Other rewrites, such as `LIKE` read back as `~~`, still show up as
+//! changes until the spec is written the way `generate` prints it.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::context::{DatabaseObject, ObjectKind, PUBLIC};
+use crate::scope::Scope;
+
+/// Row-level security by table, keyed by `schema.table`.
+pub type TablePolicies = BTreeMap<String, RowSecurity>;
+
+#[derive(Debug, Default, Clone, Deserialize, JsonSchema, Serialize, PartialEq, Eq)]
+#[schemars(deny_unknown_fields)]
+pub struct RowSecurity {
+    /// Whether row-level security is enabled on the table
+    #[serde(deserialize_with = "crate::spec::deserialize_bool")]
+    #[schemars(schema_with = "crate::schema::yes_no")]
+    #[serde(default = "crate::spec::yes")]
+    pub enabled: bool,
+    /// Whether the policies also apply to the owner of the table
+    #[serde(deserialize_with = "crate::spec::deserialize_bool")]
+    #[schemars(schema_with = "crate::schema::yes_no")]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    #[serde(default)]
+    pub forced: bool,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    #[serde(default)]
+    pub policies: BTreeMap<String, Policy>,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize, PartialEq, Eq)]
+#[schemars(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(skip_serializing_if = "PolicyCommand::is_all")]
+    #[serde(default)]
+    pub command: PolicyCommand,
+    /// Permissive policies are combined with OR, restrictive ones must all
+    /// pass as well
+    #[serde(deserialize_with = "crate::spec::deserialize_bool")]
+    #[schemars(schema_with = "crate::schema::yes_no")]
+    #[serde(skip_serializing_if = "is_permissive")]
+    #[serde(default = "crate::spec::yes")]
+    pub permissive: bool,
+    /// The roles the policy applies to, PUBLIC when empty
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// Which existing rows are visible
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub using: Option<String>,
+    /// Which new rows may be written
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub with_check: Option<String>,
+}
+
+fn is_permissive(permissive: &bool) -> bool {
+    *permissive
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            command: PolicyCommand::All,
+            permissive: true,
+            roles: vec![],
+            using: None,
+            with_check: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize, JsonSchema, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyCommand {
+    #[default]
+    All,
+    Select,
+    Insert,
+    Update,
+    Delete,
+}
+
+impl PolicyCommand {
+    fn is_all(&self) -> bool {
+        *self == PolicyCommand::All
+    }
+
+    /// Parses the command as written in SQL, in any case.
+    pub fn parse(command: &str) -> Option<Self> {
+        match command.to_ascii_uppercase().as_str() {
+            "ALL" => Some(PolicyCommand::All),
+            "SELECT" => Some(PolicyCommand::Select),
+            "INSERT" => Some(PolicyCommand::Insert),
+            "UPDATE" => Some(PolicyCommand::Update),
+            "DELETE" => Some(PolicyCommand::Delete),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PolicyCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let command = match self {
+            PolicyCommand::All => "ALL",
+            PolicyCommand::Select => "SELECT",
+            PolicyCommand::Insert => "INSERT",
+            PolicyCommand::Update => "UPDATE",
+            PolicyCommand::Delete => "DELETE",
+        };
+        f.write_str(command)
+    }
+}
+
+/// The table a `schema.table` key of the `policies` section stands for.
+pub fn table_object(table: &str) -> DatabaseObject {
+    let (schema, name) = table.split_once('.').unwrap_or(("public", table));
+    DatabaseObject::new(
+        ObjectKind::Table,
+        schema.to_string(),
+        Some(name.to_string()),
+    )
+}
+
+/// Plans the statements that bring row-level security on the tables in
+/// `desired` in line with the spec. Tables that are not listed, or are
+/// outside of `scope`, are left alone.
+///
+/// Policies are changed before row-level security is enabled, so the table
+/// is never left without the policies that should let rows through.
+pub fn plan_policies(
+    current: &TablePolicies,
+    desired: &TablePolicies,
+    scope: &Scope,
+) -> Vec<String> {
+    let mut sql = vec![];
+    let none = RowSecurity::default();
+    for (table, spec) in desired {
+        if !scope.includes_object(&table_object(table)) {
+            continue;
+        }
+        let current = current.get(table).unwrap_or(&none);
+
+        for name in current.policies.keys() {
+            if !spec.policies.contains_key(name) {
+                sql.push(format!("DROP POLICY {} ON {}", quote(name), table));
+            }
+        }
+        for (name, policy) in &spec.policies {
+            match current.policies.get(name) {
+                None => sql.push(create_policy(name, table, policy)),
+                Some(existing) => sql.extend(alter_policy(name, table, existing, policy)),
+            }
+        }
+
+        if current.enabled != spec.enabled {
+            let action = if spec.enabled { "ENABLE" } else { "DISABLE" };
+            sql.push(format!(
+                "ALTER TABLE {} {} ROW LEVEL SECURITY",
+                table, action
+            ));
+        }
+        if current.forced != spec.forced {
+            let action = if spec.forced { "FORCE" } else { "NO FORCE" };
+            sql.push(format!(
+                "ALTER TABLE {} {} ROW LEVEL SECURITY",
+                table, action
+            ));
+        }
+    }
+    sql
+}
+
+fn same_expression(current: &Option<String>, desired: &Option<String>) -> bool {
+    current.as_deref().map(canonical) == desired.as_deref().map(canonical)
+}
+
+fn create_policy(name: &str, table: &str, policy: &Policy) -> String {
+    let mut statement = format!("CREATE POLICY {} ON {}", quote(name), table);
+    if !policy.permissive {
+        statement.push_str(" AS RESTRICTIVE");
+    }
+    statement.push_str(&format!(" FOR {} TO {}", policy.command, roles(policy)));
+    statement.push_str(&expressions(
+        policy.using.as_deref(),
+        policy.with_check.as_deref(),
+    ));
+    statement
+}
+
+/// ALTER POLICY can change the roles and expressions of a policy, but not
+/// its command or kind, nor remove an expression, so those changes drop the
+/// policy and create it again.
+fn alter_policy(name: &str, table: &str, current: &Policy, desired: &Policy) -> Vec<String> {
+    let removed = (current.using.is_some() && desired.using.is_none())
+        || (current.with_check.is_some() && desired.with_check.is_none());
+    if current.command != desired.command || current.permissive != desired.permissive || removed {
+        return vec![
+            format!("DROP POLICY {} ON {}", quote(name), table),
+            create_policy(name, table, desired),
+        ];
+    }
+
+    let mut statement = format!("ALTER POLICY {} ON {}", quote(name), table);
+    let mut alter = false;
+    if role_set(current) != role_set(desired) {
+        statement.push_str(&format!(" TO {}", roles(desired)));
+        alter = true;
+    }
+    let using = desired
+        .using
+        .as_deref()
+        .filter(|_| !same_expression(&current.using, &desired.using));
+    let with_check = desired
+        .with_check
+        .as_deref()
+        .filter(|_| !same_expression(&current.with_check, &desired.with_check));
+    if using.is_some() || with_check.is_some() {
+        statement.push_str(&expressions(using, with_check));
+        alter = true;
+    }
+    if alter {
+        vec![statement]
+    } else {
+        vec![]
+    }
+}
+
+/// Policy names are free text, such as `Enable read for tenants`, so they
+/// are always quoted.
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn roles(policy: &Policy) -> String {
+    match policy.roles.is_empty() {
+        true => PUBLIC.to_string(),
+        false => policy.roles.join(", "),
+    }
+}
+
+fn role_set(policy: &Policy) -> BTreeSet<&str> {
+    match policy.roles.is_empty() {
+        true => BTreeSet::from([PUBLIC]),
+        false => policy.roles.iter().map(String::as_str).collect(),
+    }
+}
+
+fn expressions(using: Option<&str>, with_check: Option<&str>) -> String {
+    let mut clauses = String::new();
+    if let Some(using) = using {
+        clauses.push_str(&format!(" USING ({})", normalize(using)));
+    }
+    if let Some(with_check) = with_check {
+        clauses.push_str(&format!(" WITH CHECK ({})", normalize(with_check)));
+    }
+    clauses
+}
+
+/// `expression` with its whitespace collapsed and without enclosing
+/// parentheses, as Postgres adds them when storing it.
+fn normalize(expression: &str) -> String {
+    let mut expression = expression.split_whitespace().collect::<Vec<_>>().join(" ");
+    while let Some(inner) = enclosed(&expression) {
+        expression = inner.trim().to_string();
+    }
+    expression
+}
+
+/// The inside of `expression` if one pair of parentheses encloses all of it.
+fn enclosed(expression: &str) -> Option<&str> {
+    let inner = expression.strip_prefix('(')?.strip_suffix(')')?;
+    let mut depth = 0;
+    for c in inner.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return None,
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    Some(inner)
+}
+
+/// A token of a policy expression, with parenthesised parts nested.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    /// An unquoted word or number, in lowercase
+    Word(String),
+    /// A string literal or quoted identifier, with its quotes
+    Quoted(String),
+    Symbol(String),
+    Group(Vec<Token>),
+}
+
+/// `expression` in a form that does not depend on how it was written or on
+/// how Postgres prints it back, for comparisons only. Unquoted words are
+/// lowercased, casts to text are dropped, and parentheses are dropped where
+/// they cannot change the meaning: around a single term, and around
+/// operands of AND and OR that bind tighter anyway.
+fn canonical(expression: &str) -> String {
+    let mut chars = expression.chars().peekable();
+    match tokenize(&mut chars, false) {
+        Some(tokens) => render(&simplify(tokens)),
+        None => normalize(expression),
+    }
+}
+
+/// Reads tokens up to the closing parenthesis when `nested`, or the end.
+/// Unbalanced parentheses or quotes are `None`.
+fn tokenize(chars: &mut Peekable<Chars>, nested: bool) -> Option<Vec<Token>> {
+    const OPERATORS: &str = "+-*/<>=~!@#%^&|`?";
+    let mut tokens = vec![];
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '(' => tokens.push(Token::Group(tokenize(chars, true)?)),
+            ')' => return nested.then_some(tokens),
+            '\'' | '"' => {
+                let mut quoted = c.to_string();
+                loop {
+                    let next = chars.next()?;
+                    quoted.push(next);
+                    // A doubled quote stands for itself
+                    if next == c && chars.next_if_eq(&c).is_none() {
+                        break;
+                    } else if next == c {
+                        quoted.push(c);
+                    }
+                }
+                tokens.push(Token::Quoted(quoted));
+            }
+            c if c.is_alphanumeric() || c == '_' || c == '$' => {
+                let mut word = c.to_lowercase().to_string();
+                while let Some(c) = chars.next_if(|c| c.is_alphanumeric() || *c == '_' || *c == '$')
+                {
+                    word.extend(c.to_lowercase());
+                }
+                tokens.push(Token::Word(word));
+            }
+            ':' if chars.next_if_eq(&':').is_some() => tokens.push(Token::Symbol("::".into())),
+            c if OPERATORS.contains(c) => {
+                let mut operator = c.to_string();
+                while let Some(c) = chars.next_if(|c| OPERATORS.contains(*c)) {
+                    operator.push(c);
+                }
+                tokens.push(Token::Symbol(operator));
+            }
+            c => tokens.push(Token::Symbol(c.to_string())),
+        }
+    }
+    (!nested).then_some(tokens)
+}
+
+fn simplify(tokens: Vec<Token>) -> Vec<Token> {
+    let tokens: Vec<Token> = tokens
+        .into_iter()
+        .map(|token| match token {
+            Token::Group(inner) => Token::Group(simplify(inner)),
+            token => token,
+        })
+        .collect();
+
+    let mut unwrapped = vec![];
+    for (i, token) in tokens.iter().enumerate() {
+        let previous = i.checked_sub(1).map(|i| &tokens[i]);
+        match token {
+            Token::Group(inner) if redundant(inner, previous, tokens.get(i + 1)) => {
+                unwrapped.extend(inner.iter().cloned())
+            }
+            token => unwrapped.push(token.clone()),
+        }
+    }
+
+    // Postgres casts string literals, and varchar columns compared to them,
+    // to text
+    let word = |token: Option<&Token>, expected: &str| token == Some(&Token::Word(expected.into()));
+    let mut simplified = vec![];
+    let mut i = 0;
+    while i < unwrapped.len() {
+        if unwrapped[i] == Token::Symbol("::".into()) {
+            if word(unwrapped.get(i + 1), "text") {
+                i += 2;
+                continue;
+            }
+            if word(unwrapped.get(i + 1), "character") && word(unwrapped.get(i + 2), "varying") {
+                i += 3;
+                continue;
+            }
+        }
+        simplified.push(unwrapped[i].clone());
+        i += 1;
+    }
+    simplified
+}
+
+/// Whether the parentheses around `inner`, between `previous` and `next`,
+/// can go. Parentheses after a word are a function call, or belong to IN,
+/// NOT, ANY and the like, and stay, except after AND and OR.
+fn redundant(inner: &[Token], previous: Option<&Token>, next: Option<&Token>) -> bool {
+    let grouping = match previous {
+        None => true,
+        Some(Token::Symbol(symbol)) => symbol != "." && symbol != "::",
+        Some(Token::Word(word)) => word == "and" || word == "or",
+        _ => false,
+    };
+    if !grouping || inner.is_empty() {
+        return false;
+    }
+    if is_single_term(inner) {
+        return true;
+    }
+    let (Some(before), Some(after)) = (connective(previous), connective(next)) else {
+        return false;
+    };
+    // Everything but OR binds tighter than AND
+    !inner.contains(&Token::Word("or".into())) || (before != "and" && after != "and")
+}
+
+/// `""` at either end of an expression, `and` or `or` next to one of them,
+/// and `None` next to anything else.
+fn connective(token: Option<&Token>) -> Option<&str> {
+    match token {
+        None => Some(""),
+        Some(Token::Word(word)) if word == "and" || word == "or" => Some(word),
+        _ => None,
+    }
+}
+
+/// A name, literal or function call, possibly qualified, cast or
+/// subscripted, with no operator or keyword in between.
+fn is_single_term(tokens: &[Token]) -> bool {
+    tokens.iter().enumerate().all(|(i, token)| match token {
+        Token::Symbol(symbol) => matches!(symbol.as_str(), "." | "::" | "[" | "]"),
+        Token::Word(_) | Token::Quoted(_) => {
+            i == 0
+                || matches!(&tokens[i - 1], Token::Symbol(s) if s == "." || s == "::" || s == "[")
+        }
+        Token::Group(_) => true,
+    })
+}
+
+fn render(tokens: &[Token]) -> String {
+    let mut out = String::new();
+    let mut previous: Option<&Token> = None;
+    for token in tokens {
+        let glued_to_previous =
+            matches!(previous, Some(Token::Symbol(s)) if matches!(s.as_str(), "." | "::" | "["));
+        let text = match token {
+            Token::Word(text) | Token::Quoted(text) | Token::Symbol(text) => text.clone(),
+            Token::Group(inner) => format!("({})", render(inner)),
+        };
+        let glued = match token {
+            Token::Symbol(s) => matches!(s.as_str(), "." | "::" | "," | "[" | "]"),
+            // Function calls
+            Token::Group(_) => match previous {
+                Some(Token::Word(word)) => !matches!(word.as_str(), "and" | "or" | "not"),
+                Some(Token::Quoted(_)) => true,
+                _ => false,
+            },
+            _ => false,
+        };
+        if previous.is_some() && !glued && !glued_to_previous {
+            out.push(' ');
+        }
+        out.push_str(&text);
+        previous = Some(token);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policies(yaml: &str) -> TablePolicies {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_plan_policies() {
+        let current = policies(
+            "
+            finance.invoices:
+              enabled: no
+              policies:
+                stale:
+                  using: 'true'
+                by_region:
+                  command: select
+                  roles: [analyst]
+                  using: (region = current_setting('app.region'::text))
+                by_tenant:
+                  using: tenant_id = 1
+            ",
+        );
+        let desired = policies(
+            "
+            finance.invoices:
+              forced: yes
+              policies:
+                by_region:
+                  command: select
+                  roles: [analyst]
+                  using: region = current_setting('app.region'::text)
+                by_tenant:
+                  roles: [analyst, developer]
+                  using: tenant_id = 2
+                writers:
+                  command: insert
+                  permissive: no
+                  with_check: tenant_id = 2
+            marketing.leads:
+              enabled: no
+            ",
+        );
+
+        assert_eq!(
+            plan_policies(&current, &desired, &Scope::default()),
+            vec![
+                "DROP POLICY \"stale\" ON finance.invoices",
+                "ALTER POLICY \"by_tenant\" ON finance.invoices TO analyst, developer USING (tenant_id = 2)",
+                "CREATE POLICY \"writers\" ON finance.invoices AS RESTRICTIVE FOR INSERT TO PUBLIC WITH CHECK (tenant_id = 2)",
+                "ALTER TABLE finance.invoices ENABLE ROW LEVEL SECURITY",
+                "ALTER TABLE finance.invoices FORCE ROW LEVEL SECURITY",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_policies_recreates_what_alter_cannot_change() {
+        let current = policies(
+            "
+            finance.invoices:
+              policies:
+                readers:
+                  command: select
+                  using: 'true'
+                  with_check: 'true'
+            ",
+        );
+        let desired = policies(
+            "
+            finance.invoices:
+              policies:
+                readers:
+                  command: select
+                  using: 'true'
+            ",
+        );
+
+        assert_eq!(
+            plan_policies(&current, &desired, &Scope::default()),
+            vec![
+                "DROP POLICY \"readers\" ON finance.invoices",
+                "CREATE POLICY \"readers\" ON finance.invoices FOR SELECT TO PUBLIC USING (true)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_policy_names_are_quoted() {
+        let current = policies(
+            r#"
+            finance.invoices:
+              policies:
+                Old "tenant" policy:
+                  using: 'true'
+            "#,
+        );
+        let desired = policies(
+            "
+            finance.invoices:
+              policies:
+                Enable read for tenants:
+                  command: select
+                  using: 'true'
+            ",
+        );
+
+        assert_eq!(
+            plan_policies(&current, &desired, &Scope::default()),
+            vec![
+                r#"DROP POLICY "Old ""tenant"" policy" ON finance.invoices"#,
+                r#"CREATE POLICY "Enable read for tenants" ON finance.invoices FOR SELECT TO PUBLIC USING (true)"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_policies_reads_back_pg_get_expr() {
+        // As pg_get_expr prints them
+        let current = policies(
+            "
+            finance.invoices:
+              policies:
+                by_tenant:
+                  using: (tenant_id = (current_setting('app.tenant'::text))::integer)
+                by_region:
+                  using: (((region)::text = 'emea'::text) OR (region IS NULL))
+                by_owner:
+                  using: ((owner = CURRENT_USER) AND ((status = 'open'::text) OR (status = 'draft'::text)))
+            ",
+        );
+        let desired = policies(
+            "
+            finance.invoices:
+              policies:
+                by_tenant:
+                  using: tenant_id = current_setting('app.tenant')::integer
+                by_region:
+                  using: region = 'emea' or region is null
+                by_owner:
+                  using: owner = current_user AND (status = 'open' OR status = 'draft')
+            ",
+        );
+        assert_eq!(
+            plan_policies(&current, &desired, &Scope::default()),
+            Vec::<String>::new()
+        );
+
+        // Parentheses that change the meaning are kept
+        let desired = policies(
+            "
+            finance.invoices:
+              policies:
+                by_tenant:
+                  using: tenant_id = current_setting('app.tenant')::integer
+                by_region:
+                  using: region = 'emea' or region is null
+                by_owner:
+                  using: owner = current_user AND status = 'open' OR status = 'draft'
+            ",
+        );
+        assert_eq!(
+            plan_policies(&current, &desired, &Scope::default()),
+            vec!["ALTER POLICY \"by_owner\" ON finance.invoices USING (owner = current_user AND status = 'open' OR status = 'draft')"]
+        );
+    }
+
+    #[test]
+    fn test_canonical() {
+        assert_eq!(canonical("((a = 1))"), "a = 1");
+        assert_eq!(canonical("(a = 1) AND (b = 2)"), "a = 1 and b = 2");
+        assert_eq!(canonical("a AND (b OR c)"), "a and (b or c)");
+        assert_eq!(canonical("f((x))::integer"), "f(x)::integer");
+        assert_eq!(canonical("(x + 1) * 2"), "(x + 1) * 2");
+        assert_eq!(canonical("NOT (a OR b)"), "not (a or b)");
+        assert_eq!(
+            canonical("\"Region\" = 'EMEA'::text"),
+            "\"Region\" = 'EMEA'"
+        );
+        assert_eq!(
+            canonical("a = ANY (ARRAY['x'::text, 'y'::text])"),
+            "a = any(array['x', 'y'])"
+        );
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("((a = 1))"), "a = 1");
+        assert_eq!(normalize("(a = 1) AND (b = 2)"), "(a = 1) AND (b = 2)");
+        assert_eq!(normalize(" a  =\n 1 "), "a = 1");
+    }
+}
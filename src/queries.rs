@@ -2,7 +2,8 @@
 pub const Q_ALL_MEMBERSHIPS: &str = "
 SELECT
   auth_member.rolname AS member,
-  auth_group.rolname AS group
+  auth_group.rolname AS group,
+  link_table.admin_option
 FROM pg_auth_members link_table
 JOIN pg_authid auth_member ON link_table.member = auth_member.oid
 JOIN pg_authid auth_group ON link_table.roleid = auth_group.oid
@@ -23,15 +24,24 @@ WITH
           map.objkind,
           (aclexplode(c.relacl)).grantee AS grantee_oid,
           t_owner.rolname AS owner,
-          (aclexplode(c.relacl)).privilege_type
+          (aclexplode(c.relacl)).privilege_type,
+          (aclexplode(c.relacl)).is_grantable
       FROM
           pg_class c
           JOIN pg_authid t_owner ON c.relowner = t_owner.OID
           JOIN pg_namespace nsp ON c.relnamespace = nsp.oid
           JOIN relkind_mapping map ON c.relkind = map.objkey
       WHERE
-          nsp.nspname NOT LIKE 'pg\\_t%'
+          nsp.nspname NOT LIKE 'pg\\_%'
+          AND nsp.nspname != 'information_schema'
           AND c.relacl IS NOT NULL
+          AND NOT EXISTS (
+              SELECT 1
+              FROM pg_depend ext
+              WHERE ext.classid = 'pg_class'::REGCLASS
+                  AND ext.objid = c.oid
+                  AND ext.deptype = 'e'
+          )
   ), schemas AS (
       SELECT
            nsp.nspname AS schema,
@@ -39,25 +49,67 @@ WITH
            'schemas'::TEXT AS objkind,
            (aclexplode(nsp.nspacl)).grantee AS grantee_oid,
            t_owner.rolname AS owner,
-           (aclexplode(nsp.nspacl)).privilege_type
+           (aclexplode(nsp.nspacl)).privilege_type,
+           (aclexplode(nsp.nspacl)).is_grantable
       FROM pg_namespace nsp
       JOIN pg_authid t_owner
           ON nsp.nspowner = t_owner.OID
+      WHERE
+          nsp.nspname NOT LIKE 'pg\\_%'
+          AND nsp.nspname != 'information_schema'
+          AND NOT EXISTS (
+              SELECT 1
+              FROM pg_depend ext
+              WHERE ext.classid = 'pg_namespace'::REGCLASS
+                  AND ext.objid = nsp.oid
+                  AND ext.deptype = 'e'
+          )
+  ), functions AS (
+      -- A NULL proacl means the default ACL applies, which grants EXECUTE
+      -- to PUBLIC, so we expand it rather than skipping the function
+      SELECT
+          nsp.nspname AS schema,
+          p.proname || '(' || oidvectortypes(p.proargtypes) || ')' AS unqualified_name,
+          'functions'::TEXT AS objkind,
+          (aclexplode(COALESCE(p.proacl, acldefault('f', p.proowner)))).grantee AS grantee_oid,
+          t_owner.rolname AS owner,
+          (aclexplode(COALESCE(p.proacl, acldefault('f', p.proowner)))).privilege_type,
+          (aclexplode(COALESCE(p.proacl, acldefault('f', p.proowner)))).is_grantable
+      FROM pg_proc p
+      JOIN pg_authid t_owner
+          ON p.proowner = t_owner.OID
+      JOIN pg_namespace nsp
+          ON p.pronamespace = nsp.oid
+      WHERE
+          nsp.nspname NOT LIKE 'pg\\_%'
+          AND nsp.nspname != 'information_schema'
+          AND p.prokind = 'f'
+          AND NOT EXISTS (
+              SELECT 1
+              FROM pg_depend ext
+              WHERE ext.classid = 'pg_proc'::REGCLASS
+                  AND ext.objid = p.oid
+                  AND ext.deptype = 'e'
+          )
   ), combined AS (
       SELECT * FROM tables_and_sequences
       UNION ALL
       SELECT * FROM schemas
+      UNION ALL
+      SELECT * FROM functions
   )
   SELECT
-      t_grantee.rolname AS grantee,
+      -- Grantee 0 is the PUBLIC pseudo-role, which has no pg_authid entry
+      COALESCE(t_grantee.rolname, 'PUBLIC') AS grantee,
       combined.objkind,
       combined.schema,
       combined.unqualified_name,
-      combined.privilege_type
+      combined.privilege_type,
+      combined.is_grantable
   FROM
       combined
-      JOIN pg_authid t_grantee ON combined.grantee_oid = t_grantee.oid
-      WHERE combined.owner != t_grantee.rolname
+      LEFT JOIN pg_authid t_grantee ON combined.grantee_oid = t_grantee.oid
+      WHERE combined.grantee_oid = 0 OR combined.owner != t_grantee.rolname
 ORDER BY 1, 2, 3, 4, 5
 ";
 
@@ -72,9 +124,10 @@ SELECT
   rolinherit,
   rolreplication,
   rolsuper,
-  rolvaliduntil
+  -- The driver cannot represent infinity, which means the same as NULL
+  NULLIF(rolvaliduntil, 'infinity') AS rolvaliduntil
 FROM pg_authid
-WHERE rolname  NOT LIKE 'pg_%';
+WHERE rolname NOT LIKE 'pg\\_%';
 ";
 
 pub const Q_GET_DEFAULT_PERMISSIONS: &str = "
@@ -129,45 +182,71 @@ pub const Q_RAW_OBJECT_ATTRIBUTES: &str = "
             c.relname AS unqualified_name,
             c.relowner AS owner_id,
             -- Auto-dependency means that a sequence is linked to a table. Ownership of
-            -- that sequence automatically derives from the table's ownership
-            COUNT(deps.refobjid) > 0 AS is_dependent
+            -- that sequence automatically derives from the table's ownership. Identity
+            -- sequences are linked to their table with an internal dependency instead
+            EXISTS (
+                SELECT 1
+                FROM pg_depend deps
+                WHERE deps.objid = c.oid
+                    AND deps.classid = 'pg_class'::REGCLASS
+                    AND deps.refclassid = 'pg_class'::REGCLASS
+                    AND deps.deptype IN ('a', 'i')
+            ) AS is_dependent,
+            'pg_class'::REGCLASS AS classid,
+            c.oid AS objid
         FROM
             pg_class c
             JOIN relkind_mapping map
                 ON c.relkind = map.objkey
             JOIN pg_namespace nsp
                 ON c.relnamespace = nsp.OID
-            LEFT JOIN pg_depend deps
-                ON deps.objid = c.oid
-                AND deps.classid = 'pg_class'::REGCLASS
-                AND deps.refclassid = 'pg_class'::REGCLASS
-                AND deps.deptype = 'a'
-        GROUP BY
-            map.kind,
-            schema,
-            unqualified_name,
-            owner_id
     ), schemas AS (
         SELECT
             'schemas'::TEXT AS kind,
             nsp.nspname AS schema,
             NULL::TEXT AS unqualified_name,
             nsp.nspowner AS owner_id,
-            FALSE AS is_dependent
+            FALSE AS is_dependent,
+            'pg_namespace'::REGCLASS AS classid,
+            nsp.oid AS objid
         FROM pg_namespace nsp
+    ), functions AS (
+        SELECT
+            'functions'::TEXT AS kind,
+            nsp.nspname AS schema,
+            p.proname || '(' || oidvectortypes(p.proargtypes) || ')' AS unqualified_name,
+            p.proowner AS owner_id,
+            FALSE AS is_dependent,
+            'pg_proc'::REGCLASS AS classid,
+            p.oid AS objid
+        FROM pg_proc p
+        JOIN pg_namespace nsp
+            ON p.pronamespace = nsp.OID
+        WHERE p.prokind = 'f'
     ), combined AS (
         SELECT *
         FROM tables_and_sequences
         UNION ALL
         SELECT *
         FROM schemas
+        UNION ALL
+        SELECT *
+        FROM functions
     )
     SELECT
         co.kind,
         co.schema,
         co.unqualified_name,
         t_owner.rolname AS owner,
-        co.is_dependent
+        co.is_dependent,
+        -- Objects created by an extension are owned and managed by it
+        EXISTS (
+            SELECT 1
+            FROM pg_depend ext
+            WHERE ext.classid = co.classid
+                AND ext.objid = co.objid
+                AND ext.deptype = 'e'
+        ) AS is_extension_member
     FROM combined AS co
     JOIN pg_authid t_owner
         ON co.owner_id = t_owner.OID
@@ -177,3 +256,69 @@ pub const Q_RAW_OBJECT_ATTRIBUTES: &str = "
 
     ;
     ";
+
+// One row per policy, or a single row without one for tables that have
+// row-level security enabled but no policies
+pub const Q_GET_POLICIES: &str = "
+SELECT
+    nsp.nspname AS schema,
+    c.relname AS table_name,
+    c.relrowsecurity AS enabled,
+    c.relforcerowsecurity AS forced,
+    pol.polname::TEXT AS name,
+    pol.polcmd::TEXT AS command,
+    COALESCE(pol.polpermissive, TRUE) AS permissive,
+    ARRAY(
+        SELECT CASE WHEN r.oid = 0 THEN 'PUBLIC' ELSE pg_get_userbyid(r.oid)::TEXT END
+        FROM unnest(pol.polroles) AS r(oid)
+        ORDER BY 1
+    ) AS roles,
+    pg_get_expr(pol.polqual, pol.polrelid) AS using_expression,
+    pg_get_expr(pol.polwithcheck, pol.polrelid) AS with_check_expression
+FROM pg_class c
+JOIN pg_namespace nsp
+    ON c.relnamespace = nsp.oid
+LEFT JOIN pg_policy pol
+    ON pol.polrelid = c.oid
+WHERE
+    (c.relrowsecurity OR pol.oid IS NOT NULL)
+    AND nsp.nspname NOT LIKE 'pg\\_%'
+    AND nsp.nspname != 'information_schema'
+ORDER BY 1, 2, 5
+";
+
+// Settings made with ALTER ROLE ... SET, one row per setting. The database is
+// NULL for settings that apply in every database.
+pub const Q_GET_ROLE_SETTINGS: &str = "
+SELECT
+    r.rolname AS role,
+    d.datname::TEXT AS database,
+    unnest(s.setconfig) AS setting
+FROM pg_db_role_setting s
+JOIN pg_authid r
+    ON s.setrole = r.oid
+LEFT JOIN pg_database d
+    ON s.setdatabase = d.oid
+";
+
+pub const Q_GET_ROLE_PASSWORD: &str = "
+SELECT rolpassword
+FROM pg_authid
+WHERE rolname = $1
+";
+
+// Extension members are kept here, but their grants are not captured, so
+// escalations through them are not found.
+pub const Q_GET_SECURITY_DEFINER_FUNCTIONS: &str = "
+SELECT
+    nsp.nspname AS schema,
+    p.proname || '(' || oidvectortypes(p.proargtypes) || ')' AS unqualified_name
+FROM pg_proc p
+JOIN pg_namespace nsp
+    ON p.pronamespace = nsp.oid
+WHERE
+    p.prosecdef
+    AND p.prokind = 'f'
+    AND nsp.nspname NOT LIKE 'pg\\_%'
+    AND nsp.nspname != 'information_schema'
+";
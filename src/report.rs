@@ -0,0 +1,668 @@
+//! Access review reports.
+//!
+//! [`Report`] describes every login role for a periodic access review: its
+//! attributes, the groups it belongs to directly or through other groups,
+//! what it can read and write by schema, and what it owns. It is built from
+//! a database or from a spec and rendered as Markdown, for pull requests, or
+//! as a standalone HTML page.
+//!
+//! Postgres does not record when a role changed. For a spec kept in git,
+//! [`spec_history`] finds when the lines defining each role were last
+//! committed.
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::ops::Range;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, SubsecRound, TimeZone, Utc};
+
+use crate::access::{Access, AccessGraph, Source};
+use crate::context::{Attributes, Context, ObjectKind, PrivilegeType, RoleAttribute, PUBLIC};
+use crate::load;
+use crate::spec::{format_timestamp, DatabaseSpec};
+
+/// What a role can read and write in one schema, as display names.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SchemaAccess {
+    pub read: BTreeSet<String>,
+    pub write: BTreeSet<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RoleReport {
+    pub name: String,
+    pub attributes: Vec<String>,
+    pub valid_until: Option<DateTime<Utc>>,
+    /// Every group the role belongs to, with the groups it belongs through,
+    /// empty for direct memberships
+    pub groups: Vec<(String, Vec<String>)>,
+    /// Superusers can read and write everything, so `access` is left empty
+    pub is_superuser: bool,
+    pub access: BTreeMap<String, SchemaAccess>,
+    pub owns: Vec<String>,
+    pub last_changed: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// Where the roles were read from, e.g. a spec file or a database
+    pub source: String,
+    pub generated_at: DateTime<Utc>,
+    pub roles: Vec<RoleReport>,
+}
+
+impl Report {
+    pub fn from_context<T: Context>(context: &mut T, source: &str) -> Self
+    where
+        T::RoleAttribute: RoleAttribute,
+    {
+        let graph = AccessGraph::from_context(context);
+        let mut member_of = BTreeMap::new();
+        let mut logins = vec![];
+        for name in context.get_roles() {
+            let attributes = context.get_role_attributes(&name).get_attributes();
+            member_of.insert(
+                name.clone(),
+                context.get_role_memberships(&name).memberships,
+            );
+            if attributes.contains(&Attributes::Enabled) {
+                logins.push((name, attributes));
+            }
+        }
+
+        let mut roles = vec![];
+        for (name, attributes) in logins {
+            let is_superuser = attributes.contains(&Attributes::Superuser);
+            let mut access: BTreeMap<String, SchemaAccess> = BTreeMap::new();
+            if !is_superuser {
+                for effective in graph.what_can(&name) {
+                    let mut item = item(
+                        effective.object.kind,
+                        effective.object.unqualified_name.as_deref(),
+                    );
+                    if effective.access == Access::SetRole {
+                        let holder = effective.path.last().unwrap();
+                        item.push_str(&format!(" (after SET ROLE {})", holder));
+                    }
+                    if effective.source == Source::Ownership && effective.path.len() == 1 {
+                        // Listed under what the role owns
+                        continue;
+                    }
+                    let schema = access.entry(effective.object.schema.clone()).or_default();
+                    match effective.privilege {
+                        PrivilegeType::Read => schema.read.insert(item),
+                        PrivilegeType::Write => schema.write.insert(item),
+                    };
+                }
+            }
+            let owns = context
+                .get_role_ownerships(&name)
+                .iter()
+                .map(|o| format!("{} {}", o.kind, o.fqn()))
+                .collect();
+            let valid_until = attributes.iter().find_map(|a| match a {
+                Attributes::ValidUntil(t) => Some(*t),
+                _ => None,
+            });
+            roles.push(RoleReport {
+                groups: groups(&name, &member_of),
+                attributes: attribute_names(&attributes),
+                name,
+                valid_until,
+                is_superuser,
+                access,
+                owns,
+                last_changed: None,
+            });
+        }
+
+        roles.sort_by(|a, b| a.name.cmp(&b.name));
+        Report {
+            source: source.to_string(),
+            generated_at: Utc::now().trunc_subsecs(0),
+            roles,
+        }
+    }
+
+    /// Builds the report from a spec. Privileges are listed as written, so
+    /// wildcards such as `finance.*` are not expanded. `history` gives when
+    /// each role last changed, see [`spec_history`].
+    pub fn from_spec(
+        spec: &DatabaseSpec,
+        source: &str,
+        history: &BTreeMap<String, DateTime<Utc>>,
+    ) -> Self {
+        let member_of: BTreeMap<String, Vec<String>> = spec
+            .roles
+            .iter()
+            .map(|(name, role)| {
+                let groups = role.member_of.iter().map(|m| m.role.clone()).collect();
+                (name.clone(), groups)
+            })
+            .collect();
+
+        let mut roles = vec![];
+        for (name, role) in spec
+            .roles
+            .iter()
+            .filter(|(n, r)| r.can_login && *n != PUBLIC)
+        {
+            let groups = groups(name, &member_of);
+            let mut access: BTreeMap<String, SchemaAccess> = BTreeMap::new();
+            if !role.is_superuser {
+                let mut holders = vec![name.clone()];
+                holders.extend(groups.iter().map(|(group, _)| group.clone()));
+                holders.push(PUBLIC.to_string());
+                for holder in holders {
+                    let Some(holder_role) = spec.roles.get(&holder) else {
+                        continue;
+                    };
+                    for (kind, privilege, grant) in holder_role.privileges.grants() {
+                        let (schema, object) = match kind {
+                            ObjectKind::Schema => (grant.object.as_str(), None),
+                            _ => match grant.object.split_once('.') {
+                                Some((schema, object)) => (schema, Some(object)),
+                                None => (grant.object.as_str(), None),
+                            },
+                        };
+                        let mut item = item(kind, object);
+                        if holder != *name {
+                            item.push_str(&format!(" (via {})", holder));
+                        }
+                        let schema = access.entry(schema.to_string()).or_default();
+                        match privilege {
+                            PrivilegeType::Read => schema.read.insert(item),
+                            PrivilegeType::Write => schema.write.insert(item),
+                        };
+                    }
+                }
+            }
+
+            let owned = [
+                (ObjectKind::Schema, &role.owns.schemas),
+                (ObjectKind::Table, &role.owns.tables),
+                (ObjectKind::Sequence, &role.owns.sequences),
+                (ObjectKind::Function, &role.owns.functions),
+            ];
+            let mut owns: Vec<String> = owned
+                .into_iter()
+                .flat_map(|(kind, names)| names.iter().map(move |n| format!("{} {}", kind, n)))
+                .collect();
+            if role.has_personal_schema {
+                owns.push(format!("schema {} (personal)", name));
+            }
+
+            let mut attributes = vec!["login".to_string()];
+            if role.is_superuser {
+                attributes.push("superuser".to_string());
+            }
+            roles.push(RoleReport {
+                name: name.clone(),
+                attributes,
+                valid_until: role.valid_until.flatten(),
+                groups,
+                is_superuser: role.is_superuser,
+                access,
+                owns,
+                last_changed: history.get(name).copied(),
+            });
+        }
+
+        roles.sort_by(|a, b| a.name.cmp(&b.name));
+        Report {
+            source: source.to_string(),
+            generated_at: Utc::now().trunc_subsecs(0),
+            roles,
+        }
+    }
+
+    /// Renders the report in `format`, `markdown` or `html`.
+    pub fn render(&self, format: &str) -> Result<String> {
+        match format {
+            "markdown" => Ok(self.to_markdown()),
+            "html" => Ok(self.to_html()),
+            _ => bail!("Unknown format: {}, expected markdown or html", format),
+        }
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut out = vec![
+            "# Access review".to_string(),
+            String::new(),
+            format!("Source: {}  ", self.source),
+            format!("Generated: {}", format_timestamp(self.generated_at)),
+        ];
+        for role in &self.roles {
+            out.push(String::new());
+            out.push(format!("## {}", role.name));
+            out.push(String::new());
+            for (label, value) in role.summary() {
+                out.push(format!("- **{}:** {}", label, value));
+            }
+            out.push(String::new());
+            if role.is_superuser {
+                out.push("Superuser, can read and write everything.".to_string());
+            } else if role.access.is_empty() {
+                out.push("No access to any schema.".to_string());
+            } else {
+                out.push("| Schema | Read | Write |".to_string());
+                out.push("| --- | --- | --- |".to_string());
+                for (schema, access) in &role.access {
+                    out.push(format!(
+                        "| {} | {} | {} |",
+                        markdown_cell(schema),
+                        markdown_cell(&join(&access.read)),
+                        markdown_cell(&join(&access.write))
+                    ));
+                }
+            }
+        }
+        out.push(String::new());
+        out.join("\n")
+    }
+
+    pub fn to_html(&self) -> String {
+        let mut out = vec![
+            "<!DOCTYPE html>".to_string(),
+            "<html lang=\"en\">".to_string(),
+            "<head>".to_string(),
+            "<meta charset=\"utf-8\">".to_string(),
+            "<title>Access review</title>".to_string(),
+            "<style>".to_string(),
+            "body { font-family: sans-serif; margin: 2em auto; max-width: 60em; }".to_string(),
+            "table { border-collapse: collapse; margin-bottom: 1em; }".to_string(),
+            "th, td { border: 1px solid #ccc; padding: 0.3em 0.6em; text-align: left; vertical-align: top; }".to_string(),
+            "</style>".to_string(),
+            "</head>".to_string(),
+            "<body>".to_string(),
+            "<h1>Access review</h1>".to_string(),
+            format!(
+                "<p>Source: {}<br>Generated: {}</p>",
+                escape(&self.source),
+                format_timestamp(self.generated_at)
+            ),
+        ];
+        for role in &self.roles {
+            out.push(format!("<h2>{}</h2>", escape(&role.name)));
+            out.push("<ul>".to_string());
+            for (label, value) in role.summary() {
+                out.push(format!(
+                    "<li><strong>{}:</strong> {}</li>",
+                    label,
+                    escape(&value)
+                ));
+            }
+            out.push("</ul>".to_string());
+            if role.is_superuser {
+                out.push("<p>Superuser, can read and write everything.</p>".to_string());
+            } else if role.access.is_empty() {
+                out.push("<p>No access to any schema.</p>".to_string());
+            } else {
+                out.push("<table>".to_string());
+                out.push("<tr><th>Schema</th><th>Read</th><th>Write</th></tr>".to_string());
+                for (schema, access) in &role.access {
+                    out.push(format!(
+                        "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+                        escape(schema),
+                        escape(&join(&access.read)),
+                        escape(&join(&access.write))
+                    ));
+                }
+                out.push("</table>".to_string());
+            }
+        }
+        out.push("</body>".to_string());
+        out.push("</html>".to_string());
+        out.push(String::new());
+        out.join("\n")
+    }
+}
+
+impl RoleReport {
+    /// The labelled facts listed before the access table.
+    fn summary(&self) -> Vec<(&'static str, String)> {
+        let none = || "none".to_string();
+        let groups: Vec<String> = self
+            .groups
+            .iter()
+            .map(|(group, through)| match through.is_empty() {
+                true => group.clone(),
+                false => format!("{} (via {})", group, through.join(" -> ")),
+            })
+            .collect();
+        let mut summary = vec![("Attributes", self.attributes.join(", "))];
+        if let Some(valid_until) = self.valid_until {
+            summary.push(("Password valid until", format_timestamp(valid_until)));
+        }
+        summary.push((
+            "Member of",
+            Some(groups.join(", "))
+                .filter(|g| !g.is_empty())
+                .unwrap_or_else(none),
+        ));
+        summary.push((
+            "Owns",
+            Some(self.owns.join(", "))
+                .filter(|o| !o.is_empty())
+                .unwrap_or_else(none),
+        ));
+        summary.push((
+            "Last changed",
+            self.last_changed
+                .map_or("unknown".to_string(), format_timestamp),
+        ));
+        summary
+    }
+}
+
+/// The groups `role` belongs to, directly or through other groups, with the
+/// shortest chain of groups in between.
+fn groups(role: &str, member_of: &BTreeMap<String, Vec<String>>) -> Vec<(String, Vec<String>)> {
+    let mut found = vec![];
+    let mut seen = HashSet::from([role.to_string()]);
+    let mut queue = VecDeque::from([(role.to_string(), vec![])]);
+    while let Some((name, through)) = queue.pop_front() {
+        for group in member_of.get(&name).into_iter().flatten() {
+            if !seen.insert(group.clone()) {
+                continue;
+            }
+            found.push((group.clone(), through.clone()));
+            let mut next = through.clone();
+            next.push(group.clone());
+            queue.push_back((group.clone(), next));
+        }
+    }
+    found
+}
+
+fn attribute_names(attributes: &[Attributes]) -> Vec<String> {
+    attributes
+        .iter()
+        .filter_map(|a| match a {
+            Attributes::Enabled => Some("login".to_string()),
+            Attributes::Superuser => Some("superuser".to_string()),
+            Attributes::CreateDb => Some("createdb".to_string()),
+            Attributes::CreateRole => Some("createrole".to_string()),
+            Attributes::Replication => Some("replication".to_string()),
+            Attributes::BypassRls => Some("bypassrls".to_string()),
+            Attributes::ConnectionLimit(n) => Some(format!("connection limit {}", n)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// How an object appears in its schema's row: the schema itself, every
+/// object of a kind for `*`, or the object's name.
+fn item(kind: ObjectKind, name: Option<&str>) -> String {
+    match name {
+        None => "(schema)".to_string(),
+        Some("*") => format!("all {}s", kind),
+        Some(name) if kind == ObjectKind::Table => name.to_string(),
+        Some(name) => format!("{} ({})", name, kind),
+    }
+}
+
+fn join(items: &BTreeSet<String>) -> String {
+    items.iter().cloned().collect::<Vec<_>>().join(", ")
+}
+
+fn markdown_cell(text: &str) -> String {
+    text.replace('|', "\\|")
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// When each role in the spec at `path` was last changed, from `git blame`
+/// of the lines defining it under `roles`. Roles in files outside git, or
+/// only changed in uncommitted lines, are left out.
+pub fn spec_history(path: &str) -> BTreeMap<String, DateTime<Utc>> {
+    let mut history: BTreeMap<String, DateTime<Utc>> = BTreeMap::new();
+    let Ok(files) = load::spec_files(Path::new(path)) else {
+        return history;
+    };
+    for file in files {
+        let Some(blame) = blame(&file) else {
+            continue;
+        };
+        let Ok(source) = std::fs::read_to_string(&file) else {
+            continue;
+        };
+        for (role, lines) in role_lines(&source) {
+            let changed = lines.filter_map(|line| blame.get(&line)).max();
+            if let Some(changed) = changed {
+                let entry = history.entry(role).or_insert(*changed);
+                *entry = (*entry).max(*changed);
+            }
+        }
+    }
+    history
+}
+
+/// The commit time of every committed line of `file`, by 1-based line.
+fn blame(file: &Path) -> Option<BTreeMap<usize, DateTime<Utc>>> {
+    let directory = file.parent().filter(|d| !d.as_os_str().is_empty());
+    let output = Command::new("git")
+        .args(["blame", "--porcelain", "--"])
+        .arg(file.file_name()?)
+        .current_dir(directory.unwrap_or(Path::new(".")))
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(parse_blame(&String::from_utf8_lossy(&output.stdout)))
+}
+
+fn parse_blame(porcelain: &str) -> BTreeMap<usize, DateTime<Utc>> {
+    let mut times: BTreeMap<&str, DateTime<Utc>> = BTreeMap::new();
+    let mut lines = BTreeMap::new();
+    let mut current: Option<(&str, usize)> = None;
+    for line in porcelain.lines() {
+        if line.starts_with('\t') {
+            if let Some((commit, number)) = current {
+                // Uncommitted lines are blamed on an all-zero commit
+                if let Some(time) = times
+                    .get(commit)
+                    .filter(|_| !commit.trim_matches('0').is_empty())
+                {
+                    lines.insert(number, *time);
+                }
+            }
+        } else if let Some(time) = line.strip_prefix("committer-time ") {
+            let time = time
+                .parse()
+                .ok()
+                .and_then(|t| Utc.timestamp_opt(t, 0).single());
+            if let (Some((commit, _)), Some(time)) = (current, time) {
+                times.insert(commit, time);
+            }
+        } else {
+            let mut words = line.split(' ');
+            let commit = words.next().unwrap_or_default();
+            let number = words.nth(1).and_then(|n| n.parse().ok());
+            if let Some(number) = number.filter(|_| commit.len() == 40) {
+                current = Some((commit, number));
+            }
+        }
+    }
+    lines
+}
+
+/// The 1-based lines each role takes up under the top-level `roles` key,
+/// from its name to the line before the next role or top-level key.
+fn role_lines(source: &str) -> Vec<(String, Range<usize>)> {
+    let mut roles: Vec<(String, Range<usize>)> = vec![];
+    let mut in_roles = false;
+    let mut role_indent = None;
+    let end = source.lines().count() + 1;
+    for (index, line) in source.lines().enumerate() {
+        let number = index + 1;
+        let content = line.trim();
+        if content.is_empty() || content.starts_with('#') {
+            continue;
+        }
+        let indent = line.len() - line.trim_start().len();
+        let is_role = in_roles && *role_indent.get_or_insert(indent) == indent;
+        if indent == 0 || is_role {
+            if let Some((_, lines)) = roles.last_mut().filter(|(_, l)| l.end == end) {
+                lines.end = number;
+            }
+        }
+        if indent == 0 {
+            in_roles = content.starts_with("roles:");
+        } else if let Some(name) = key(content).filter(|_| is_role) {
+            roles.push((name.to_string(), number..end));
+        }
+    }
+    roles
+}
+
+/// The key of a `key: value` line, without the quotes YAML allows around it.
+fn key(content: &str) -> Option<&str> {
+    for quote in ['"', '\''] {
+        if let Some(rest) = content.strip_prefix(quote) {
+            return rest.split_once(quote).map(|(key, _)| key);
+        }
+    }
+    content.split_once(':').map(|(key, _)| key.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::fakedb::FakeDb;
+
+    #[test]
+    fn test_report_from_context() {
+        let mut db = FakeDb::default();
+        let mut report = Report::from_context(&mut db, "fake_db");
+        report.generated_at = Utc.with_ymd_and_hms(2025, 7, 1, 0, 0, 0).unwrap();
+
+        let names: Vec<&str> = report.roles.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["alice", "bob", "carol"]);
+        let alice = &report.roles[0];
+        assert_eq!(
+            alice.groups,
+            vec![
+                ("developer".to_string(), vec![]),
+                ("analyst".to_string(), vec!["developer".to_string()]),
+            ]
+        );
+        assert!(alice.owns.contains(&"table finance.q2_results".to_string()));
+
+        let markdown = report.to_markdown();
+        assert!(
+            markdown.starts_with("# Access review\n\nSource: fake_db  \nGenerated: 2025-07-01\n")
+        );
+        assert!(markdown.contains(
+            "## bob\n\n- **Attributes:** login\n- **Member of:** analyst\n- **Owns:** none\n- **Last changed:** unknown\n"
+        ));
+        assert!(markdown.contains("| finance | (schema), q2_results, total_revenue(integer) (function) | (schema), q2_results |"));
+
+        let html = report.to_html();
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<h2>carol</h2>"));
+        assert!(html.contains("<td>marketing</td><td>(schema)</td><td></td>"));
+    }
+
+    #[test]
+    fn test_report_from_spec() {
+        let spec: DatabaseSpec = serde_yaml::from_str(
+            "
+            version: 1
+            adapter: postgres
+            roles:
+              PUBLIC:
+                privileges:
+                  schemas:
+                    read:
+                      - public
+              analyst:
+                can_login: no
+                privileges:
+                  tables:
+                    read:
+                      - finance.*
+              jdoe:
+                valid_until: 2025-12-31
+                member_of:
+                  - analyst
+                has_personal_schema: yes
+                privileges:
+                  tables:
+                    write:
+                      - finance.q2_results
+            ",
+        )
+        .unwrap();
+        let changed = Utc.with_ymd_and_hms(2025, 5, 1, 12, 0, 0).unwrap();
+        let history = BTreeMap::from([("jdoe".to_string(), changed)]);
+
+        let report = Report::from_spec(&spec, "spec.yml", &history);
+        assert_eq!(report.roles.len(), 1);
+        let jdoe = &report.roles[0];
+        assert_eq!(jdoe.owns, vec!["schema jdoe (personal)"]);
+        assert_eq!(
+            jdoe.access["finance"].read,
+            BTreeSet::from(["all tables (via analyst)".to_string()])
+        );
+        assert_eq!(
+            jdoe.access["public"].read,
+            BTreeSet::from(["(schema) (via PUBLIC)".to_string()])
+        );
+        let markdown = report.to_markdown();
+        assert!(markdown.contains("- **Password valid until:** 2025-12-31\n"));
+        assert!(markdown.contains("- **Last changed:** 2025-05-01T12:00:00Z\n"));
+    }
+
+    #[test]
+    fn test_role_history() {
+        let source = "\
+version: 1
+roles:
+  # Analysts
+  analyst:
+    can_login: no
+  jdoe:
+    member_of:
+      - analyst
+  \"PUBLIC\": {}
+  'svc-x':
+    can_login: yes
+templates:
+  base: {}
+";
+        assert_eq!(
+            role_lines(source),
+            vec![
+                ("analyst".to_string(), 4..6),
+                ("jdoe".to_string(), 6..9),
+                ("PUBLIC".to_string(), 9..10),
+                ("svc-x".to_string(), 10..12),
+            ]
+        );
+
+        let porcelain = "\
+1111111111111111111111111111111111111111 1 4 2
+committer-time 1746100800
+\tanalyst:
+1111111111111111111111111111111111111111 2 5
+\t  can_login: no
+0000000000000000000000000000000000000000 6 6 1
+committer-time 1751328000
+\tjdoe:
+";
+        let lines = parse_blame(porcelain);
+        assert_eq!(lines.keys().copied().collect::<Vec<_>>(), vec![4, 5]);
+        assert_eq!(
+            lines[&4],
+            Utc.with_ymd_and_hms(2025, 5, 1, 12, 0, 0).unwrap()
+        );
+    }
+}
@@ -0,0 +1,552 @@
+//! Security policy checks.
+//!
+//! A rules file lists the built-in checks to run and how they apply, so a
+//! written security policy can be enforced in CI:
+//!
+//! ```yaml
+//! rules:
+//!   - check: login_role_privileges
+//!     except: [airflow]
+//!   - check: superuser
+//!     except: [postgres]
+//!   - check: createrole_login
+//!   - check: public_create
+//!   - check: table_owner_group
+//!     schemas: [finance]
+//!     severity: warning
+//! ```
+//!
+//! The checks run against a [`Catalog`], built from a spec or from a
+//! database, and report [`Finding`]s as text, JSON or SARIF. A spec can only
+//! express what permirust manages, so checks on attributes such as
+//! CREATEROLE only find something in a database.
+use std::collections::BTreeMap;
+use std::fmt;
+
+use anyhow::{bail, Context as _, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::context::{Attributes, Context, ObjectKind, PrivilegeType, RoleAttribute, PUBLIC};
+use crate::spec::DatabaseSpec;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Check {
+    /// Login roles must not hold privileges on objects directly, only
+    /// through the group roles they are members of
+    LoginRolePrivileges,
+    /// No role is a superuser
+    Superuser,
+    /// No role has both CREATEROLE and LOGIN
+    CreateroleLogin,
+    /// PUBLIC has no CREATE on any schema
+    PublicCreate,
+    /// Every table is owned by a group role, not by a login role
+    TableOwnerGroup,
+}
+
+impl Check {
+    pub const ALL: [Check; 5] = [
+        Check::LoginRolePrivileges,
+        Check::Superuser,
+        Check::CreateroleLogin,
+        Check::PublicCreate,
+        Check::TableOwnerGroup,
+    ];
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Check::LoginRolePrivileges => {
+                "Login roles must not hold object privileges directly, only through groups"
+            }
+            Check::Superuser => "No role is a superuser",
+            Check::CreateroleLogin => "No role has both CREATEROLE and LOGIN",
+            Check::PublicCreate => "PUBLIC has no CREATE on any schema",
+            Check::TableOwnerGroup => "Tables are owned by a group role, not a login role",
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Check::LoginRolePrivileges => "login_role_privileges",
+            Check::Superuser => "superuser",
+            Check::CreateroleLogin => "createrole_login",
+            Check::PublicCreate => "public_create",
+            Check::TableOwnerGroup => "table_owner_group",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    #[default]
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => f.write_str("warning"),
+            Severity::Error => f.write_str("error"),
+        }
+    }
+}
+
+/// A check and how it applies.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub check: Check,
+    #[serde(default)]
+    pub severity: Severity,
+    /// Roles the check does not apply to
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub except: Vec<String>,
+    /// Only check objects in these schemas, every schema when empty
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub schemas: Vec<String>,
+}
+
+impl From<Check> for Rule {
+    fn from(check: Check) -> Self {
+        Rule {
+            check,
+            severity: Severity::default(),
+            except: vec![],
+            schemas: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rules {
+    pub rules: Vec<Rule>,
+}
+
+impl Default for Rules {
+    /// Every check, with its defaults.
+    fn default() -> Self {
+        Rules {
+            rules: Check::ALL.into_iter().map(Rule::from).collect(),
+        }
+    }
+}
+
+impl Rules {
+    pub fn read_file(path: &str) -> Result<Rules> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open rules file: {}", path))?;
+        serde_yaml::from_reader(file).with_context(|| format!("Invalid rules file: {}", path))
+    }
+
+    /// Runs every rule against `catalog`.
+    pub fn check(&self, catalog: &Catalog) -> Vec<Finding> {
+        self.rules
+            .iter()
+            .flat_map(|rule| rule.check_catalog(catalog))
+            .collect()
+    }
+}
+
+/// What a check found, on a role or an object.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    pub check: Check,
+    pub severity: Severity,
+    /// The role or object the finding is about
+    pub subject: String,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}[{}]: {}", self.severity, self.check, self.message)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CatalogRole {
+    pub can_login: bool,
+    pub is_superuser: bool,
+    pub create_role: bool,
+    /// Privileges held directly, by object kind and name
+    pub privileges: Vec<(ObjectKind, PrivilegeType, String)>,
+    pub owns: Vec<(ObjectKind, String)>,
+}
+
+/// The roles the checks look at, from a spec or a database. PUBLIC is kept
+/// with the privileges granted to everyone.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Catalog {
+    pub roles: BTreeMap<String, CatalogRole>,
+}
+
+impl Catalog {
+    pub fn from_spec(spec: &DatabaseSpec) -> Self {
+        let mut catalog = Catalog::default();
+        for (name, role) in &spec.roles {
+            let owns = &role.owns;
+            let owned = [
+                (ObjectKind::Schema, &owns.schemas),
+                (ObjectKind::Table, &owns.tables),
+                (ObjectKind::Sequence, &owns.sequences),
+                (ObjectKind::Function, &owns.functions),
+            ];
+            let node = CatalogRole {
+                can_login: role.can_login && name != PUBLIC,
+                is_superuser: role.is_superuser,
+                create_role: false,
+                privileges: role
+                    .privileges
+                    .grants()
+                    .into_iter()
+                    .map(|(kind, privilege, grant)| (kind, privilege, grant.object.clone()))
+                    .collect(),
+                owns: owned
+                    .into_iter()
+                    .flat_map(|(kind, names)| names.iter().map(move |n| (kind, n.clone())))
+                    .collect(),
+            };
+            catalog.roles.insert(name.clone(), node);
+        }
+        catalog
+    }
+
+    pub fn from_context<T: Context>(context: &mut T) -> Self
+    where
+        T::RoleAttribute: RoleAttribute,
+    {
+        let mut catalog = Catalog::default();
+        let public = CatalogRole {
+            privileges: privileges(context, PUBLIC),
+            ..Default::default()
+        };
+        catalog.roles.insert(PUBLIC.to_string(), public);
+        for name in context.get_roles() {
+            let attributes = context.get_role_attributes(&name).get_attributes();
+            let node = CatalogRole {
+                can_login: attributes.contains(&Attributes::Enabled),
+                is_superuser: attributes.contains(&Attributes::Superuser),
+                create_role: attributes.contains(&Attributes::CreateRole),
+                privileges: privileges(context, &name),
+                owns: context
+                    .get_role_ownerships(&name)
+                    .into_iter()
+                    .map(|o| (o.kind, o.fqn()))
+                    .collect(),
+            };
+            catalog.roles.insert(name, node);
+        }
+        catalog
+    }
+}
+
+fn privileges<T: Context>(context: &mut T, role: &str) -> Vec<(ObjectKind, PrivilegeType, String)> {
+    let mut privileges = vec![];
+    for privilege in context.get_role_permissions(role) {
+        let mut types: Vec<PrivilegeType> = privilege.privs.into_iter().collect();
+        types.sort();
+        for privilege_type in types {
+            privileges.push((
+                privilege.object.kind,
+                privilege_type,
+                privilege.object.fqn(),
+            ));
+        }
+    }
+    privileges
+}
+
+impl Rule {
+    fn check_catalog(&self, catalog: &Catalog) -> Vec<Finding> {
+        let finding = |subject: &str, message: String| Finding {
+            check: self.check,
+            severity: self.severity,
+            subject: subject.to_string(),
+            message,
+        };
+        let roles = catalog
+            .roles
+            .iter()
+            .filter(|(name, _)| !self.except.contains(name));
+        let mut findings = vec![];
+        match self.check {
+            Check::LoginRolePrivileges => {
+                for (name, role) in roles.filter(|(_, r)| r.can_login) {
+                    for (kind, privilege, object) in &role.privileges {
+                        if !self.in_schemas(*kind, object) {
+                            continue;
+                        }
+                        findings.push(finding(
+                            name,
+                            format!(
+                                "Login role {} holds {} on {} {} directly",
+                                name, privilege, kind, object
+                            ),
+                        ));
+                    }
+                }
+            }
+            Check::Superuser => {
+                for (name, _) in roles.filter(|(_, r)| r.is_superuser) {
+                    findings.push(finding(name, format!("{} is a superuser", name)));
+                }
+            }
+            Check::CreateroleLogin => {
+                for (name, _) in roles.filter(|(_, r)| r.create_role && r.can_login) {
+                    findings.push(finding(
+                        name,
+                        format!("{} has both CREATEROLE and LOGIN", name),
+                    ));
+                }
+            }
+            Check::PublicCreate => {
+                let public = roles.filter(|(name, _)| *name == PUBLIC);
+                for (_, role) in public {
+                    for (kind, privilege, schema) in &role.privileges {
+                        if *kind == ObjectKind::Schema
+                            && *privilege == PrivilegeType::Write
+                            && self.in_schemas(*kind, schema)
+                        {
+                            findings.push(finding(
+                                schema,
+                                format!("PUBLIC has CREATE on schema {}", schema),
+                            ));
+                        }
+                    }
+                }
+            }
+            Check::TableOwnerGroup => {
+                for (name, role) in roles.filter(|(_, r)| r.can_login) {
+                    for (kind, object) in &role.owns {
+                        if matches!(kind, ObjectKind::Table | ObjectKind::View)
+                            && self.in_schemas(*kind, object)
+                        {
+                            findings.push(finding(
+                                object,
+                                format!("{} {} is owned by login role {}", kind, object, name),
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        findings
+    }
+
+    /// Whether the object is in one of `schemas`, when the rule names any.
+    fn in_schemas(&self, kind: ObjectKind, object: &str) -> bool {
+        let schema = match kind {
+            ObjectKind::Schema => object,
+            _ => object.split_once('.').map_or(object, |(schema, _)| schema),
+        };
+        self.schemas.is_empty() || self.schemas.iter().any(|s| s == schema)
+    }
+}
+
+/// Renders findings in `format`, `text`, `json` or `sarif`. SARIF results
+/// point at `source`, the spec or snapshot checked, when there is one.
+pub fn render(
+    findings: &[Finding],
+    rules: &Rules,
+    format: &str,
+    source: Option<&str>,
+) -> Result<String> {
+    match format {
+        "text" => Ok(findings
+            .iter()
+            .map(|f| f.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")),
+        "json" => Ok(serde_json::to_string_pretty(findings)?),
+        "sarif" => Ok(serde_json::to_string_pretty(&sarif(
+            findings, rules, source,
+        ))?),
+        _ => bail!("Unknown format: {}, expected text, json or sarif", format),
+    }
+}
+
+/// A SARIF 2.1.0 log, as read by code scanning tools.
+fn sarif(findings: &[Finding], rules: &Rules, source: Option<&str>) -> serde_json::Value {
+    let mut checks: Vec<Check> = rules.rules.iter().map(|r| r.check).collect();
+    checks.sort();
+    checks.dedup();
+    let descriptors: Vec<serde_json::Value> = checks
+        .iter()
+        .map(|check| {
+            json!({
+                "id": check.to_string(),
+                "shortDescription": { "text": check.description() },
+            })
+        })
+        .collect();
+    let results: Vec<serde_json::Value> = findings
+        .iter()
+        .map(|finding| {
+            let mut location = json!({
+                "logicalLocations": [{ "name": finding.subject }],
+            });
+            if let Some(source) = source {
+                location["physicalLocation"] = json!({ "artifactLocation": { "uri": source } });
+            }
+            json!({
+                "ruleId": finding.check.to_string(),
+                "level": finding.severity.to_string(),
+                "message": { "text": finding.message },
+                "locations": [location],
+            })
+        })
+        .collect();
+    json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "permirust",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "rules": descriptors,
+                }
+            },
+            "results": results,
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::fakedb::FakeDb;
+
+    #[test]
+    fn test_checks_spec() {
+        let spec: DatabaseSpec = serde_yaml::from_str(
+            "
+            version: 1
+            adapter: postgres
+            roles:
+              PUBLIC:
+                privileges:
+                  schemas:
+                    write:
+                      - public
+              admin:
+                is_superuser: yes
+              postgres:
+                is_superuser: yes
+              analyst:
+                can_login: no
+                privileges:
+                  tables:
+                    read:
+                      - finance.*
+              jdoe:
+                member_of:
+                  - analyst
+                owns:
+                  tables:
+                    - finance.q2_results
+                    - scratch.notes
+                privileges:
+                  tables:
+                    write:
+                      - marketing.leads
+            ",
+        )
+        .unwrap();
+        let rules: Rules = serde_yaml::from_str(
+            "
+            rules:
+              - check: login_role_privileges
+              - check: superuser
+                except: [postgres]
+              - check: public_create
+                severity: warning
+              - check: table_owner_group
+                schemas: [finance]
+            ",
+        )
+        .unwrap();
+
+        let findings: Vec<String> = rules
+            .check(&Catalog::from_spec(&spec))
+            .iter()
+            .map(|f| f.to_string())
+            .collect();
+        assert_eq!(
+            findings,
+            vec![
+                "error[login_role_privileges]: Login role jdoe holds write on table marketing.leads directly",
+                "error[superuser]: admin is a superuser",
+                "warning[public_create]: PUBLIC has CREATE on schema public",
+                "error[table_owner_group]: table finance.q2_results is owned by login role jdoe",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_checks_context() {
+        let mut db = FakeDb::default();
+        db.apply("ALTER ROLE carol CREATEROLE").unwrap();
+        let rules: Rules = serde_yaml::from_str(
+            "
+            rules:
+              - check: login_role_privileges
+                except: [bob]
+              - check: createrole_login
+            ",
+        )
+        .unwrap();
+
+        let findings = rules.check(&Catalog::from_context(&mut db));
+        let subjects: Vec<(Check, &str)> = findings
+            .iter()
+            .map(|f| (f.check, f.subject.as_str()))
+            .collect();
+        assert_eq!(
+            subjects,
+            vec![
+                (Check::LoginRolePrivileges, "carol"),
+                (Check::LoginRolePrivileges, "carol"),
+                (Check::CreateroleLogin, "carol"),
+            ]
+        );
+
+        let sarif: serde_json::Value = serde_json::from_str(
+            &render(&findings, &rules, "sarif", Some("snapshot.yml")).unwrap(),
+        )
+        .unwrap();
+        let result = &sarif["runs"][0]["results"][2];
+        assert_eq!(result["ruleId"], "createrole_login");
+        assert_eq!(result["level"], "error");
+        assert_eq!(
+            result["locations"][0]["physicalLocation"]["artifactLocation"]["uri"],
+            "snapshot.yml"
+        );
+        assert_eq!(
+            sarif["runs"][0]["tool"]["driver"]["rules"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_rejects_unknown_checks() {
+        let error =
+            serde_yaml::from_str::<Rules>("rules:\n  - check: no_superusers\n").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("unknown variant `no_superusers`"));
+    }
+}
@@ -0,0 +1,251 @@
+//! JSON Schema for the spec format.
+//!
+//! The schema is derived from the spec types in [`crate::spec`], so it always
+//! matches what permirust reads. Editors can use it to autocomplete and check
+//! spec files, e.g. with the VS Code YAML extension:
+//!
+//! ```yaml
+//! # yaml-language-server: $schema=./permirust.schema.json
+//! version: 1
+//! ```
+//!
+//! The validator in [`crate::validate`] reads the allowed keys from it too.
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{
+    ArrayValidation, InstanceType, ObjectValidation, RootSchema, Schema, SchemaObject,
+    SubschemaValidation,
+};
+use schemars::JsonSchema;
+use serde_json::Value;
+
+use crate::spec::{DatabaseSpec, Role};
+use crate::validate::SUPPORTED_VERSIONS;
+
+/// A date, an RFC 3339 timestamp or `infinity`, see
+/// [`crate::spec::parse_timestamp`].
+pub(crate) fn timestamp(_: &mut SchemaGenerator) -> Schema {
+    let format = |format: &str| {
+        Schema::Object(SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            format: Some(format.to_string()),
+            ..Default::default()
+        })
+    };
+    Schema::Object(SchemaObject {
+        subschemas: Some(Box::new(SubschemaValidation {
+            any_of: Some(vec![
+                format("date"),
+                format("date-time"),
+                Schema::Object(SchemaObject {
+                    enum_values: Some(vec![Value::from("infinity")]),
+                    ..Default::default()
+                }),
+            ]),
+            ..Default::default()
+        })),
+        ..Default::default()
+    })
+}
+
+/// The JSON Schema of [`DatabaseSpec`].
+pub fn spec_schema() -> RootSchema {
+    schemars::schema_for!(DatabaseSpec)
+}
+
+/// The JSON Schema of [`DatabaseSpec`], pretty printed.
+pub fn spec_schema_json() -> Result<String, serde_json::Error> {
+    serde_json::to_string_pretty(&spec_schema())
+}
+
+/// The keys allowed in the map described by `definition`, e.g. `Role`. For
+/// the spec itself pass `None`. Definitions that accept either a plain name
+/// or a map, such as `Membership`, return the keys of the map.
+pub(crate) fn properties(schema: &RootSchema, definition: Option<&str>) -> Vec<String> {
+    definition_object(schema, definition)
+        .and_then(object_properties)
+        .unwrap_or_default()
+}
+
+/// The keys that must be present in the map described by `definition`.
+pub(crate) fn required(schema: &RootSchema, definition: Option<&str>) -> Vec<String> {
+    definition_object(schema, definition)
+        .and_then(object_validation)
+        .map(|o| o.required.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+fn definition_object<'a>(
+    schema: &'a RootSchema,
+    definition: Option<&str>,
+) -> Option<&'a SchemaObject> {
+    match definition {
+        None => Some(&schema.schema),
+        Some(name) => match schema.definitions.get(name) {
+            Some(Schema::Object(object)) => Some(object),
+            _ => None,
+        },
+    }
+}
+
+fn object_properties(schema: &SchemaObject) -> Option<Vec<String>> {
+    object_validation(schema).map(|o| o.properties.keys().cloned().collect())
+}
+
+fn object_validation(schema: &SchemaObject) -> Option<&ObjectValidation> {
+    if let Some(object) = &schema.object {
+        return Some(object);
+    }
+    schema
+        .subschemas
+        .as_ref()?
+        .any_of
+        .as_ref()?
+        .iter()
+        .find_map(|s| match s {
+            Schema::Object(object) => object_validation(object),
+            _ => None,
+        })
+}
+
+/// A bare name or the map of options `T`, such as a `member_of` entry.
+pub(crate) fn name_or<T: JsonSchema>(gen: &mut SchemaGenerator) -> Schema {
+    let name = SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        ..Default::default()
+    };
+    Schema::Object(SchemaObject {
+        subschemas: Some(Box::new(SubschemaValidation {
+            any_of: Some(vec![name.into(), T::json_schema(gen)]),
+            ..Default::default()
+        })),
+        ..Default::default()
+    })
+}
+
+/// Booleans in a spec can also be written as `yes` or `no`.
+pub(crate) fn yes_no(_: &mut SchemaGenerator) -> Schema {
+    let boolean = SchemaObject {
+        instance_type: Some(InstanceType::Boolean.into()),
+        ..Default::default()
+    };
+    let words = SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        enum_values: Some(
+            ["yes", "no", "true", "false"]
+                .into_iter()
+                .map(Value::from)
+                .collect(),
+        ),
+        ..Default::default()
+    };
+    Schema::Object(SchemaObject {
+        subschemas: Some(Box::new(SubschemaValidation {
+            any_of: Some(vec![boolean.into(), words.into()]),
+            ..Default::default()
+        })),
+        ..Default::default()
+    })
+}
+
+/// `extends` names one template or a list of them.
+pub(crate) fn extends(_: &mut SchemaGenerator) -> Schema {
+    let name = SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        ..Default::default()
+    };
+    let names = SchemaObject {
+        instance_type: Some(InstanceType::Array.into()),
+        array: Some(Box::new(ArrayValidation {
+            items: Some(Schema::from(name.clone()).into()),
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    Schema::Object(SchemaObject {
+        subschemas: Some(Box::new(SubschemaValidation {
+            any_of: Some(vec![name.into(), names.into()]),
+            ..Default::default()
+        })),
+        ..Default::default()
+    })
+}
+
+/// Templates are written like roles.
+pub(crate) fn templates(gen: &mut SchemaGenerator) -> Schema {
+    Schema::Object(SchemaObject {
+        instance_type: Some(InstanceType::Object.into()),
+        object: Some(Box::new(ObjectValidation {
+            additional_properties: Some(Box::new(gen.subschema_for::<Role>())),
+            ..Default::default()
+        })),
+        ..Default::default()
+    })
+}
+
+pub(crate) fn version(_: &mut SchemaGenerator) -> Schema {
+    Schema::Object(SchemaObject {
+        instance_type: Some(InstanceType::Integer.into()),
+        enum_values: Some(
+            SUPPORTED_VERSIONS
+                .iter()
+                .map(|v| Value::from(v.parse::<u64>().unwrap()))
+                .collect(),
+        ),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema_describes_spec() {
+        let schema = spec_schema();
+
+        assert_eq!(
+            properties(&schema, None),
+            vec![
+                "adapter",
+                "ignore",
+                "include",
+                "manage_only",
+                "policies",
+                "roles",
+                "templates",
+                "version"
+            ]
+        );
+        assert_eq!(
+            properties(&schema, Some("Role")),
+            vec![
+                "can_login",
+                "extends",
+                "has_personal_schema",
+                "is_superuser",
+                "member_of",
+                "owns",
+                "password",
+                "privileges",
+                "settings",
+                "valid_until"
+            ]
+        );
+        assert_eq!(
+            properties(&schema, Some("Membership")),
+            vec!["expires", "role", "with_admin_option"]
+        );
+        assert_eq!(
+            properties(&schema, Some("Ownership")),
+            vec!["functions", "schemas", "sequences", "tables"]
+        );
+        assert_eq!(
+            properties(&schema, Some("Policy")),
+            vec!["command", "permissive", "roles", "using", "with_check"]
+        );
+        assert_eq!(required(&schema, None), vec!["adapter", "version"]);
+        assert_eq!(required(&schema, Some("Grant")), vec!["object"]);
+        let json = spec_schema_json().unwrap();
+        assert!(json.contains("\"additionalProperties\": false"));
+    }
+}
@@ -0,0 +1,169 @@
+//! Rules that decide which roles and objects permirust manages.
+//!
+//! A spec can list glob patterns under `ignore` and `manage_only`:
+//!
+//! ```yaml
+//! ignore:
+//!   roles:
+//!     - rds_*
+//!   objects:
+//!     - finance.tmp_*
+//! manage_only:
+//!   schemas:
+//!     - finance
+//!     - marketing
+//! ```
+//!
+//! Anything matching `ignore`, or not matching a non-empty `manage_only` list,
+//! is out of scope: it is left out of generated specs and the planner never
+//! grants or revokes anything on it.
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::context::{DatabaseObject, ObjectKind};
+
+/// Glob patterns for roles, schemas and objects. Patterns support `*`, which
+/// matches any run of characters, and `?`, which matches a single character.
+#[derive(Debug, Default, Clone, Deserialize, JsonSchema, Serialize, PartialEq, Eq)]
+#[schemars(deny_unknown_fields)]
+pub struct ScopeRules {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub schemas: Vec<String>,
+    /// Patterns matched against the fully qualified name of tables,
+    /// sequences and functions, e.g. `finance.tmp_*`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub objects: Vec<String>,
+}
+
+impl ScopeRules {
+    pub fn is_empty(&self) -> bool {
+        self.roles.is_empty() && self.schemas.is_empty() && self.objects.is_empty()
+    }
+}
+
+/// The combination of `ignore` and `manage_only` rules from a spec.
+#[derive(Debug, Default, Clone)]
+pub struct Scope {
+    pub ignore: ScopeRules,
+    pub manage_only: ScopeRules,
+}
+
+impl Scope {
+    pub fn new(ignore: ScopeRules, manage_only: ScopeRules) -> Self {
+        Scope {
+            ignore,
+            manage_only,
+        }
+    }
+
+    pub fn includes_role(&self, role: &str) -> bool {
+        is_included(&self.ignore.roles, &self.manage_only.roles, role)
+    }
+
+    /// Schema rules apply to a schema and everything inside it, object rules
+    /// apply to the objects inside a schema.
+    pub fn includes_object(&self, object: &DatabaseObject) -> bool {
+        if !is_included(
+            &self.ignore.schemas,
+            &self.manage_only.schemas,
+            &object.schema,
+        ) {
+            return false;
+        }
+
+        match object.kind {
+            ObjectKind::Schema => true,
+            _ => is_included(
+                &self.ignore.objects,
+                &self.manage_only.objects,
+                &object.fqn(),
+            ),
+        }
+    }
+}
+
+fn is_included(ignore: &[String], manage_only: &[String], name: &str) -> bool {
+    if ignore.iter().any(|p| glob_match(p, name)) {
+        return false;
+    }
+    manage_only.is_empty() || manage_only.iter().any(|p| glob_match(p, name))
+}
+
+/// Matches `name` against a glob `pattern` supporting `*` and `?`.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    // Classic backtracking matcher: remember the last `*` and retry from
+    // there whenever the rest of the pattern fails to match.
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("rds_*", "rds_superuser"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("finance.q?_*", "finance.q2_revenue"));
+        assert!(!glob_match("finance.q?_*", "finance.q10_revenue"));
+        assert!(!glob_match("rds_*", "analyst"));
+        assert!(glob_match("analyst", "analyst"));
+    }
+
+    #[test]
+    fn test_scope() {
+        let scope = Scope::new(
+            ScopeRules {
+                roles: vec!["rds_*".into()],
+                schemas: vec![],
+                objects: vec!["finance.tmp_*".into()],
+            },
+            ScopeRules {
+                roles: vec![],
+                schemas: vec!["finance".into(), "marketing".into()],
+                objects: vec![],
+            },
+        );
+
+        assert!(scope.includes_role("analyst"));
+        assert!(!scope.includes_role("rds_superuser"));
+
+        let table = |schema: &str, name: &str| {
+            DatabaseObject::new(ObjectKind::Table, schema.into(), Some(name.into()))
+        };
+        assert!(scope.includes_object(&table("finance", "q2_revenue")));
+        assert!(!scope.includes_object(&table("finance", "tmp_load")));
+        assert!(!scope.includes_object(&table("reports", "some_report")));
+        assert!(scope.includes_object(&DatabaseObject::new(
+            ObjectKind::Schema,
+            "marketing".into(),
+            None
+        )));
+    }
+}
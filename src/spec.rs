@@ -1,19 +1,57 @@
-use anyhow::{Context, Result};
+use anyhow::Result;
+use chrono::{DateTime, NaiveDate, NaiveTime, SecondsFormat, TimeZone, Utc};
+use log::warn;
+use schemars::JsonSchema;
+use serde::de::value::MapAccessDeserializer;
+use serde::de::{MapAccess, Visitor};
 use serde::{Deserialize, Deserializer, Serialize};
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::{self, Display},
+    marker::PhantomData,
+};
 
 use crate::context::{
-    Attributes, DatabaseObject, DefaultPrivilege, ObjectKind, Privilege, RoleAttribute,
-    RoleMembership,
+    is_predefined_role, Attributes, DatabaseObject, DefaultPrivilege, ObjectKind, Privilege,
+    PrivilegeType, RoleAttribute, RoleMembership, PUBLIC,
 };
+use crate::load::SpecFile;
+use crate::password::PasswordSource;
+use crate::policy::TablePolicies;
+use crate::scope::{Scope, ScopeRules};
+use crate::template::Templates;
 
 pub type RoleSpec = HashMap<String, Role>;
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+#[schemars(deny_unknown_fields)]
+#[serde(try_from = "SpecFile")]
 pub struct DatabaseSpec {
+    #[schemars(schema_with = "crate::schema::version")]
     pub version: u8,
     pub adapter: String,
+    #[serde(skip_serializing_if = "ScopeRules::is_empty")]
+    #[serde(default)]
+    pub ignore: ScopeRules,
+    #[serde(skip_serializing_if = "ScopeRules::is_empty")]
+    #[serde(default)]
+    pub manage_only: ScopeRules,
+    /// Other spec files or directories to merge into this one, see
+    /// [`crate::load`].
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Reusable role definitions, see [`crate::template`].
+    #[serde(skip_serializing_if = "Templates::is_empty")]
+    #[serde(default)]
+    #[schemars(schema_with = "crate::schema::templates")]
+    pub templates: Templates,
+    #[serde(default)]
     pub roles: RoleSpec,
+    /// Row-level security by table, see [`crate::policy`].
+    #[serde(skip_serializing_if = "TablePolicies::is_empty")]
+    #[serde(default)]
+    pub policies: TablePolicies,
 }
 
 impl DatabaseSpec {
@@ -21,20 +59,211 @@ impl DatabaseSpec {
         DatabaseSpec {
             version: 1,
             adapter: adapter.to_string(),
+            ignore: Default::default(),
+            manage_only: Default::default(),
+            include: vec![],
+            templates: Default::default(),
             roles: Default::default(),
+            policies: Default::default(),
+        }
+    }
+
+    /// The roles and objects this spec manages, see [`crate::scope`].
+    pub fn scope(&self) -> Scope {
+        Scope::new(self.ignore.clone(), self.manage_only.clone())
+    }
+
+    /// Checks the spec for mistakes that would make an apply fail halfway,
+    /// reporting all of them at once:
+    ///
+    /// * predefined `pg_*` roles, which cannot be managed;
+    /// * `member_of` entries naming roles the spec does not define, unless
+    ///   they are predefined or outside the managed scope;
+    /// * roles that are members of themselves;
+    /// * membership cycles, e.g. `a -> b -> a`;
+    /// * policies applying to roles the spec does not define, with the same
+    ///   exceptions as `member_of`.
+    pub fn validate(&self) -> Result<()> {
+        let errors: Vec<String> = self
+            .membership_errors()
+            .into_iter()
+            .chain(self.policy_errors())
+            .map(|(_, message)| message)
+            .collect();
+        if !errors.is_empty() {
+            anyhow::bail!("Invalid spec:\n  {}", errors.join("\n  "));
+        }
+        Ok(())
+    }
+
+    /// The problems found by [`DatabaseSpec::validate`], each with the role
+    /// it was found on.
+    pub(crate) fn membership_errors(&self) -> Vec<(String, String)> {
+        let scope = self.scope();
+        let mut errors = vec![];
+        let mut names: Vec<&String> = self.roles.keys().collect();
+        names.sort();
+
+        for name in &names {
+            let mut error = |message: String| errors.push((name.to_string(), message));
+            if is_predefined_role(name) {
+                error(format!(
+                    "{} is a predefined role and cannot be managed, it can only be used in member_of",
+                    name
+                ));
+            }
+            for membership in &self.roles[*name].member_of {
+                let group = &membership.role;
+                if group == *name {
+                    error(format!("{} is a member of itself", name));
+                } else if !self.roles.contains_key(group)
+                    && !is_predefined_role(group)
+                    && scope.includes_role(group)
+                {
+                    error(format!(
+                        "{} is a member of {}, which is not defined in the spec",
+                        name, group
+                    ));
+                }
+            }
+        }
+
+        for cycle in self.membership_cycles(&names) {
+            let message = format!("Membership cycle: {}", cycle.join(" -> "));
+            errors.push((cycle[0].clone(), message));
+        }
+        errors
+    }
+
+    /// The memberships and grants that expire before `until`, each with the
+    /// role it is on, what it grants and when it expires.
+    pub fn expiring(&self, until: DateTime<Utc>) -> Vec<(String, String, DateTime<Utc>)> {
+        let mut entries = vec![];
+        for (name, role) in &self.roles {
+            for membership in &role.member_of {
+                if let Some(expires) = membership.expires.filter(|e| *e < until) {
+                    let what = format!("membership in {}", membership.role);
+                    entries.push((name.clone(), what, expires));
+                }
+            }
+            for (kind, privilege, grant) in role.privileges.grants() {
+                if let Some(expires) = grant.expires.filter(|e| *e < until) {
+                    let what = format!("{} on {} {}", privilege, kind, grant.object);
+                    entries.push((name.clone(), what, expires));
+                }
+            }
+        }
+        entries
+    }
+
+    /// Drops the memberships and grants that have expired by `now`, so they
+    /// are revoked like entries removed from the spec. Returns what was
+    /// dropped, like [`DatabaseSpec::expiring`].
+    pub fn remove_expired(&mut self, now: DateTime<Utc>) -> Vec<(String, String, DateTime<Utc>)> {
+        let expired = self.expiring(now + chrono::Duration::nanoseconds(1));
+        for role in self.roles.values_mut() {
+            role.member_of.retain(|m| !m.is_expired(now));
+            role.privileges.retain(|g| !g.is_expired(now));
+        }
+        expired
+    }
+
+    /// The policies applying to undefined roles, each with the table it is
+    /// on.
+    pub(crate) fn policy_errors(&self) -> Vec<(String, String)> {
+        let scope = self.scope();
+        let mut errors = vec![];
+        for (table, security) in &self.policies {
+            for (name, policy) in &security.policies {
+                for role in &policy.roles {
+                    if role != PUBLIC
+                        && !self.roles.contains_key(role)
+                        && !is_predefined_role(role)
+                        && scope.includes_role(role)
+                    {
+                        errors.push((
+                            table.clone(),
+                            format!(
+                                "Policy {} on {} applies to {}, which is not defined in the spec",
+                                name, table, role
+                            ),
+                        ));
+                    }
+                }
+            }
+        }
+        errors
+    }
+
+    /// Finds cycles between the roles defined in the spec with a depth-first
+    /// search, each reported as the path that closes it.
+    fn membership_cycles(&self, names: &[&String]) -> Vec<Vec<String>> {
+        fn visit<'a>(
+            spec: &'a DatabaseSpec,
+            name: &'a String,
+            stack: &mut Vec<&'a String>,
+            done: &mut HashSet<&'a String>,
+            cycles: &mut Vec<Vec<String>>,
+        ) {
+            if done.contains(&name) {
+                return;
+            }
+            if let Some(start) = stack.iter().position(|n| *n == name) {
+                let mut cycle: Vec<String> = stack[start..].iter().map(|n| n.to_string()).collect();
+                cycle.push(name.clone());
+                cycles.push(cycle);
+                return;
+            }
+            let Some(role) = spec.roles.get(name) else {
+                return;
+            };
+            stack.push(name);
+            let mut groups: Vec<&String> = role
+                .member_of
+                .iter()
+                .map(|m| &m.role)
+                .filter(|group| *group != name)
+                .collect();
+            groups.sort();
+            for group in groups {
+                visit(spec, group, stack, done, cycles);
+            }
+            stack.pop();
+            done.insert(name);
+        }
+
+        let mut cycles = vec![];
+        let mut done = HashSet::new();
+        for name in names {
+            visit(self, name, &mut vec![], &mut done, &mut cycles);
         }
+        cycles
     }
 
+    /// Reads the spec at `path`, which may be a single file, a directory or
+    /// a file with `include`s, see [`crate::load`].
     pub fn read_file(path: &str) -> Result<DatabaseSpec> {
-        let file = std::fs::File::open(path)
-            .with_context(|| format!("Failed to open spec file: {}", path))?;
-        serde_yaml::from_reader(file).map_err(|e| e.into())
+        crate::load::load_spec(std::path::Path::new(path), None)
+    }
+
+    /// Like [`DatabaseSpec::read_file`], merging in the overlays for `env`,
+    /// see [`crate::overlay`].
+    pub fn read_file_for_env(path: &str, env: Option<&str>) -> Result<DatabaseSpec> {
+        crate::load::load_spec(std::path::Path::new(path), env)
     }
 
     pub fn add_role(&mut self, name: &str, role: &impl RoleAttribute) {
         let role = Role {
+            extends: vec![],
             can_login: role.is_enabled(),
             is_superuser: role.get_attributes().contains(&Attributes::Superuser),
+            has_personal_schema: false,
+            password: None,
+            valid_until: role.get_attributes().into_iter().find_map(|a| match a {
+                Attributes::ValidUntil(t) => Some(Some(t)),
+                _ => None,
+            }),
+            settings: Settings::default(),
             member_of: vec![],
             owns: Ownership::new(),
             privileges: Privileges::new(),
@@ -42,13 +271,40 @@ impl DatabaseSpec {
         self.roles.insert(name.to_string(), role);
     }
 
+    /// Adds the PUBLIC pseudo-role. It cannot log in or be a member of other
+    /// roles, it only holds privileges.
+    pub fn add_public(&mut self) {
+        let role = Role {
+            extends: vec![],
+            can_login: false,
+            is_superuser: false,
+            has_personal_schema: false,
+            password: None,
+            valid_until: None,
+            settings: Settings::default(),
+            member_of: vec![],
+            owns: Ownership::new(),
+            privileges: Privileges::new(),
+        };
+        self.roles.insert(PUBLIC.to_string(), role);
+    }
+
     pub fn add_memberships(&mut self, name: &str, memberships: &RoleMembership) {
         let role = self.roles.get_mut(name).unwrap();
         memberships.memberships.iter().for_each(|m| {
-            role.member_of.push(m.to_string());
+            role.member_of.push(Membership {
+                role: m.to_string(),
+                with_admin_option: memberships.is_admin_of(m),
+                expires: None,
+            });
         });
     }
 
+    pub fn add_settings(&mut self, name: &str, settings: &Settings) {
+        let role = self.roles.get_mut(name).unwrap();
+        role.settings = settings.clone();
+    }
+
     pub fn add_ownerships(&mut self, name: &str, ownership: &[DatabaseObject]) {
         let role = self.roles.get_mut(name).unwrap();
         ownership.iter().for_each(|o| match o.kind {
@@ -61,78 +317,202 @@ impl DatabaseSpec {
             ObjectKind::Sequence => {
                 role.owns.sequences.push(o.fqn());
             }
+            ObjectKind::Function => {
+                role.owns.functions.push(o.fqn());
+            }
             _ => panic!("Unknown object kind: {}", o.kind),
         });
     }
 
     pub fn add_privileges(&mut self, name: &str, privileges: &[Privilege]) {
         let role = self.roles.get_mut(name).unwrap();
-        privileges.iter().for_each(|p| match p.object.kind {
-            // TODO: Abstract this out. Maybe each ObjectKind has a
-            // from_privilege(privilege) method? That way each object
-            // owns its own Read/Write definitions
-            ObjectKind::Schema => {
-                if p.privs.contains(&crate::context::PrivilegeType::Write) {
-                    role.privileges.schemas.write.push(p.object.fqn());
-                }
-                if p.privs.contains(&crate::context::PrivilegeType::Read) {
-                    role.privileges.schemas.read.push(p.object.fqn());
-                }
+        privileges.iter().for_each(|p| {
+            let list: &mut dyn ObjectPrivileges = match p.object.kind {
+                ObjectKind::Schema => &mut role.privileges.schemas,
+                ObjectKind::Table => &mut role.privileges.tables,
+                ObjectKind::Sequence => &mut role.privileges.sequences,
+                ObjectKind::Function => &mut role.privileges.functions,
+                _ => panic!("Unknown object kind: {}", p.object.kind),
+            };
+            if p.privs.contains(&PrivilegeType::Write) {
+                list.write_mut().push(Grant {
+                    object: p.object.fqn(),
+                    with_grant_option: p.grantable.contains(&PrivilegeType::Write),
+                    expires: None,
+                });
             }
-            ObjectKind::Table => {
-                if p.privs.contains(&crate::context::PrivilegeType::Write) {
-                    role.privileges.tables.write.push(p.object.fqn());
-                }
-                if p.privs.contains(&crate::context::PrivilegeType::Read) {
-                    role.privileges.tables.read.push(p.object.fqn());
-                }
-            }
-            ObjectKind::Sequence => {
-                if p.privs.contains(&crate::context::PrivilegeType::Write) {
-                    role.privileges.sequences.write.push(p.object.fqn());
-                }
-                if p.privs.contains(&crate::context::PrivilegeType::Read) {
-                    role.privileges.sequences.read.push(p.object.fqn());
-                }
+            if p.privs.contains(&PrivilegeType::Read) {
+                list.read_mut().push(Grant {
+                    object: p.object.fqn(),
+                    with_grant_option: p.grantable.contains(&PrivilegeType::Read),
+                    expires: None,
+                });
             }
-            _ => panic!("Unknown object kind: {}", p.object.kind),
         });
     }
 
-    pub fn add_defaults(&mut self, name: &str, defaults: &[DefaultPrivilege]) {
-        let role = self.roles.get_mut(name).unwrap();
+    pub fn add_defaults(&mut self, name: &str, _defaults: &[DefaultPrivilege]) {
+        let _role = self.roles.get_mut(name).unwrap();
     }
 }
 
-fn deserialize_bool<'de, D>(deserializer: D) -> Result<bool, D::Error>
+/// Accepts YAML 1.1 style `yes`/`no` as well as real booleans, which is what
+/// we emit when serializing a spec.
+pub(crate) fn deserialize_bool<'de, D>(deserializer: D) -> Result<bool, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let s = String::deserialize(deserializer)?;
-    match s.as_ref() {
-        "yes" => Ok(true),
-        "no" => Ok(false),
-        "true" => Ok(true),
-        "false" => Ok(false),
-        _ => Err(serde::de::Error::custom("expected yes or no")),
+    struct BoolVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for BoolVisitor {
+        type Value = bool;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("yes or no")
+        }
+
+        fn visit_bool<E: serde::de::Error>(self, v: bool) -> Result<bool, E> {
+            Ok(v)
+        }
+
+        fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<bool, E> {
+            match v {
+                "yes" => Ok(true),
+                "no" => Ok(false),
+                "true" => Ok(true),
+                "false" => Ok(false),
+                _ => Err(E::invalid_value(serde::de::Unexpected::Str(v), &self)),
+            }
+        }
     }
+
+    deserializer.deserialize_any(BoolVisitor)
 }
 
-fn yes() -> bool {
+pub(crate) fn yes() -> bool {
     true
 }
 
-#[derive(Debug, Deserialize, Serialize, Eq, PartialEq)]
+/// Parses a date, taken as midnight UTC, or an RFC 3339 timestamp.
+/// `infinity`, as Postgres writes it, is no timestamp at all.
+pub fn parse_timestamp(text: &str) -> Result<Option<DateTime<Utc>>> {
+    if text == "infinity" {
+        return Ok(None);
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+        return Ok(Some(Utc.from_utc_datetime(&date.and_time(NaiveTime::MIN))));
+    }
+    match DateTime::parse_from_rfc3339(text) {
+        Ok(timestamp) => Ok(Some(timestamp.with_timezone(&Utc))),
+        Err(_) => anyhow::bail!(
+            "Invalid timestamp `{}`, expected a date such as 2025-06-30 or a timestamp such as 2025-06-30T18:00:00Z",
+            text
+        ),
+    }
+}
+
+pub(crate) fn deserialize_timestamp<'de, D>(
+    deserializer: D,
+) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let text = String::deserialize(deserializer)?;
+    parse_timestamp(&text).map_err(serde::de::Error::custom)
+}
+
+/// Like [`deserialize_timestamp`], for fields where being left out means
+/// something else than `infinity`.
+pub(crate) fn deserialize_explicit_timestamp<'de, D>(
+    deserializer: D,
+) -> Result<Option<Option<DateTime<Utc>>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_timestamp(deserializer).map(Some)
+}
+
+/// A timestamp the way a spec would have it, as a date when it is at
+/// midnight UTC.
+pub fn format_timestamp(timestamp: DateTime<Utc>) -> String {
+    if timestamp.time() == NaiveTime::MIN {
+        timestamp.format("%Y-%m-%d").to_string()
+    } else {
+        timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+    }
+}
+
+pub(crate) fn serialize_timestamp<S>(
+    timestamp: &Option<DateTime<Utc>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match timestamp {
+        Some(t) => serializer.serialize_str(&format_timestamp(*t)),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub(crate) fn serialize_explicit_timestamp<S>(
+    timestamp: &Option<Option<DateTime<Utc>>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match timestamp {
+        Some(Some(t)) => serializer.serialize_str(&format_timestamp(*t)),
+        Some(None) => serializer.serialize_str("infinity"),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize, Eq, PartialEq)]
+#[schemars(deny_unknown_fields)]
 pub struct Role {
+    /// The templates this role is based on, already merged into it
+    #[serde(deserialize_with = "crate::template::deserialize_extends")]
+    #[serde(serialize_with = "crate::template::serialize_extends")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    #[schemars(schema_with = "crate::schema::extends")]
+    pub extends: Vec<String>,
     #[serde(deserialize_with = "crate::spec::deserialize_bool")]
+    #[schemars(schema_with = "crate::schema::yes_no")]
     #[serde(default = "yes")]
     pub can_login: bool,
     #[serde(deserialize_with = "crate::spec::deserialize_bool")]
+    #[schemars(schema_with = "crate::schema::yes_no")]
     #[serde(default)]
     pub is_superuser: bool,
+    /// Whether the role has a schema of its own, named after it. Objects in
+    /// it belong to the role and are not listed in the spec.
+    #[serde(deserialize_with = "crate::spec::deserialize_bool")]
+    #[schemars(schema_with = "crate::schema::yes_no")]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    #[serde(default)]
+    pub has_personal_schema: bool,
+    /// Where to read the role's password from, see [`crate::password`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub password: Option<PasswordSource>,
+    /// When the role's password stops working, e.g. `2025-06-30` or
+    /// `2025-06-30T18:00:00Z`, or `Some(None)` for `infinity`, which clears
+    /// it. Whatever the role has is left alone when left out.
+    #[serde(deserialize_with = "crate::spec::deserialize_explicit_timestamp")]
+    #[serde(serialize_with = "crate::spec::serialize_explicit_timestamp")]
+    #[schemars(schema_with = "crate::schema::timestamp")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub valid_until: Option<Option<DateTime<Utc>>>,
+    #[serde(skip_serializing_if = "Settings::is_empty")]
+    #[serde(default)]
+    pub settings: Settings,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(default)]
-    pub member_of: Vec<String>,
+    pub member_of: Vec<Membership>,
     #[serde(default)]
     #[serde(skip_serializing_if = "Ownership::is_empty")]
     pub owns: Ownership,
@@ -141,7 +521,390 @@ pub struct Role {
     pub privileges: Privileges,
 }
 
-#[derive(Debug, Deserialize, Serialize, Eq, PartialEq, Ord, PartialOrd)]
+/// The same role as an empty entry in a spec.
+impl Default for Role {
+    fn default() -> Self {
+        Role {
+            extends: vec![],
+            can_login: true,
+            is_superuser: false,
+            has_personal_schema: false,
+            password: None,
+            valid_until: None,
+            settings: Settings::default(),
+            member_of: vec![],
+            owns: Ownership::new(),
+            privileges: Privileges::new(),
+        }
+    }
+}
+
+impl Role {
+    /// Whether the spec grants membership in `role` WITH ADMIN OPTION.
+    pub fn is_admin_of(&self, role: &str) -> bool {
+        self.member_of
+            .iter()
+            .any(|m| m.role == role && m.with_admin_option)
+    }
+}
+
+/// Configuration parameters set on a role, applied when it logs in. Settings
+/// can apply in every database, or only in the databases listed under
+/// `databases`:
+///
+/// ```yaml
+/// settings:
+///   statement_timeout: 5min
+///   search_path: $user, public
+///   databases:
+///     warehouse:
+///       work_mem: 256MB
+/// ```
+#[derive(Debug, Default, Clone, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(from = "SettingsEntry", into = "SettingsEntry")]
+pub struct Settings {
+    pub all: BTreeMap<String, String>,
+    /// Settings by database
+    pub databases: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl Settings {
+    pub fn is_empty(&self) -> bool {
+        self.all.is_empty() && self.databases.is_empty()
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Serialize)]
+struct SettingsEntry {
+    #[serde(flatten)]
+    all: BTreeMap<String, SettingValue>,
+    /// Settings that only apply in one database, by database
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    #[serde(default)]
+    databases: BTreeMap<String, BTreeMap<String, SettingValue>>,
+}
+
+/// Values are passed to Postgres as text, but need not be quoted in YAML,
+/// e.g. `statement_timeout: 30000`.
+#[derive(Deserialize, JsonSchema, Serialize)]
+#[serde(untagged)]
+enum SettingValue {
+    Text(String),
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl From<SettingValue> for String {
+    fn from(value: SettingValue) -> Self {
+        match value {
+            SettingValue::Text(text) => text,
+            SettingValue::Integer(n) => n.to_string(),
+            SettingValue::Float(n) => n.to_string(),
+            SettingValue::Bool(b) => b.to_string(),
+        }
+    }
+}
+
+fn setting_values(values: BTreeMap<String, SettingValue>) -> BTreeMap<String, String> {
+    values.into_iter().map(|(k, v)| (k, v.into())).collect()
+}
+
+impl From<SettingsEntry> for Settings {
+    fn from(entry: SettingsEntry) -> Self {
+        Settings {
+            all: setting_values(entry.all),
+            databases: entry
+                .databases
+                .into_iter()
+                .map(|(database, values)| (database, setting_values(values)))
+                .collect(),
+        }
+    }
+}
+
+impl From<Settings> for SettingsEntry {
+    fn from(settings: Settings) -> Self {
+        let text = |values: BTreeMap<String, String>| {
+            values
+                .into_iter()
+                .map(|(k, v)| (k, SettingValue::Text(v)))
+                .collect()
+        };
+        SettingsEntry {
+            databases: settings
+                .databases
+                .into_iter()
+                .map(|(database, values)| (database, text(values)))
+                .collect(),
+            all: text(settings.all),
+        }
+    }
+}
+
+impl JsonSchema for Settings {
+    fn schema_name() -> String {
+        "Settings".to_string()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        SettingsEntry::json_schema(gen)
+    }
+}
+
+/// A role this role is a member of. Usually written as the bare role name,
+/// but can be written as a map to grant the membership WITH ADMIN OPTION,
+/// or only until it `expires`:
+///
+/// ```yaml
+/// member_of:
+///   - analyst
+///   - role: engineer
+///     with_admin_option: yes
+///   - role: finance_writer
+///     expires: 2025-06-30T18:00:00Z
+/// ```
+#[derive(Debug, Clone, Deserialize, Serialize, Eq, PartialEq, Hash, Ord, PartialOrd)]
+#[serde(from = "Entry<MembershipOptions>", into = "Entry<MembershipOptions>")]
+pub struct Membership {
+    pub role: String,
+    pub with_admin_option: bool,
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl Membership {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+impl From<&str> for Membership {
+    fn from(role: &str) -> Self {
+        Membership {
+            role: role.to_string(),
+            with_admin_option: false,
+            expires: None,
+        }
+    }
+}
+
+impl Display for Membership {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.role)?;
+        if self.with_admin_option {
+            f.write_str(" (with admin option)")?;
+        }
+        if let Some(expires) = self.expires {
+            write!(f, " (expires {})", format_timestamp(expires))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Serialize)]
+#[schemars(deny_unknown_fields)]
+struct MembershipOptions {
+    role: String,
+    #[serde(deserialize_with = "crate::spec::deserialize_bool")]
+    #[schemars(schema_with = "crate::schema::yes_no")]
+    #[serde(default)]
+    with_admin_option: bool,
+    #[serde(deserialize_with = "crate::spec::deserialize_timestamp")]
+    #[serde(serialize_with = "crate::spec::serialize_timestamp")]
+    #[schemars(schema_with = "crate::schema::timestamp")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    expires: Option<DateTime<Utc>>,
+}
+
+impl JsonSchema for Membership {
+    fn schema_name() -> String {
+        "Membership".to_string()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        crate::schema::name_or::<MembershipOptions>(gen)
+    }
+}
+
+impl From<Entry<MembershipOptions>> for Membership {
+    fn from(entry: Entry<MembershipOptions>) -> Self {
+        match entry {
+            Entry::Name(role) => Membership::from(role.as_str()),
+            Entry::Options(MembershipOptions {
+                role,
+                with_admin_option,
+                expires,
+            }) => Membership {
+                role,
+                with_admin_option,
+                expires,
+            },
+        }
+    }
+}
+
+impl From<Membership> for Entry<MembershipOptions> {
+    fn from(membership: Membership) -> Self {
+        if membership.with_admin_option || membership.expires.is_some() {
+            Entry::Options(MembershipOptions {
+                role: membership.role,
+                with_admin_option: membership.with_admin_option,
+                expires: membership.expires,
+            })
+        } else {
+            Entry::Name(membership.role)
+        }
+    }
+}
+
+/// An object listed under a privilege. Usually written as the bare object
+/// name, but can be written as a map to grant the privilege WITH GRANT
+/// OPTION, or only until it `expires`:
+///
+/// ```yaml
+/// read:
+///   - finance.q2_revenue
+///   - object: finance.q2_margin
+///     with_grant_option: yes
+/// write:
+///   - object: finance.*
+///     expires: 2025-06-30
+/// ```
+#[derive(Debug, Clone, Deserialize, Serialize, Eq, PartialEq, Hash, Ord, PartialOrd)]
+#[serde(from = "Entry<GrantOptions>", into = "Entry<GrantOptions>")]
+pub struct Grant {
+    pub object: String,
+    pub with_grant_option: bool,
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl Grant {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+impl From<&str> for Grant {
+    fn from(object: &str) -> Self {
+        Grant {
+            object: object.to_string(),
+            with_grant_option: false,
+            expires: None,
+        }
+    }
+}
+
+impl Display for Grant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.object)?;
+        if self.with_grant_option {
+            f.write_str(" (with grant option)")?;
+        }
+        if let Some(expires) = self.expires {
+            write!(f, " (expires {})", format_timestamp(expires))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Serialize)]
+#[schemars(deny_unknown_fields)]
+struct GrantOptions {
+    object: String,
+    #[serde(deserialize_with = "crate::spec::deserialize_bool")]
+    #[schemars(schema_with = "crate::schema::yes_no")]
+    #[serde(default)]
+    with_grant_option: bool,
+    #[serde(deserialize_with = "crate::spec::deserialize_timestamp")]
+    #[serde(serialize_with = "crate::spec::serialize_timestamp")]
+    #[schemars(schema_with = "crate::schema::timestamp")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    expires: Option<DateTime<Utc>>,
+}
+
+impl JsonSchema for Grant {
+    fn schema_name() -> String {
+        "Grant".to_string()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        crate::schema::name_or::<GrantOptions>(gen)
+    }
+}
+
+impl From<Entry<GrantOptions>> for Grant {
+    fn from(entry: Entry<GrantOptions>) -> Self {
+        match entry {
+            Entry::Name(object) => Grant::from(object.as_str()),
+            Entry::Options(GrantOptions {
+                object,
+                with_grant_option,
+                expires,
+            }) => Grant {
+                object,
+                with_grant_option,
+                expires,
+            },
+        }
+    }
+}
+
+impl From<Grant> for Entry<GrantOptions> {
+    fn from(grant: Grant) -> Self {
+        if grant.with_grant_option || grant.expires.is_some() {
+            Entry::Options(GrantOptions {
+                object: grant.object,
+                with_grant_option: grant.with_grant_option,
+                expires: grant.expires,
+            })
+        } else {
+            Entry::Name(grant.object)
+        }
+    }
+}
+
+/// A list entry written either as a bare name or as a map of options, see
+/// [`Membership`] and [`Grant`]. Unlike `#[serde(untagged)]`, a map that
+/// fails to deserialize reports its own error, e.g. a bad `expires`, rather
+/// than "data did not match any variant".
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Entry<T> {
+    Name(String),
+    Options(T),
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Entry<T> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct EntryVisitor<T>(PhantomData<T>);
+
+        impl<'de, T: Deserialize<'de>> Visitor<'de> for EntryVisitor<T> {
+            type Value = Entry<T>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a name or a map")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<Self::Value, E> {
+                Ok(Entry::Name(value.to_string()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                T::deserialize(MapAccessDeserializer::new(map)).map(Entry::Options)
+            }
+        }
+
+        deserializer.deserialize_any(EntryVisitor(PhantomData))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize, Eq, PartialEq, Ord, PartialOrd)]
+#[schemars(deny_unknown_fields)]
 pub struct Ownership {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(default)]
@@ -152,6 +915,9 @@ pub struct Ownership {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(default)]
     pub sequences: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub functions: Vec<String>,
 }
 
 impl Default for Ownership {
@@ -172,6 +938,9 @@ impl Display for Ownership {
         if !self.sequences.is_empty() {
             s.push_str(&format!("SQ: {}. ", self.sequences.join(", ")));
         }
+        if !self.functions.is_empty() {
+            s.push_str(&format!("FN: {}. ", self.functions.join(", ")));
+        }
         write!(f, "{}", s)
     }
 }
@@ -181,11 +950,13 @@ impl Ownership {
             schemas: vec![],
             tables: vec![],
             sequences: vec![],
+            functions: vec![],
         }
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, Eq, PartialEq)]
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize, Eq, PartialEq)]
+#[schemars(deny_unknown_fields)]
 pub struct Privileges {
     #[serde(skip_serializing_if = "SchemaPrivileges::is_empty")]
     #[serde(default)]
@@ -196,6 +967,9 @@ pub struct Privileges {
     #[serde(skip_serializing_if = "SequencePrivileges::is_empty")]
     #[serde(default)]
     pub sequences: SequencePrivileges,
+    #[serde(skip_serializing_if = "FunctionPrivileges::is_empty")]
+    #[serde(default)]
+    pub functions: FunctionPrivileges,
 }
 
 impl Default for Privileges {
@@ -205,6 +979,75 @@ impl Default for Privileges {
 }
 
 impl Privileges {
+    /// Every grant, with the kind of object and the privilege it is for.
+    pub fn grants(&self) -> Vec<(ObjectKind, PrivilegeType, &Grant)> {
+        let lists: [(ObjectKind, &dyn ObjectPrivileges); 4] = [
+            (ObjectKind::Schema, &self.schemas),
+            (ObjectKind::Table, &self.tables),
+            (ObjectKind::Sequence, &self.sequences),
+            (ObjectKind::Function, &self.functions),
+        ];
+        let mut grants = vec![];
+        for (kind, list) in lists {
+            for grant in list.read() {
+                grants.push((kind, PrivilegeType::Read, grant));
+            }
+            for grant in list.write() {
+                grants.push((kind, PrivilegeType::Write, grant));
+            }
+        }
+        grants
+    }
+
+    /// Keeps only the grants for which `keep` is true.
+    pub fn retain(&mut self, mut keep: impl FnMut(&Grant) -> bool) {
+        let lists: [&mut dyn ObjectPrivileges; 4] = [
+            &mut self.schemas,
+            &mut self.tables,
+            &mut self.sequences,
+            &mut self.functions,
+        ];
+        for list in lists {
+            list.read_mut().retain(&mut keep);
+            list.write_mut().retain(&mut keep);
+        }
+    }
+
+    /// Resolves the privileges in the spec against the objects that exist in
+    /// the database. Wildcards such as `finance.*` expand to every object of
+    /// that kind in the schema. Objects that do not exist are kept as-is so
+    /// that the resulting GRANT surfaces the problem.
+    pub fn resolve(&self, objects: &[DatabaseObject]) -> Vec<Privilege> {
+        let mut resolved: BTreeMap<DatabaseObject, Privilege> = BTreeMap::new();
+        let lists: [(ObjectKind, &dyn ObjectPrivileges); 4] = [
+            (ObjectKind::Schema, &self.schemas),
+            (ObjectKind::Table, &self.tables),
+            (ObjectKind::Sequence, &self.sequences),
+            (ObjectKind::Function, &self.functions),
+        ];
+
+        for (kind, list) in lists {
+            for (privilege, grants) in [
+                (PrivilegeType::Read, list.read()),
+                (PrivilegeType::Write, list.write()),
+            ] {
+                for grant in grants {
+                    for object in resolve_object(kind, &grant.object, objects) {
+                        let entry = resolved
+                            .entry(object.clone())
+                            .or_insert_with(|| Privilege::new(object, vec![]));
+                        entry.privs.insert(privilege);
+                        if grant.with_grant_option {
+                            entry.grantable.insert(privilege);
+                        }
+                    }
+                }
+            }
+        }
+
+        resolved.into_values().collect()
+    }
+
     pub fn new() -> Self {
         Privileges {
             schemas: SchemaPrivileges {
@@ -219,43 +1062,169 @@ impl Privileges {
                 read: vec![],
                 write: vec![],
             },
+            functions: FunctionPrivileges {
+                read: vec![],
+                write: vec![],
+            },
         }
     }
 }
 
+/// Finds the objects of `kind` that a spec entry refers to.
+pub(crate) fn resolve_object(
+    kind: ObjectKind,
+    name: &str,
+    objects: &[DatabaseObject],
+) -> Vec<DatabaseObject> {
+    let same_kind = |o: &&DatabaseObject| match kind {
+        ObjectKind::Table => matches!(o.kind, ObjectKind::Table | ObjectKind::View),
+        _ => o.kind == kind,
+    };
+
+    if let Some(schema) = name.strip_suffix(".*") {
+        return objects
+            .iter()
+            .filter(same_kind)
+            .filter(|o| o.schema == schema && o.unqualified_name.is_some())
+            .cloned()
+            .collect();
+    }
+
+    match objects.iter().filter(same_kind).find(|o| o.fqn() == name) {
+        Some(object) => vec![object.clone()],
+        None => match name.split_once('.') {
+            _ if kind == ObjectKind::Schema => {
+                vec![DatabaseObject::new(kind, name.to_string(), None)]
+            }
+            Some((schema, table)) => vec![DatabaseObject::new(
+                kind,
+                schema.to_string(),
+                Some(table.to_string()),
+            )],
+            // Reported by `validate`, e.g. a schema listed under `tables`
+            None => {
+                warn!(
+                    "Ignoring {} `{}`, expected schema.name or schema.*",
+                    kind, name
+                );
+                vec![]
+            }
+        },
+    }
+}
+
 trait ObjectPrivileges {
-    fn read(&self) -> &Vec<String>;
-    fn write(&self) -> &Vec<String>;
+    fn read(&self) -> &Vec<Grant>;
+    fn write(&self) -> &Vec<Grant>;
+    fn read_mut(&mut self) -> &mut Vec<Grant>;
+    fn write_mut(&mut self) -> &mut Vec<Grant>;
 }
 
-#[derive(Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize, Eq, PartialEq)]
+#[schemars(deny_unknown_fields)]
 pub struct SchemaPrivileges {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(default)]
-    pub read: Vec<String>,
+    pub read: Vec<Grant>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(default)]
-    pub write: Vec<String>,
+    pub write: Vec<Grant>,
 }
 
-#[derive(Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize, Eq, PartialEq)]
+#[schemars(deny_unknown_fields)]
 pub struct TablePrivileges {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(default)]
-    pub read: Vec<String>,
+    pub read: Vec<Grant>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(default)]
-    pub write: Vec<String>,
+    pub write: Vec<Grant>,
 }
 
-#[derive(Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize, Eq, PartialEq)]
+#[schemars(deny_unknown_fields)]
 pub struct SequencePrivileges {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(default)]
-    pub read: Vec<String>,
+    pub read: Vec<Grant>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(default)]
-    pub write: Vec<String>,
+    pub write: Vec<Grant>,
+}
+
+/// Functions only support `read`, which grants EXECUTE.
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize, Eq, PartialEq)]
+#[schemars(deny_unknown_fields)]
+pub struct FunctionPrivileges {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub read: Vec<Grant>,
+    /// Always empty, there is no privilege to write a function and
+    /// `validate` rejects it
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub write: Vec<Grant>,
+}
+
+impl ObjectPrivileges for SchemaPrivileges {
+    fn read(&self) -> &Vec<Grant> {
+        &self.read
+    }
+    fn write(&self) -> &Vec<Grant> {
+        &self.write
+    }
+    fn read_mut(&mut self) -> &mut Vec<Grant> {
+        &mut self.read
+    }
+    fn write_mut(&mut self) -> &mut Vec<Grant> {
+        &mut self.write
+    }
+}
+
+impl ObjectPrivileges for TablePrivileges {
+    fn read(&self) -> &Vec<Grant> {
+        &self.read
+    }
+    fn write(&self) -> &Vec<Grant> {
+        &self.write
+    }
+    fn read_mut(&mut self) -> &mut Vec<Grant> {
+        &mut self.read
+    }
+    fn write_mut(&mut self) -> &mut Vec<Grant> {
+        &mut self.write
+    }
+}
+
+impl ObjectPrivileges for SequencePrivileges {
+    fn read(&self) -> &Vec<Grant> {
+        &self.read
+    }
+    fn write(&self) -> &Vec<Grant> {
+        &self.write
+    }
+    fn read_mut(&mut self) -> &mut Vec<Grant> {
+        &mut self.read
+    }
+    fn write_mut(&mut self) -> &mut Vec<Grant> {
+        &mut self.write
+    }
+}
+
+impl ObjectPrivileges for FunctionPrivileges {
+    fn read(&self) -> &Vec<Grant> {
+        &self.read
+    }
+    fn write(&self) -> &Vec<Grant> {
+        &self.write
+    }
+    fn read_mut(&mut self) -> &mut Vec<Grant> {
+        &mut self.read
+    }
+    fn write_mut(&mut self) -> &mut Vec<Grant> {
+        &mut self.write
+    }
 }
 
 pub trait IsEmpty {
@@ -280,15 +1249,27 @@ impl IsEmpty for SequencePrivileges {
     }
 }
 
+impl IsEmpty for FunctionPrivileges {
+    fn is_empty(&self) -> bool {
+        self.read.is_empty() && self.write.is_empty()
+    }
+}
+
 impl IsEmpty for Ownership {
     fn is_empty(&self) -> bool {
-        self.schemas.is_empty() && self.tables.is_empty() && self.sequences.is_empty()
+        self.schemas.is_empty()
+            && self.tables.is_empty()
+            && self.sequences.is_empty()
+            && self.functions.is_empty()
     }
 }
 
 impl IsEmpty for Privileges {
     fn is_empty(&self) -> bool {
-        self.schemas.is_empty() && self.tables.is_empty() && self.sequences.is_empty()
+        self.schemas.is_empty()
+            && self.tables.is_empty()
+            && self.sequences.is_empty()
+            && self.functions.is_empty()
     }
 }
 
@@ -297,3 +1278,224 @@ impl DatabaseSpec {
         serde_yaml::to_string(&self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_member_of_with_admin_option() {
+        let role: Role = serde_yaml::from_str(
+            "
+            member_of:
+              - analyst
+              - role: engineer
+                with_admin_option: yes
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            role.member_of,
+            vec![
+                Membership::from("analyst"),
+                Membership {
+                    role: "engineer".into(),
+                    with_admin_option: true,
+                    expires: None,
+                },
+            ]
+        );
+        assert!(role.is_admin_of("engineer"));
+        assert!(!role.is_admin_of("analyst"));
+    }
+
+    #[test]
+    fn test_validate_reports_membership_errors() {
+        let spec: DatabaseSpec = serde_yaml::from_str(
+            "
+            version: 1
+            adapter: postgres
+            ignore:
+              roles:
+                - rds_*
+            roles:
+              a:
+                member_of:
+                  - b
+                  - rds_admin
+                  - pg_monitor
+              b:
+                member_of:
+                  - c
+              c:
+                member_of:
+                  - a
+                  - c
+                  - ghost
+            ",
+        )
+        .unwrap();
+
+        let error = spec.validate().unwrap_err().to_string();
+
+        assert_eq!(
+            error,
+            "Invalid spec:
+  c is a member of itself
+  c is a member of ghost, which is not defined in the spec
+  Membership cycle: a -> b -> c -> a"
+        );
+    }
+
+    #[test]
+    fn test_validate_accepts_resources_spec() {
+        DatabaseSpec::read_file("resources/spec.yml")
+            .unwrap()
+            .validate()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_remove_expired() {
+        let mut spec: DatabaseSpec = serde_yaml::from_str(
+            "
+            version: 1
+            adapter: postgres
+            roles:
+              analyst: {}
+              bob:
+                member_of:
+                  - role: analyst
+                    expires: 2025-06-30
+                privileges:
+                  tables:
+                    write:
+                      - object: finance.*
+                        expires: 2025-06-27T18:00:00Z
+                      - finance.q2_results
+            ",
+        )
+        .unwrap();
+        let now = Utc.with_ymd_and_hms(2025, 6, 28, 9, 0, 0).unwrap();
+
+        let expired = spec.remove_expired(now);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].1, "write on table finance.*");
+        let bob = &spec.roles["bob"];
+        assert_eq!(
+            bob.privileges.tables.write,
+            vec![Grant::from("finance.q2_results")]
+        );
+        assert_eq!(
+            format!("{}", bob.member_of[0]),
+            "analyst (expires 2025-06-30)"
+        );
+
+        let serialized = serde_yaml::to_string(bob).unwrap();
+        assert!(serialized.contains("expires: 2025-06-30\n"));
+        assert_eq!(&serde_yaml::from_str::<Role>(&serialized).unwrap(), bob);
+
+        let expiring = spec.expiring(now + chrono::Duration::days(7));
+        assert_eq!(expiring[0].1, "membership in analyst");
+    }
+
+    #[test]
+    fn test_bad_expires_reports_timestamp_error() {
+        let expected = "Invalid timestamp `2025-13-01`, expected a date such as 2025-06-30";
+        let membership = serde_yaml::from_str::<Role>(
+            "
+            member_of:
+              - role: analyst
+                expires: 2025-13-01
+            ",
+        )
+        .unwrap_err();
+        assert!(membership.to_string().contains(expected), "{}", membership);
+
+        let grant = serde_yaml::from_str::<Role>(
+            "
+            privileges:
+              tables:
+                read:
+                  - object: finance.*
+                    expires: 2025-13-01
+            ",
+        )
+        .unwrap_err();
+        assert!(grant.to_string().contains(expected), "{}", grant);
+    }
+
+    #[test]
+    fn test_grants_round_trip() {
+        let yaml = "
+            can_login: true
+            is_superuser: false
+            privileges:
+              tables:
+                read:
+                  - finance.q2_revenue
+                  - object: finance.q2_margin
+                    with_grant_option: true
+            ";
+        let role: Role = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            role.privileges.tables.read[1],
+            Grant {
+                object: "finance.q2_margin".into(),
+                with_grant_option: true,
+                expires: None,
+            }
+        );
+
+        let serialized = serde_yaml::to_string(&role).unwrap();
+        assert!(serialized.contains("- finance.q2_revenue\n"));
+        assert_eq!(serde_yaml::from_str::<Role>(&serialized).unwrap(), role);
+    }
+
+    #[test]
+    fn test_resolve_privileges_expands_wildcards() {
+        let role: Role = serde_yaml::from_str(
+            "
+            privileges:
+              tables:
+                read:
+                  - finance.*
+                  - finance
+                write:
+                  - object: finance.q2_margin
+                    with_grant_option: yes
+            ",
+        )
+        .unwrap();
+        let objects = vec![
+            DatabaseObject::new(ObjectKind::Schema, "finance".into(), None),
+            DatabaseObject::new(
+                ObjectKind::Table,
+                "finance".into(),
+                Some("q2_margin".into()),
+            ),
+            DatabaseObject::new(
+                ObjectKind::View,
+                "finance".into(),
+                Some("q2_summary".into()),
+            ),
+            DatabaseObject::new(
+                ObjectKind::Table,
+                "reports".into(),
+                Some("q2_report".into()),
+            ),
+        ];
+
+        let resolved = role.privileges.resolve(&objects);
+
+        assert_eq!(resolved.len(), 2);
+        assert_eq!(resolved[0].object.fqn(), "finance.q2_margin");
+        assert_eq!(
+            resolved[0].privs,
+            [PrivilegeType::Read, PrivilegeType::Write].into()
+        );
+        assert_eq!(resolved[0].grantable, [PrivilegeType::Write].into());
+        assert_eq!(resolved[1].object.fqn(), "finance.q2_summary");
+    }
+}
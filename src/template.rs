@@ -0,0 +1,396 @@
+//! Role templates.
+//!
+//! Roles that are nearly identical can share a template:
+//!
+//! ```yaml
+//! templates:
+//!   analyst_base:
+//!     can_login: yes
+//!     member_of:
+//!       - analyst
+//!     privileges:
+//!       schemas:
+//!         read:
+//!           - finance
+//! roles:
+//!   jdoe:
+//!     extends: analyst_base
+//!     privileges:
+//!       schemas:
+//!         read:
+//!           - marketing
+//! ```
+//!
+//! Templates are merged into each role when the spec is loaded:
+//!
+//! * values set on the role win over values from its templates;
+//! * with several templates, e.g. `extends: [a, b]`, later ones win over
+//!   earlier ones;
+//! * templates can extend other templates, with the same rules;
+//! * lists such as `member_of` or grants are combined, keeping each entry
+//!   once: an entry for the same role or object, e.g. `role: analyst` with
+//!   `with_admin_option: yes`, replaces the template's;
+//! * maps such as `privileges` are merged key by key.
+//!
+//! [`detect_templates`] goes the other way and factors out what roles have
+//! in common, which `generate --detect-templates` uses.
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_yaml::{Mapping, Value};
+
+use crate::context::PUBLIC;
+use crate::spec::{DatabaseSpec, Ownership, Privileges, Role, RoleSpec};
+
+pub type Templates = BTreeMap<String, Value>;
+
+const EXTENDS: &str = "extends";
+
+/// Merges the templates into `roles`, reporting every unknown template,
+/// template cycle and invalid role at once.
+pub(crate) fn expand_roles(
+    templates: &Templates,
+    roles: HashMap<String, Value>,
+) -> Result<RoleSpec, String> {
+    let mut names: Vec<String> = roles.keys().cloned().collect();
+    names.sort();
+    let mut spec = RoleSpec::new();
+    let mut errors = vec![];
+    for name in names {
+        let role = resolve(
+            templates,
+            &roles[&name],
+            &format!("role {}", name),
+            &mut vec![],
+        )
+        .and_then(|role| {
+            serde_yaml::from_value::<Role>(role)
+                .map_err(|e| format!("Invalid role {}: {}", name, e))
+        });
+        match role {
+            Ok(role) => {
+                spec.insert(name, role);
+            }
+            Err(e) => errors.push(e),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+    Ok(spec)
+}
+
+/// `body` with its templates merged in. `stack` holds the templates being
+/// resolved, to catch cycles.
+fn resolve(
+    templates: &Templates,
+    body: &Value,
+    what: &str,
+    stack: &mut Vec<String>,
+) -> Result<Value, String> {
+    let mut merged = Value::Mapping(Mapping::new());
+    for parent in extends(body).map_err(|e| format!("Invalid `extends` in {}: {}", what, e))? {
+        if stack.contains(&parent) {
+            return Err(format!(
+                "Template cycle: {} -> {}",
+                stack.join(" -> "),
+                parent
+            ));
+        }
+        let Some(template) = templates.get(&parent) else {
+            return Err(format!("{} extends unknown template {}", what, parent));
+        };
+        stack.push(parent.clone());
+        let mut template = resolve(templates, template, &format!("template {}", parent), stack)?;
+        stack.pop();
+        if let Value::Mapping(template) = &mut template {
+            template.remove(EXTENDS);
+        }
+        merged = merge(merged, template);
+    }
+    Ok(merge(merged, body.clone()))
+}
+
+fn extends(body: &Value) -> Result<Vec<String>, serde_yaml::Error> {
+    match body.get(EXTENDS) {
+        Some(value) => deserialize_extends(value.clone()),
+        None => Ok(vec![]),
+    }
+}
+
+/// Merges `over` into `base`, `over` winning on conflicting values.
+pub(crate) fn merge(base: Value, over: Value) -> Value {
+    match (base, over) {
+        (Value::Mapping(mut base), Value::Mapping(over)) => {
+            for (key, value) in over {
+                let value = match base.remove(&key) {
+                    Some(previous) => merge(previous, value),
+                    None => value,
+                };
+                base.insert(key, value);
+            }
+            Value::Mapping(base)
+        }
+        (Value::Sequence(mut base), Value::Sequence(over)) => {
+            for item in over {
+                match base.iter().position(|b| entry_key(b) == entry_key(&item)) {
+                    Some(i) => base[i] = item,
+                    None => base.push(item),
+                }
+            }
+            Value::Sequence(base)
+        }
+        // An empty key, e.g. `privileges:`, keeps what the template says
+        (base, Value::Null) => base,
+        (_, over) => over,
+    }
+}
+
+/// What identifies an entry of a list: the role of a membership or the
+/// object of a grant, whether written as a name or in full.
+fn entry_key(item: &Value) -> &Value {
+    match item {
+        Value::Mapping(entry) => entry.get("role").or(entry.get("object")).unwrap_or(item),
+        _ => item,
+    }
+}
+
+/// `extends` can name one template or a list of them.
+pub(crate) fn deserialize_extends<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Extends {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match Extends::deserialize(deserializer)? {
+        Extends::One(name) => vec![name],
+        Extends::Many(names) => names,
+    })
+}
+
+pub(crate) fn serialize_extends<S>(extends: &[String], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match extends {
+        [name] => name.serialize(serializer),
+        names => names.serialize(serializer),
+    }
+}
+
+/// Finds groups of roles with the same login, superuser, `member_of` and
+/// privileges, and moves what they share into a template. Ownership stays on
+/// the roles, it belongs to a single role.
+///
+/// Templates are named after the first group the roles are a member of,
+/// e.g. `analyst_member`, or numbered when they have no groups.
+pub fn detect_templates(spec: &mut DatabaseSpec) {
+    let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut names: Vec<&String> = spec.roles.keys().filter(|n| *n != PUBLIC).collect();
+    names.sort();
+    for name in names {
+        let role = &spec.roles[name];
+        if role.member_of.is_empty() && role.privileges == Privileges::new() {
+            continue;
+        }
+        let shared = Role {
+            owns: Ownership::new(),
+            extends: vec![],
+            ..role.clone()
+        };
+        let key = serde_yaml::to_string(&shared).expect("roles serialize to YAML");
+        groups.entry(key).or_default().push(name.clone());
+    }
+
+    let mut count = 0;
+    for (key, members) in groups {
+        if members.len() < 2 {
+            continue;
+        }
+        let template: Value = serde_yaml::from_str(&key).expect("roles serialize to YAML");
+        let shared = &spec.roles[&members[0]];
+        let mut name = match shared.member_of.first() {
+            Some(group) => format!("{}_member", group.role),
+            None => {
+                count += 1;
+                format!("template_{}", count)
+            }
+        };
+        while spec.templates.contains_key(&name) {
+            count += 1;
+            name = format!("template_{}", count);
+        }
+        for member in &members {
+            let role = spec.roles.get_mut(member).unwrap();
+            role.member_of = vec![];
+            role.privileges = Privileges::new();
+            role.extends = vec![name.clone()];
+        }
+        spec.templates.insert(name, template);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::Membership;
+
+    fn spec(source: &str) -> Result<DatabaseSpec, serde_yaml::Error> {
+        serde_yaml::from_str(source)
+    }
+
+    #[test]
+    fn test_roles_extend_templates() {
+        let spec = spec(
+            "
+            version: 1
+            adapter: postgres
+            templates:
+              base:
+                can_login: no
+                member_of:
+                  - analyst
+                privileges:
+                  schemas:
+                    read:
+                      - finance
+              reporting:
+                extends: base
+                can_login: yes
+                privileges:
+                  tables:
+                    read:
+                      - reports.*
+            roles:
+              analyst: {}
+              jdoe:
+                extends: [reporting]
+                member_of:
+                  - role: analyst
+                    with_admin_option: yes
+                privileges:
+                  schemas:
+                    read:
+                      - finance
+                      - marketing
+                  tables:
+                    read:
+                      - object: reports.*
+                        with_grant_option: yes
+              service:
+                extends: base
+                can_login: yes
+            ",
+        )
+        .unwrap();
+
+        let jdoe = &spec.roles["jdoe"];
+        assert!(jdoe.can_login);
+        assert_eq!(jdoe.extends, vec!["reporting"]);
+        // The role's entry replaces the template's
+        assert_eq!(
+            jdoe.member_of,
+            vec![Membership {
+                role: "analyst".to_string(),
+                with_admin_option: true,
+                expires: None,
+            }]
+        );
+        let schemas: Vec<&str> = jdoe
+            .privileges
+            .schemas
+            .read
+            .iter()
+            .map(|g| g.object.as_str())
+            .collect();
+        assert_eq!(schemas, vec!["finance", "marketing"]);
+        assert_eq!(jdoe.privileges.tables.read.len(), 1);
+        assert!(jdoe.privileges.tables.read[0].with_grant_option);
+
+        // The role wins over its template
+        assert!(spec.roles["service"].can_login);
+        assert!(spec.roles["service"].privileges.tables.read.is_empty());
+    }
+
+    #[test]
+    fn test_reports_unknown_templates_and_cycles() {
+        let error = spec(
+            "
+            version: 1
+            adapter: postgres
+            templates:
+              a:
+                extends: b
+              b:
+                extends: a
+            roles:
+              jdoe:
+                extends: a
+              bob:
+                extends: ghost
+            ",
+        )
+        .unwrap_err()
+        .to_string();
+
+        assert!(error.contains("role bob extends unknown template ghost"));
+        assert!(error.contains("Template cycle: a -> b -> a"));
+    }
+
+    #[test]
+    fn test_detect_templates() {
+        let mut spec = spec(
+            "
+            version: 1
+            adapter: postgres
+            roles:
+              alice:
+                member_of:
+                  - analyst
+                owns:
+                  schemas:
+                    - alice
+                privileges:
+                  schemas:
+                    read:
+                      - finance
+              bob:
+                member_of:
+                  - analyst
+                privileges:
+                  schemas:
+                    read:
+                      - finance
+              carol:
+                member_of:
+                  - developer
+            ",
+        )
+        .unwrap();
+        let expanded = spec.roles.clone();
+
+        detect_templates(&mut spec);
+
+        assert_eq!(
+            spec.templates.keys().collect::<Vec<_>>(),
+            ["analyst_member"]
+        );
+        assert_eq!(spec.roles["alice"].extends, vec!["analyst_member"]);
+        assert!(spec.roles["bob"].member_of.is_empty());
+        assert!(spec.roles["carol"].extends.is_empty());
+        assert_eq!(spec.roles["alice"].owns.schemas, vec!["alice"]);
+
+        // Reading the result back gives the same roles
+        let reread: DatabaseSpec = serde_yaml::from_str(&spec.to_yaml().unwrap()).unwrap();
+        for (name, role) in expanded {
+            assert_eq!(reread.roles[&name].privileges, role.privileges);
+            assert_eq!(reread.roles[&name].member_of, role.member_of);
+            assert_eq!(reread.roles[&name].owns, role.owns);
+        }
+    }
+}
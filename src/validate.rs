@@ -0,0 +1,1338 @@
+//! Spec validation with precise locations.
+//!
+//! serde stops at the first problem and silently ignores unknown keys, which
+//! makes for poor feedback on a spec written by hand. [`validate_str`] walks
+//! the YAML itself, keeping the line and column of every node, and reports
+//! every problem it finds: unknown keys, duplicate roles, malformed object
+//! names, unknown object kinds, bad booleans and unsupported versions. A spec
+//! that passes is then checked with [`DatabaseSpec::validate`]. Specs split
+//! across files are checked file by file, then for conflicts between them.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context as _, Result};
+use chrono::{DateTime, Duration, Utc};
+use schemars::schema::RootSchema;
+use yaml_rust2::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust2::scanner::{Marker, TScalarStyle};
+
+use crate::context::ObjectKind;
+use crate::load::{self, SpecFile};
+use crate::overlay;
+use crate::policy::PolicyCommand;
+use crate::schema;
+use crate::spec::{format_timestamp, parse_timestamp, DatabaseSpec};
+
+/// The spec versions this release understands.
+pub const SUPPORTED_VERSIONS: &[&str] = &["1"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.file, self.line, self.column, self.message
+        )
+    }
+}
+
+/// Validates the spec at `path`, a file or a directory, following includes
+/// like [`crate::load`] and checking the overlays for `env`, see
+/// [`crate::overlay`]. Only failing to read a file is an error, problems
+/// with the spec itself are returned.
+pub fn validate_file(path: &str, env: Option<&str>) -> Result<Vec<ValidationError>> {
+    let files = load::spec_files(Path::new(path))?;
+    let overlays: Vec<PathBuf> = files
+        .iter()
+        .filter_map(|file| env.and_then(|env| overlay::overlay_path(file, env)))
+        .collect();
+    if files.len() == 1 && overlays.is_empty() && !Path::new(path).is_dir() {
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to open spec file: {}", path))?;
+        return Ok(validate_str(&source, path));
+    }
+
+    let mut errors = vec![];
+    let mut trees = vec![];
+    for file in files {
+        let name = file.display().to_string();
+        let source = std::fs::read_to_string(&file)
+            .with_context(|| format!("Failed to open spec file: {}", name))?;
+        let (file_errors, root) = check_source(&source, &name, true);
+        errors.extend(file_errors);
+        if let Some(root) = root {
+            trees.push((name, root));
+        }
+    }
+    // Overlays redefine roles from their base file, so they are left out of
+    // the checks across files
+    for overlay in overlays {
+        let name = overlay.display().to_string();
+        let source = std::fs::read_to_string(&overlay)
+            .with_context(|| format!("Failed to open spec file: {}", name))?;
+        errors.extend(check_source(&source, &name, true).0);
+    }
+    errors.extend(conflicts(&trees));
+    errors.extend(unknown_templates(&trees));
+    if !errors.is_empty() {
+        return Ok(errors);
+    }
+
+    match load::load_spec(Path::new(path), env) {
+        Ok(spec) => errors.extend(spec_errors(&spec, &trees)),
+        Err(e) => errors.push(ValidationError {
+            file: path.to_string(),
+            line: 1,
+            column: 1,
+            message: format!("{:#}", e),
+        }),
+    }
+    Ok(errors)
+}
+
+/// Validates a spec, naming it `file` in the errors.
+pub fn validate_str(source: &str, file: &str) -> Vec<ValidationError> {
+    let (mut errors, root) = check_source(source, file, false);
+    let Some(root) = root else {
+        return errors;
+    };
+    let trees = [(file.to_string(), root)];
+    if errors.is_empty() {
+        errors = unknown_templates(&trees);
+    }
+    if !errors.is_empty() {
+        return errors;
+    }
+    match spec_value(source).and_then(serde_yaml::from_value::<DatabaseSpec>) {
+        Ok(spec) => spec_errors(&spec, &trees),
+        Err(e) => vec![ValidationError {
+            file: file.to_string(),
+            line: 1,
+            column: 1,
+            message: e.to_string(),
+        }],
+    }
+}
+
+/// Checks the structure of one spec file, then its values with serde. A
+/// `fragment` is a file that is merged with others, so it need not set the
+/// required keys. Returns the problems found and the parsed tree.
+fn check_source(source: &str, file: &str, fragment: bool) -> (Vec<ValidationError>, Option<Node>) {
+    let mut validator = Validator {
+        file,
+        fragment,
+        schema: schema::spec_schema(),
+        errors: vec![],
+    };
+
+    let mut root = match parse(source) {
+        Ok(Some(root)) => root,
+        Ok(None) if fragment => return (vec![], None),
+        Ok(None) => {
+            validator.error_at(1, 1, "The spec is empty".to_string());
+            return (validator.errors, None);
+        }
+        Err(e) => {
+            let mark = e.marker();
+            validator.error_at(mark.line(), mark.col() + 1, e.to_string());
+            return (validator.errors, None);
+        }
+    };
+    validator.substitute(&mut root);
+    validator.check_spec(&root);
+    if !validator.errors.is_empty() {
+        validator
+            .errors
+            .sort_by_key(|e| (e.line, e.column, e.message.clone()));
+        return (validator.errors, Some(root));
+    }
+
+    // The structure is sound, so anything serde finds is about values. Roles
+    // are only read once their templates are known
+    if let Err(e) = spec_value(source).and_then(serde_yaml::from_value::<SpecFile>) {
+        let (line, column) = e.location().map_or((1, 1), |l| (l.line(), l.column()));
+        validator.error_at(line, column, e.to_string());
+    }
+    (validator.errors, Some(root))
+}
+
+/// `source` as YAML with environment variables substituted. Unset variables
+/// are reported by [`Validator::substitute`].
+fn spec_value(source: &str) -> Result<serde_yaml::Value, serde_yaml::Error> {
+    let mut value = serde_yaml::from_str(source)?;
+    let _ = overlay::substitute_value(&mut value);
+    Ok(value)
+}
+
+/// Roles defined in more than one file, and `version` or `adapter` set to
+/// different values, reported where they are repeated.
+fn conflicts(trees: &[(String, Node)]) -> Vec<ValidationError> {
+    let mut errors = vec![];
+    let mut first: BTreeMap<String, (&str, &Node)> = BTreeMap::new();
+    let mut error = |file: &str, node: &Node, message: String| {
+        errors.push(ValidationError {
+            file: file.to_string(),
+            line: node.line,
+            column: node.column,
+            message,
+        })
+    };
+
+    for key in ["version", "adapter"] {
+        let mut values = trees
+            .iter()
+            .filter_map(|(file, root)| root.get(key).map(|value| (file, value)));
+        let Some((first_file, first_value)) = values.next() else {
+            if let Some((file, root)) = trees.first() {
+                error(file, root, format!("No spec file sets `{}`", key));
+            }
+            continue;
+        };
+        for (file, value) in values {
+            if value.as_str() != first_value.as_str() {
+                error(
+                    file,
+                    value,
+                    format!(
+                        "`{}` is {} here but {} in {}:{}:{}",
+                        key,
+                        value.as_str().unwrap_or("?"),
+                        first_value.as_str().unwrap_or("?"),
+                        first_file,
+                        first_value.line,
+                        first_value.column
+                    ),
+                );
+            }
+        }
+    }
+
+    for (file, root) in trees {
+        let Some(roles) = root.get("roles") else {
+            continue;
+        };
+        for (key, _) in roles.entries() {
+            let Some(name) = key.as_str() else {
+                continue;
+            };
+            match first.get(name) {
+                Some((first_file, first_key)) if first_file != file => error(
+                    file,
+                    key,
+                    format!(
+                        "Role {} is already defined in {}:{}:{}",
+                        name, first_file, first_key.line, first_key.column
+                    ),
+                ),
+                Some(_) => {}
+                None => {
+                    first.insert(name.to_string(), (file, key));
+                }
+            }
+        }
+    }
+    errors
+}
+
+/// `extends` naming templates that no file defines.
+fn unknown_templates(trees: &[(String, Node)]) -> Vec<ValidationError> {
+    let defined: Vec<&str> = trees
+        .iter()
+        .filter_map(|(_, root)| root.get("templates"))
+        .flat_map(|templates| templates.entries().iter().filter_map(|(k, _)| k.as_str()))
+        .collect();
+    let mut errors = vec![];
+    for (file, root) in trees {
+        for section in ["templates", "roles"] {
+            let Some(definitions) = root.get(section) else {
+                continue;
+            };
+            for (name, body) in definitions.entries() {
+                let Some(extends) = body.get("extends") else {
+                    continue;
+                };
+                let templates = match &extends.value {
+                    Value::Sequence(items) => items.iter().collect(),
+                    _ => vec![extends],
+                };
+                for node in templates {
+                    let Some(template) = node.as_str().filter(|t| !defined.contains(t)) else {
+                        continue;
+                    };
+                    errors.push(ValidationError {
+                        file: file.clone(),
+                        line: node.line,
+                        column: node.column,
+                        message: format!(
+                            "{} extends unknown template {}",
+                            name.as_str().unwrap_or("?"),
+                            template
+                        ),
+                    });
+                }
+            }
+        }
+    }
+    errors
+}
+
+/// The problems found by [`DatabaseSpec::validate`], located at the role
+/// or table they were found on in whichever file defines it.
+fn spec_errors(spec: &DatabaseSpec, trees: &[(String, Node)]) -> Vec<ValidationError> {
+    let memberships = spec.membership_errors().into_iter().map(|e| ("roles", e));
+    let policies = spec.policy_errors().into_iter().map(|e| ("policies", e));
+    memberships
+        .chain(policies)
+        .filter_map(|(section, (name, message))| locate(trees, section, &name, message))
+        .collect()
+}
+
+/// Memberships and grants in the spec at `path` that expire before
+/// `now + within`, or have already expired and are revoked by the next
+/// configure, located at the role they are on. Meant for a spec that
+/// passed [`validate_file`].
+pub fn expiry_warnings(
+    path: &str,
+    env: Option<&str>,
+    now: DateTime<Utc>,
+    within: Duration,
+) -> Result<Vec<ValidationError>> {
+    let mut trees = vec![];
+    for file in load::spec_files(Path::new(path))? {
+        let name = file.display().to_string();
+        let source = std::fs::read_to_string(&file)
+            .with_context(|| format!("Failed to open spec file: {}", name))?;
+        if let Ok(Some(root)) = parse(&source) {
+            trees.push((name, root));
+        }
+    }
+    let spec = load::load_spec(Path::new(path), env)?;
+    let warnings = spec
+        .expiring(now + within)
+        .into_iter()
+        .filter_map(|(name, what, expires)| {
+            let message = if expires <= now {
+                format!(
+                    "The {} of {} expired {}, remove it from the spec",
+                    what,
+                    name,
+                    format_timestamp(expires)
+                )
+            } else {
+                format!(
+                    "The {} of {} expires {}",
+                    what,
+                    name,
+                    format_timestamp(expires)
+                )
+            };
+            locate(&trees, "roles", &name, message)
+        })
+        .collect();
+    Ok(warnings)
+}
+
+/// `message` located at `name` in `section` of whichever file defines it,
+/// or at the top of the first file.
+fn locate(
+    trees: &[(String, Node)],
+    section: &str,
+    name: &str,
+    message: String,
+) -> Option<ValidationError> {
+    let (file, node) = trees
+        .iter()
+        .find_map(|(file, root)| {
+            root.get(section)
+                .and_then(|s| s.key(name))
+                .map(|key| (file, key))
+        })
+        .or_else(|| trees.first().map(|(file, root)| (file, root)))?;
+    Some(ValidationError {
+        file: file.clone(),
+        line: node.line,
+        column: node.column,
+        message,
+    })
+}
+
+/// A YAML node with the position it starts at.
+#[derive(Debug)]
+struct Node {
+    value: Value,
+    line: usize,
+    column: usize,
+}
+
+#[derive(Debug)]
+enum Value {
+    Null,
+    Scalar(String),
+    Sequence(Vec<Node>),
+    Mapping(Vec<(Node, Node)>),
+}
+
+impl Node {
+    fn entries(&self) -> &[(Node, Node)] {
+        match &self.value {
+            Value::Mapping(entries) => entries,
+            _ => &[],
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&Node> {
+        self.entries()
+            .iter()
+            .find(|(k, _)| k.as_str() == Some(key))
+            .map(|(_, v)| v)
+    }
+
+    fn key(&self, key: &str) -> Option<&Node> {
+        self.entries()
+            .iter()
+            .find(|(k, _)| k.as_str() == Some(key))
+            .map(|(k, _)| k)
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match &self.value {
+            Value::Scalar(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match self.value {
+            Value::Null => "nothing",
+            Value::Scalar(_) => "a value",
+            Value::Sequence(_) => "a list",
+            Value::Mapping(_) => "a map",
+        }
+    }
+}
+
+/// Builds a tree of [`Node`]s from parser events.
+#[derive(Default)]
+struct TreeBuilder {
+    /// Open sequences and mappings, with the key waiting for a value
+    stack: Vec<(Node, Option<Node>)>,
+    root: Option<Node>,
+}
+
+impl TreeBuilder {
+    fn push(&mut self, node: Node) {
+        match self.stack.last_mut() {
+            None => self.root = Some(node),
+            Some((parent, pending_key)) => match &mut parent.value {
+                Value::Sequence(items) => items.push(node),
+                Value::Mapping(entries) => match pending_key.take() {
+                    Some(key) => entries.push((key, node)),
+                    None => *pending_key = Some(node),
+                },
+                _ => unreachable!("only collections are pushed on the stack"),
+            },
+        }
+    }
+}
+
+impl MarkedEventReceiver for TreeBuilder {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        let node = |value| Node {
+            value,
+            line: mark.line(),
+            column: mark.col() + 1,
+        };
+        match event {
+            Event::Scalar(value, style, _, _) => {
+                let is_null =
+                    style == TScalarStyle::Plain && matches!(value.as_str(), "~" | "null" | "");
+                self.push(node(if is_null {
+                    Value::Null
+                } else {
+                    Value::Scalar(value)
+                }));
+            }
+            // Anchors are rare in specs, and serde reports anything wrong
+            // with what an alias points to
+            Event::Alias(_) => self.push(node(Value::Null)),
+            Event::SequenceStart(..) => self.stack.push((node(Value::Sequence(vec![])), None)),
+            Event::MappingStart(..) => self.stack.push((node(Value::Mapping(vec![])), None)),
+            Event::SequenceEnd | Event::MappingEnd => {
+                if let Some((collection, _)) = self.stack.pop() {
+                    self.push(collection);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn parse(source: &str) -> Result<Option<Node>, yaml_rust2::ScanError> {
+    let mut builder = TreeBuilder::default();
+    Parser::new(source.chars()).load(&mut builder, false)?;
+    Ok(builder.root)
+}
+
+struct Validator<'a> {
+    file: &'a str,
+    fragment: bool,
+    schema: RootSchema,
+    errors: Vec<ValidationError>,
+}
+
+impl Validator<'_> {
+    fn error_at(&mut self, line: usize, column: usize, message: String) {
+        self.errors.push(ValidationError {
+            file: self.file.to_string(),
+            line,
+            column,
+            message,
+        });
+    }
+
+    fn error(&mut self, node: &Node, message: String) {
+        self.error_at(node.line, node.column, message);
+    }
+
+    /// Checks that `node` is a map whose keys are all in `known`, reporting
+    /// duplicates, and returns its entries. `what` names the map in errors.
+    fn mapping<'n>(
+        &mut self,
+        node: &'n Node,
+        known: Option<&[String]>,
+        what: &str,
+    ) -> Vec<(&'n str, &'n Node)> {
+        let entries = match &node.value {
+            Value::Mapping(entries) => entries,
+            Value::Null => return vec![],
+            _ => {
+                self.error(
+                    node,
+                    format!("Expected a map for {}, found {}", what, node.describe()),
+                );
+                return vec![];
+            }
+        };
+
+        let mut seen: Vec<(&str, &Node, &Node)> = vec![];
+        for (key, value) in entries {
+            let Some(name) = key.as_str() else {
+                self.error(key, format!("Expected a name as key in {}", what));
+                continue;
+            };
+            if let Some((_, first, _)) = seen.iter().find(|(n, _, _)| *n == name) {
+                self.error(
+                    key,
+                    format!(
+                        "Duplicate key `{}` in {}, first defined on line {}",
+                        name, what, first.line
+                    ),
+                );
+                continue;
+            }
+            if let Some(known) = known.filter(|known| !known.iter().any(|k| k == name)) {
+                let mut message = format!("Unknown key `{}` in {}", name, what);
+                match suggest(name, known) {
+                    Some(suggestion) => {
+                        message.push_str(&format!(", did you mean `{}`?", suggestion))
+                    }
+                    None => message.push_str(&format!(", expected one of: {}", known.join(", "))),
+                }
+                self.error(key, message);
+                continue;
+            }
+            seen.push((name, key, value));
+        }
+        seen.into_iter()
+            .map(|(name, _, value)| (name, value))
+            .collect()
+    }
+
+    /// Checks a map against `definition` in the spec schema: only the keys
+    /// it describes are allowed, and the keys it requires must be present.
+    fn definition<'n>(
+        &mut self,
+        node: &'n Node,
+        definition: Option<&str>,
+        what: &str,
+    ) -> Vec<(&'n str, &'n Node)> {
+        let known = schema::properties(&self.schema, definition);
+        let entries = self.mapping(node, Some(&known), what);
+        let optional = self.fragment && definition.is_none();
+        if matches!(node.value, Value::Mapping(_)) && !optional {
+            for required in schema::required(&self.schema, definition) {
+                if node.get(&required).is_none() {
+                    self.error(
+                        node,
+                        format!("Missing required key `{}` in {}", required, what),
+                    );
+                }
+            }
+        }
+        entries
+    }
+
+    /// Substitutes environment variables in every scalar of `node`,
+    /// reporting those that are not set where they are used.
+    fn substitute(&mut self, node: &mut Node) {
+        match &mut node.value {
+            Value::Scalar(text) => match overlay::substitute(text) {
+                Ok(substituted) => *text = substituted,
+                Err(missing) => {
+                    let (line, column) = (node.line, node.column);
+                    for name in missing {
+                        let message = format!("Environment variable {} is not set", name);
+                        self.error_at(line, column, message);
+                    }
+                }
+            },
+            Value::Sequence(items) => {
+                for item in items {
+                    self.substitute(item);
+                }
+            }
+            Value::Mapping(entries) => {
+                for (key, value) in entries {
+                    self.substitute(key);
+                    self.substitute(value);
+                }
+            }
+            Value::Null => {}
+        }
+    }
+
+    fn sequence<'n>(&mut self, node: &'n Node, what: &str) -> &'n [Node] {
+        match &node.value {
+            Value::Sequence(items) => items,
+            Value::Null => &[],
+            _ => {
+                self.error(
+                    node,
+                    format!("Expected a list for {}, found {}", what, node.describe()),
+                );
+                &[]
+            }
+        }
+    }
+
+    fn string<'n>(&mut self, node: &'n Node, what: &str) -> Option<&'n str> {
+        match node.as_str() {
+            Some(value) => Some(value),
+            None => {
+                self.error(
+                    node,
+                    format!("Expected a name for {}, found {}", what, node.describe()),
+                );
+                None
+            }
+        }
+    }
+
+    fn boolean(&mut self, node: &Node, what: &str) {
+        match node.as_str() {
+            Some("yes" | "no" | "true" | "false") => {}
+            Some(value) => self.error(
+                node,
+                format!("Expected yes or no for {}, found `{}`", what, value),
+            ),
+            None => self.error(
+                node,
+                format!("Expected yes or no for {}, found {}", what, node.describe()),
+            ),
+        }
+    }
+
+    fn timestamp(&mut self, node: &Node, what: &str) {
+        match node.as_str() {
+            Some(text) => {
+                if let Err(e) = parse_timestamp(text) {
+                    self.error(node, format!("{:#}", e));
+                }
+            }
+            None => self.error(
+                node,
+                format!(
+                    "Expected a timestamp for {}, found {}",
+                    what,
+                    node.describe()
+                ),
+            ),
+        }
+    }
+
+    fn check_spec(&mut self, root: &Node) {
+        for (key, value) in self.definition(root, None, "the spec") {
+            match key {
+                "version" => match value.as_str() {
+                    Some(version) if SUPPORTED_VERSIONS.contains(&version) => {}
+                    _ => self.error(
+                        value,
+                        format!(
+                            "Unsupported spec version {}, expected one of: {}",
+                            value.as_str().unwrap_or("?"),
+                            SUPPORTED_VERSIONS.join(", ")
+                        ),
+                    ),
+                },
+                "adapter" => {
+                    self.string(value, "adapter");
+                }
+                "ignore" | "manage_only" => self.check_scope_rules(value, key),
+                "include" => {
+                    for path in self.sequence(value, "include") {
+                        self.string(path, "include");
+                    }
+                }
+                "templates" => {
+                    for (name, template) in self.mapping(value, None, "templates") {
+                        self.check_role(&format!("template {}", name), template);
+                    }
+                }
+                "roles" => {
+                    for (name, role) in self.mapping(value, None, "roles") {
+                        self.check_role(&format!("role {}", name), role);
+                    }
+                }
+                "policies" => {
+                    for (table, security) in self.mapping(value, None, "policies") {
+                        if let Some(key) = value.key(table) {
+                            self.check_object_name(key, ObjectKind::Table, table);
+                        }
+                        self.check_row_security(table, security);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn check_row_security(&mut self, table: &str, node: &Node) {
+        let what = format!("policies for {}", table);
+        for (key, value) in self.definition(node, Some("RowSecurity"), &what) {
+            match key {
+                "policies" => {
+                    for (name, policy) in self.mapping(value, None, &what) {
+                        self.check_policy(&format!("policy {} on {}", name, table), policy);
+                    }
+                }
+                _ => self.boolean(value, key),
+            }
+        }
+    }
+
+    fn check_policy(&mut self, what: &str, node: &Node) {
+        for (key, value) in self.definition(node, Some("Policy"), what) {
+            match key {
+                "command" => {
+                    let command = self.string(value, "command");
+                    if let Some(command) = command.filter(|c| PolicyCommand::parse(c).is_none()) {
+                        self.error(
+                            value,
+                            format!(
+                                "Unknown policy command `{}`, expected one of: all, select, insert, update, delete",
+                                command
+                            ),
+                        );
+                    }
+                }
+                "permissive" => self.boolean(value, key),
+                "roles" => {
+                    for role in self.sequence(value, "roles") {
+                        self.string(role, "roles");
+                    }
+                }
+                _ => {
+                    self.string(value, key);
+                }
+            }
+        }
+    }
+
+    fn check_scope_rules(&mut self, node: &Node, what: &str) {
+        for (key, value) in self.definition(node, Some("ScopeRules"), what) {
+            for pattern in self.sequence(value, key) {
+                self.string(pattern, &format!("{}.{}", what, key));
+            }
+        }
+    }
+
+    fn check_role(&mut self, what: &str, node: &Node) {
+        for (key, value) in self.definition(node, Some("Role"), what) {
+            match key {
+                "extends" if value.as_str().is_none() => {
+                    for template in self.sequence(value, "extends") {
+                        self.string(template, "extends");
+                    }
+                }
+                "can_login" | "is_superuser" | "has_personal_schema" => self.boolean(value, key),
+                "settings" => self.check_settings(value),
+                "password" => self.check_password(value),
+                "valid_until" => self.timestamp(value, key),
+                "member_of" => {
+                    for membership in self.sequence(value, "member_of") {
+                        self.check_membership(membership);
+                    }
+                }
+                "owns" => {
+                    for (kind, objects) in self.object_kinds(value, "Ownership", "owns") {
+                        for object in self.sequence(objects, "owns") {
+                            if let Some(name) = self.string(object, "an owned object") {
+                                self.check_object_name(object, kind, name);
+                            }
+                        }
+                    }
+                }
+                "privileges" => {
+                    for (kind, privileges) in self.object_kinds(value, "Privileges", "privileges") {
+                        let what = format!("{} privileges", kind);
+                        let definition = match kind {
+                            ObjectKind::Schema => "SchemaPrivileges",
+                            ObjectKind::Sequence => "SequencePrivileges",
+                            ObjectKind::Function => "FunctionPrivileges",
+                            _ => "TablePrivileges",
+                        };
+                        for (access, grants) in self.definition(privileges, Some(definition), &what)
+                        {
+                            if kind == ObjectKind::Function && access == "write" {
+                                self.error(
+                                    grants,
+                                    "Functions only support read, which grants EXECUTE".to_string(),
+                                );
+                                continue;
+                            }
+                            for grant in self.sequence(grants, &what) {
+                                self.check_grant(grant, kind);
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn check_password(&mut self, node: &Node) {
+        if node.as_str().is_some() {
+            self.error(
+                node,
+                "Passwords cannot be written in the spec, use `env` or `file`".to_string(),
+            );
+            return;
+        }
+        let known = ["env".to_string(), "file".to_string()];
+        let entries = self.mapping(node, Some(&known), "password");
+        for (key, value) in &entries {
+            self.string(value, &format!("password.{}", key));
+        }
+        if matches!(node.value, Value::Mapping(_)) && entries.len() != 1 {
+            self.error(
+                node,
+                "Expected exactly one of `env` or `file` for password".to_string(),
+            );
+        }
+    }
+
+    fn check_settings(&mut self, node: &Node) {
+        for (key, value) in self.mapping(node, None, "settings") {
+            if key == "databases" {
+                for (database, values) in self.mapping(value, None, "settings.databases") {
+                    let what = format!("settings in database {}", database);
+                    for (key, value) in self.mapping(values, None, &what) {
+                        self.setting_value(key, value);
+                    }
+                }
+            } else {
+                self.setting_value(key, value);
+            }
+        }
+    }
+
+    fn setting_value(&mut self, key: &str, node: &Node) {
+        if node.as_str().is_none() {
+            self.error(
+                node,
+                format!(
+                    "Expected a value for setting {}, found {}",
+                    key,
+                    node.describe()
+                ),
+            );
+        }
+    }
+
+    /// The entries of an `owns` or `privileges` map, keyed by object kind.
+    fn object_kinds<'n>(
+        &mut self,
+        node: &'n Node,
+        definition: &str,
+        what: &str,
+    ) -> Vec<(ObjectKind, &'n Node)> {
+        let kinds = schema::properties(&self.schema, Some(definition));
+        let entries = match &node.value {
+            Value::Mapping(entries) => entries,
+            _ => {
+                self.mapping(node, Some(&kinds), what);
+                return vec![];
+            }
+        };
+        for (key, _) in entries {
+            if let Some(kind) = key.as_str().filter(|k| !kinds.iter().any(|c| c == k)) {
+                let mut message = format!("Unknown object kind `{}` in {}", kind, what);
+                match suggest(kind, &kinds) {
+                    Some(suggestion) => {
+                        message.push_str(&format!(", did you mean `{}`?", suggestion))
+                    }
+                    None => message.push_str(&format!(", expected one of: {}", kinds.join(", "))),
+                }
+                self.error(key, message);
+            }
+        }
+        self.mapping(node, None, what)
+            .into_iter()
+            .filter(|(key, _)| kinds.iter().any(|k| k == key))
+            .map(|(key, value)| (ObjectKind::from(key), value))
+            .collect()
+    }
+
+    fn check_membership(&mut self, node: &Node) {
+        if node.as_str().is_some() {
+            return;
+        }
+        for (key, value) in self.definition(node, Some("Membership"), "member_of") {
+            match key {
+                "role" => {
+                    self.string(value, "role");
+                }
+                "expires" => self.timestamp(value, key),
+                _ => self.boolean(value, key),
+            }
+        }
+    }
+
+    fn check_grant(&mut self, node: &Node, kind: ObjectKind) {
+        if let Some(name) = node.as_str() {
+            self.check_object_name(node, kind, name);
+            return;
+        }
+        for (key, value) in self.definition(node, Some("Grant"), "a grant") {
+            match key {
+                "object" => {
+                    if let Some(name) = self.string(value, "object") {
+                        self.check_object_name(value, kind, name);
+                    }
+                }
+                "expires" => self.timestamp(value, key),
+                _ => self.boolean(value, key),
+            }
+        }
+    }
+
+    /// Schemas are a single name, other objects are `schema.name` or
+    /// `schema.*`. Function names carry their argument types, which may be
+    /// qualified themselves, so only the part before `(` is checked.
+    fn check_object_name(&mut self, node: &Node, kind: ObjectKind, name: &str) {
+        let expected = match kind {
+            ObjectKind::Schema => "a schema name",
+            ObjectKind::Function => "schema.function(argument types) or schema.*",
+            _ => "schema.name or schema.*",
+        };
+        let path = match kind {
+            ObjectKind::Function if !name.ends_with(".*") => match name.split_once('(') {
+                Some((path, args)) if args.ends_with(')') => path,
+                _ => "",
+            },
+            _ => name,
+        };
+        let parts: Vec<&str> = path.split('.').collect();
+        let valid = match kind {
+            ObjectKind::Schema => parts.len() == 1 && !parts[0].is_empty(),
+            _ => parts.len() == 2 && parts.iter().all(|p| !p.is_empty()),
+        };
+        if !valid {
+            self.error(
+                node,
+                format!("Malformed {} name `{}`, expected {}", kind, name, expected),
+            );
+        }
+    }
+}
+
+/// The closest of `candidates` to `name`, if it is close enough to be a
+/// likely typo.
+fn suggest<'a>(name: &str, candidates: &'a [String]) -> Option<&'a str> {
+    candidates
+        .iter()
+        .map(|c| (edit_distance(name, c), c.as_str()))
+        .filter(|(distance, _)| *distance <= 2)
+        .min()
+        .map(|(_, c)| c)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(source: &str) -> Vec<String> {
+        validate_str(source, "spec.yml")
+            .iter()
+            .map(|e| e.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_reports_every_problem_with_location() {
+        let source = "\
+version: 2
+adapter: postgres
+roles:
+  jdoe:
+    can_login: maybe
+    privilages:
+      tables:
+        read:
+          - finance.q2_revenue
+  analyst:
+    member_of:
+      - role: jdoe
+        admin: yes
+    privileges:
+      tables:
+        read:
+          - finance.reports.q2
+      views:
+        read:
+          - finance.v
+  jdoe:
+    can_login: no
+";
+
+        assert_eq!(
+            messages(source),
+            vec![
+                "spec.yml:1:10: Unsupported spec version 2, expected one of: 1",
+                "spec.yml:5:16: Expected yes or no for can_login, found `maybe`",
+                "spec.yml:6:5: Unknown key `privilages` in role jdoe, did you mean `privileges`?",
+                "spec.yml:13:9: Unknown key `admin` in member_of, expected one of: expires, role, with_admin_option",
+                "spec.yml:17:13: Malformed table name `finance.reports.q2`, expected schema.name or schema.*",
+                "spec.yml:18:7: Unknown object kind `views` in privileges, expected one of: functions, schemas, sequences, tables",
+                "spec.yml:21:3: Duplicate key `jdoe` in roles, first defined on line 4",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_object_names() {
+        let source = "\
+version: 1
+adapter: postgres
+roles:
+  jdoe:
+    owns:
+      schemas:
+        - finance.q2
+    privileges:
+      functions:
+        read:
+          - finance.total(public.money, integer)
+          - finance.*
+          - finance.total
+        write:
+          - finance.*
+      sequences:
+        write:
+          - object: reports.
+            with_grant_option: yes
+";
+
+        assert_eq!(
+            messages(source),
+            vec![
+                "spec.yml:7:11: Malformed schema name `finance.q2`, expected a schema name",
+                "spec.yml:13:13: Malformed function name `finance.total`, expected schema.function(argument types) or schema.*",
+                "spec.yml:15:11: Functions only support read, which grants EXECUTE",
+                "spec.yml:18:21: Malformed sequence name `reports.`, expected schema.name or schema.*",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reports_membership_errors_on_role() {
+        let source = "\
+version: 1
+adapter: postgres
+roles:
+  jdoe:
+    member_of:
+      - ghost
+";
+
+        assert_eq!(
+            messages(source),
+            vec!["spec.yml:4:3: jdoe is a member of ghost, which is not defined in the spec"]
+        );
+    }
+
+    #[test]
+    fn test_settings() {
+        let source = "\
+version: 1
+adapter: postgres
+roles:
+  jdoe:
+    settings:
+      statement_timeout: 5min
+      search_path:
+        - public
+      databases:
+        warehouse:
+          work_mem: {}
+";
+        assert_eq!(
+            messages(source),
+            vec![
+                "spec.yml:8:9: Expected a value for setting search_path, found a list",
+                "spec.yml:11:21: Expected a value for setting work_mem, found a map",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_policies() {
+        let source = "\
+version: 1
+adapter: postgres
+roles:
+  analyst: {}
+policies:
+  invoices:
+    enabled: maybe
+    policies:
+      readers:
+        command: read
+        roles:
+          - analyst
+";
+        assert_eq!(
+            messages(source),
+            vec![
+                "spec.yml:6:3: Malformed table name `invoices`, expected schema.name or schema.*",
+                "spec.yml:7:14: Expected yes or no for enabled, found `maybe`",
+                "spec.yml:10:18: Unknown policy command `read`, expected one of: all, select, insert, update, delete",
+            ]
+        );
+
+        let source = "\
+version: 1
+adapter: postgres
+roles:
+  analyst: {}
+policies:
+  finance.invoices:
+    policies:
+      readers:
+        roles:
+          - ghost
+";
+        assert_eq!(
+            messages(source),
+            vec!["spec.yml:6:3: Policy readers on finance.invoices applies to ghost, which is not defined in the spec"]
+        );
+    }
+
+    #[test]
+    fn test_reports_bad_passwords() {
+        let source = "\
+version: 1
+adapter: postgres
+roles:
+  jdoe:
+    can_login: yes
+    password: hunter2
+    valid_until: next week
+  loader:
+    password:
+      env: LOADER_PASSWORD
+      file: /run/secrets/loader
+";
+
+        assert_eq!(
+            messages(source),
+            vec![
+                "spec.yml:6:15: Passwords cannot be written in the spec, use `env` or `file`",
+                "spec.yml:7:18: Invalid timestamp `next week`, expected a date such as 2025-06-30 or a timestamp such as 2025-06-30T18:00:00Z",
+                "spec.yml:10:10: Expected exactly one of `env` or `file` for password",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reports_unknown_templates() {
+        let source = "\
+version: 1
+adapter: postgres
+templates:
+  base:
+    can_login: no
+    privilages: {}
+roles:
+  jdoe:
+    extends: [base, reporting]
+";
+
+        assert_eq!(
+            messages(source),
+            vec!["spec.yml:6:5: Unknown key `privilages` in template base, did you mean `privileges`?"]
+        );
+        assert_eq!(
+            messages(&source.replace("    privilages: {}\n", "")),
+            vec!["spec.yml:8:21: jdoe extends unknown template reporting"]
+        );
+    }
+
+    #[test]
+    fn test_reports_unset_environment_variables() {
+        let source = "\
+version: 1
+adapter: ${PERMIRUST_TEST_ADAPTER:-postgres}
+roles:
+  ${PERMIRUST_TEST_UNSET}:
+    can_login: yes
+";
+
+        assert_eq!(
+            messages(source),
+            vec!["spec.yml:4:3: Environment variable PERMIRUST_TEST_UNSET is not set"]
+        );
+        assert_eq!(
+            validate_file("resources/overlay", Some("dev")).unwrap(),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_syntax_error() {
+        let errors = validate_str("roles: [jdoe\n", "spec.yml");
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, 2);
+    }
+
+    #[test]
+    fn test_resources_spec_is_valid() {
+        assert_eq!(
+            validate_file("resources/spec.yml", None).unwrap(),
+            Vec::<ValidationError>::new()
+        );
+    }
+
+    #[test]
+    fn test_expiry_warnings() {
+        let path =
+            std::env::temp_dir().join(format!("permirust-expiry-{}.yml", std::process::id()));
+        std::fs::write(
+            &path,
+            "\
+version: 1
+adapter: postgres
+roles:
+  analyst: {}
+  bob:
+    member_of:
+      - role: analyst
+        expires: 2025-06-30
+    privileges:
+      schemas:
+        write:
+          - object: finance
+            expires: 2025-06-27T18:00:00Z
+          - object: marketing
+            expires: 2025-09-01
+",
+        )
+        .unwrap();
+        let path = path.display().to_string();
+        let now = DateTime::parse_from_rfc3339("2025-06-28T09:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let warnings: Vec<String> = expiry_warnings(&path, None, now, Duration::days(7))
+            .unwrap()
+            .iter()
+            .map(|w| w.to_string().replace(&path, "spec.yml"))
+            .collect();
+        assert_eq!(
+            warnings,
+            vec![
+                "spec.yml:5:3: The membership in analyst of bob expires 2025-06-30",
+                "spec.yml:5:3: The write on schema finance of bob expired 2025-06-27T18:00:00Z, remove it from the spec",
+            ]
+        );
+        assert_eq!(
+            messages(&std::fs::read_to_string(&path).unwrap().replace("2025-09-01", "soon")),
+            vec!["spec.yml:15:22: Invalid timestamp `soon`, expected a date such as 2025-06-30 or a timestamp such as 2025-06-30T18:00:00Z"]
+        );
+    }
+
+    #[test]
+    fn test_split_spec() {
+        assert_eq!(validate_file("resources/split", None).unwrap(), vec![]);
+
+        let dir = std::env::temp_dir().join(format!("permirust-validate-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("a.yml"),
+            "version: 1\nadapter: postgres\nroles:\n  jdoe:\n    member_of:\n      - ghost\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("b.yml"),
+            "roles:\n  bob: {}\n  jdoe:\n    can_login: no\n",
+        )
+        .unwrap();
+        let errors = validate_file(&dir.to_string_lossy(), None).unwrap();
+        let a = dir.join("a.yml").display().to_string();
+        let b = dir.join("b.yml").display().to_string();
+        assert_eq!(
+            errors.iter().map(|e| e.to_string()).collect::<Vec<_>>(),
+            vec![format!(
+                "{}:3:3: Role jdoe is already defined in {}:4:3",
+                b, a
+            )]
+        );
+
+        std::fs::write(dir.join("b.yml"), "roles:\n  bob: {}\n").unwrap();
+        let errors = validate_file(&dir.to_string_lossy(), None).unwrap();
+        assert_eq!(
+            errors.iter().map(|e| e.to_string()).collect::<Vec<_>>(),
+            vec![format!(
+                "{}:4:3: jdoe is a member of ghost, which is not defined in the spec",
+                a
+            )]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,6 +2,7 @@
 
 // The spec loaded in Postgres is defined in lib/pg-scripts/init-user-db.sh
 //
+use itertools::Itertools;
 use permirust::adapters::postgres::PostgresClient;
 use permirust::adapters::postgres::PostgresRoleAttributes;
 use permirust::context::DatabaseObject;
@@ -9,6 +10,7 @@ use permirust::context::ObjectKind::*;
 use permirust::context::Privilege;
 use permirust::context::PrivilegeType::*;
 use permirust::context::RoleMembership;
+use permirust::context::PUBLIC;
 use permirust::generate::generate_spec;
 use permirust::spec::DatabaseSpec;
 
@@ -30,23 +32,17 @@ fn test_postgres_generates_correct_spec() {
     // Add Memberships
     expected_spec.add_memberships(
         "jdoe",
-        &RoleMembership {
-            memberships: vec!["analyst".to_string(), "engineer".to_string()],
-        },
+        &RoleMembership::new(vec!["analyst".to_string(), "engineer".to_string()]),
     );
 
     expected_spec.add_memberships(
         "engineer",
-        &RoleMembership {
-            memberships: vec!["analyst".to_string()],
-        },
+        &RoleMembership::new(vec!["analyst".to_string()]),
     );
 
     expected_spec.add_memberships(
         "postgres",
-        &RoleMembership {
-            memberships: vec!["engineer".to_string()],
-        },
+        &RoleMembership::new(vec!["engineer".to_string()]),
     );
 
     // Add Ownerships
@@ -132,6 +128,16 @@ fn test_postgres_generates_correct_spec() {
             ),
         ],
     );
+    // PUBLIC can use the public schema by default
+    expected_spec.add_public();
+    expected_spec.add_privileges(
+        PUBLIC,
+        &[Privilege::new(
+            DatabaseObject::new(Schema, "public".into(), None),
+            vec![Read],
+        )],
+    );
+
     // Test Spec
     assert_eq!(spec.version, expected_spec.version);
     assert_eq!(spec.adapter, expected_spec.adapter);
@@ -148,8 +154,8 @@ fn test_postgres_generates_correct_spec() {
             expected_role.member_of,
             "\nEnsuring {} is member_of {}. We have {}",
             role_name,
-            expected_role.member_of.join(","),
-            spec.roles[role_name].member_of.join(","),
+            expected_role.member_of.iter().join(","),
+            spec.roles[role_name].member_of.iter().join(","),
         );
 
         // Test Ownership -- note this is order dependent, we should fix that